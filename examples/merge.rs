@@ -20,10 +20,11 @@ fn main() -> Result<(),Error> {
   for (b_index,bdir) in args[2..].iter().enumerate() {
     let mut bfile = PathBuf::from(bdir);
     bfile.push("range");
-    let mut ranges = eyros::DataRange::new(
+    let ranges = eyros::DataRange::<_,P>::new(
       RandomAccessDisk::builder(bfile)
         .auto_sync(false)
         .build()?,
+      "range".to_string(),
       0
     );
     // TODO: incorporate len field and pre-set data offsets into Row enum
@@ -13,7 +13,7 @@ type I = (u32,u64);
 fn main() -> Result<(),Error> {
   let args: Vec<String> = std::env::args().collect();
   let base = PathBuf::from(args[1].clone());
-  let mut db: DB<_,_,R,I> = DB::open(|name| {
+  let db: DB<_,_,R,I> = DB::open(|name| {
     let mut p = base.clone();
     p.push(name);
     Ok(RandomAccessDisk::builder(p)
@@ -26,14 +26,19 @@ fn main() -> Result<(),Error> {
     for dir in args[2..n].iter() {
       let mut bfile = PathBuf::from(dir.clone());
       let mut dfile = PathBuf::from(dir.clone());
+      let mut ifile = PathBuf::from(dir.clone());
       bfile.push("bbox");
       dfile.push("data");
+      ifile.push("ids");
       res.push(<eyros::DataStore<S,P,V>>::open(
+        0,
         RandomAccessDisk::open(dfile)?,
         RandomAccessDisk::open(bfile)?,
+        RandomAccessDisk::open(ifile)?,
         db.fields.max_data_size,
         db.fields.bbox_cache_size,
-        db.fields.data_list_cache_size
+        db.fields.data_list_cache_size,
+        None
       )?);
     }
     res
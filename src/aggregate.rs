@@ -0,0 +1,68 @@
+/// Numeric projection of a value for `db.aggregate()`.
+///
+/// This is implemented for the built-in numeric types. Implement it for your
+/// own value types to aggregate over one of their fields, for example a sensor
+/// reading.
+pub trait Measure {
+  fn measure (&self) -> f64;
+}
+
+macro_rules! impl_measure {
+  ($($T:ty),+) => {
+    $(impl Measure for $T {
+      fn measure (&self) -> f64 { *self as f64 }
+    })+
+  }
+}
+
+impl_measure![f32,f64,u8,u16,u32,u64,i8,i16,i32,i64];
+
+/// Aggregate function to compute with `db.aggregate()`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Aggregate { Min, Max, Sum, Count }
+
+/// Count, sum, minimum and maximum of a set of measured values.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Summary {
+  pub count: u64,
+  pub sum: f64,
+  pub min: f64,
+  pub max: f64
+}
+
+impl Default for Summary {
+  fn default () -> Self {
+    Self {
+      count: 0,
+      sum: 0.0,
+      min: std::f64::INFINITY,
+      max: std::f64::NEG_INFINITY
+    }
+  }
+}
+
+impl Summary {
+  pub fn add (&mut self, x: f64) {
+    self.count += 1;
+    self.sum += x;
+    self.min = self.min.min(x);
+    self.max = self.max.max(x);
+  }
+  pub fn merge (&mut self, other: &Summary) {
+    self.count += other.count;
+    self.sum += other.sum;
+    self.min = self.min.min(other.min);
+    self.max = self.max.max(other.max);
+  }
+  /// Get the result for `agg`, or `None` for the minimum or maximum of an
+  /// empty set.
+  pub fn get (&self, agg: Aggregate) -> Option<f64> {
+    match agg {
+      Aggregate::Count => Some(self.count as f64),
+      Aggregate::Sum => Some(self.sum),
+      Aggregate::Min if self.count > 0 => Some(self.min),
+      Aggregate::Max if self.count > 0 => Some(self.max),
+      _ => None
+    }
+  }
+}
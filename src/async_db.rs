@@ -0,0 +1,357 @@
+use crate::{DB,Setup,SetupFields,Point,Value,Row,Location,Id,QueryIterator};
+use crate::lock::Acquire;
+use failure::{Error,bail};
+use futures::future::LocalBoxFuture;
+use futures::Stream;
+use random_access_storage::RandomAccess;
+use std::collections::{HashMap,VecDeque};
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use std::task::{Context,Poll};
+
+/// Storage with the same operations as `RandomAccess` where each operation
+/// returns a future.
+///
+/// Implement this trait to back an `AsyncDB` with storage that does its I/O
+/// asynchronously. The on-disk format is the same as for a `DB`.
+///
+/// The futures are polled by the executor that polls the `AsyncDB` futures,
+/// and each one is awaited before the next operation starts, so operations on
+/// a store never run concurrently.
+pub trait AsyncRandomAccess: Send {
+  fn write<'a> (&'a mut self, offset: u64, data: &'a [u8])
+    -> LocalBoxFuture<'a,Result<(),Error>>;
+  fn read (&mut self, offset: u64, length: u64)
+    -> LocalBoxFuture<'_,Result<Vec<u8>,Error>>;
+  fn del (&mut self, offset: u64, length: u64)
+    -> LocalBoxFuture<'_,Result<(),Error>>;
+  fn truncate (&mut self, length: u64) -> LocalBoxFuture<'_,Result<(),Error>>;
+  fn len (&self) -> LocalBoxFuture<'_,Result<u64,Error>>;
+  fn is_empty (&mut self) -> LocalBoxFuture<'_,Result<bool,Error>>;
+  fn sync_all (&mut self) -> LocalBoxFuture<'_,Result<(),Error>>;
+}
+
+enum Op {
+  Write(u64,Vec<u8>),
+  Del(u64,u64),
+  Truncate(u64),
+  Sync
+}
+
+// in-memory copies of the files of a database along with the operations on
+// them that haven't been written to storage yet, in the order they were made
+struct Cache {
+  files: HashMap<String,Vec<u8>>,
+  ops: VecDeque<(String,Op)>,
+  // files the database asked for while it was opened that aren't loaded yet
+  missing: Vec<String>,
+  // contents from storage of the files written to while the database was
+  // opened
+  originals: HashMap<String,Vec<u8>>,
+  loading: bool
+}
+
+// store for the database that reads and writes the in-memory copy of a file
+// and queues its writes for storage
+struct Mirror {
+  name: String,
+  cache: Arc<Mutex<Cache>>
+}
+
+impl Mirror {
+  fn update<F> (&mut self, op: Op, f: F) -> Result<(),Error>
+  where F: FnOnce(&mut Vec<u8>) {
+    let mut cache = self.cache.acquire()?;
+    let cache = &mut *cache;
+    let bytes = match cache.files.get_mut(&self.name) {
+      Some(bytes) => bytes,
+      None => bail!["file {} is not loaded", self.name]
+    };
+    if cache.loading && !cache.originals.contains_key(&self.name) {
+      cache.originals.insert(self.name.clone(), bytes.clone());
+    }
+    f(bytes);
+    cache.ops.push_back((self.name.clone(), op));
+    Ok(())
+  }
+  fn view<F,T> (&self, f: F) -> Result<T,Error>
+  where F: FnOnce(&Vec<u8>) -> Result<T,Error> {
+    let cache = self.cache.acquire()?;
+    match cache.files.get(&self.name) {
+      Some(bytes) => f(bytes),
+      None => bail!["file {} is not loaded", self.name]
+    }
+  }
+}
+
+impl RandomAccess for Mirror {
+  type Error = Error;
+  fn write (&mut self, offset: u64, data: &[u8]) -> Result<(),Error> {
+    self.update(Op::Write(offset, data.to_vec()), |bytes| {
+      let end = offset as usize + data.len();
+      if bytes.len() < end { bytes.resize(end, 0) }
+      bytes[offset as usize..end].copy_from_slice(data);
+    })
+  }
+  fn read (&mut self, offset: u64, length: u64) -> Result<Vec<u8>,Error> {
+    self.view(|bytes| {
+      let end = (offset + length) as usize;
+      if end > bytes.len() {
+        bail!["read of {} bytes at {} is out of bounds", length, offset]
+      }
+      Ok(bytes[offset as usize..end].to_vec())
+    })
+  }
+  fn read_to_writer (&mut self, offset: u64, length: u64,
+  buf: &mut impl Write) -> Result<(),Error> {
+    buf.write_all(&self.read(offset, length)?)?;
+    Ok(())
+  }
+  fn del (&mut self, offset: u64, length: u64) -> Result<(),Error> {
+    self.update(Op::Del(offset, length), |bytes| {
+      let end = ((offset + length) as usize).min(bytes.len());
+      let start = (offset as usize).min(end);
+      for b in bytes[start..end].iter_mut() { *b = 0 }
+    })
+  }
+  fn truncate (&mut self, length: u64) -> Result<(),Error> {
+    self.update(Op::Truncate(length), |bytes| {
+      bytes.resize(length as usize, 0);
+    })
+  }
+  fn len (&self) -> Result<u64,Error> {
+    self.view(|bytes| Ok(bytes.len() as u64))
+  }
+  fn is_empty (&mut self) -> Result<bool,Error> {
+    self.view(|bytes| Ok(bytes.is_empty()))
+  }
+  fn sync_all (&mut self) -> Result<(),Error> {
+    self.update(Op::Sync, |_| {})
+  }
+}
+
+type OpenMirror = Box<dyn Fn(&str) -> Result<Mirror,Error>+Send>;
+
+fn open_mirror (cache: Arc<Mutex<Cache>>) -> OpenMirror {
+  Box::new(move |name: &str| {
+    let mut c = cache.acquire()?;
+    if !c.files.contains_key(name) {
+      if c.loading {
+        c.missing.push(name.to_string());
+        bail!["file {} is not loaded", name];
+      }
+      // every file that holds records is opened along with the database, so a
+      // file opened later is new or left over and starts out empty
+      c.files.insert(name.to_string(), vec![]);
+      c.ops.push_back((name.to_string(), Op::Truncate(0)));
+    }
+    Ok(Mirror { name: name.to_string(), cache: Arc::clone(&cache) })
+  })
+}
+
+type OpenStore =
+  Box<dyn Fn(&str) -> Result<Box<dyn AsyncRandomAccess>,Error>+Send>;
+
+// async stores of a database by file name
+struct Stores {
+  open_store: OpenStore,
+  stores: HashMap<String,Box<dyn AsyncRandomAccess>>
+}
+
+impl Stores {
+  fn get (&mut self, name: &str)
+  -> Result<&mut Box<dyn AsyncRandomAccess>,Error> {
+    if !self.stores.contains_key(name) {
+      let store = (self.open_store)(name)?;
+      self.stores.insert(name.to_string(), store);
+    }
+    Ok(self.stores.get_mut(name).unwrap())
+  }
+  async fn load (&mut self, name: &str) -> Result<Vec<u8>,Error> {
+    let store = self.get(name)?;
+    let len = store.len().await?;
+    if len == 0 { return Ok(vec![]) }
+    store.read(0, len).await
+  }
+  // write the queued operations to storage in order. an operation that fails
+  // stays at the front of the queue
+  async fn flush (&mut self, cache: &Mutex<Cache>) -> Result<(),Error> {
+    loop {
+      let (name,op) = match cache.acquire()?.ops.pop_front() {
+        Some(op) => op,
+        None => return Ok(())
+      };
+      let store = self.get(&name)?;
+      let result = match &op {
+        Op::Write(offset,data) => store.write(*offset, data).await,
+        Op::Del(offset,length) => store.del(*offset, *length).await,
+        Op::Truncate(length) => store.truncate(*length).await,
+        Op::Sync => store.sync_all().await
+      };
+      if let Err(e) = result {
+        cache.acquire()?.ops.push_front((name,op));
+        return Err(e)
+      }
+    }
+  }
+}
+
+/// Database API for async code.
+///
+/// An `AsyncDB` keeps a copy of every file of the database in memory, which it
+/// reads from `AsyncRandomAccess` storage when it is opened. Batches and
+/// queries run on the copies, so queries never wait on storage. Each batch
+/// then writes its changes to storage in the same order as a `DB` would,
+/// awaiting every operation before starting the next one, so batches are
+/// atomic in the same way.
+///
+/// A `QueryStream` that is still open during a `batch()` can return records
+/// written or deleted by that batch, the same as for `DB::query()`.
+///
+/// ```rust,no_run
+/// use eyros::{AsyncDB,AsyncRandomAccess,Row};
+/// use failure::Error;
+/// use futures::StreamExt;
+///
+/// type P = ((f32,f32),(f32,f32));
+/// type V = u32;
+///
+/// async fn run<A,U> (storage: U) -> Result<(),Error>
+/// where A: AsyncRandomAccess+'static,
+/// U: Fn(&str) -> Result<A,Error>+Send+'static {
+///   let mut db: AsyncDB<P,V> = AsyncDB::open(storage).await?;
+///   db.batch(&[
+///     Row::Insert(((0.1,0.2),(0.3,0.3)), 100),
+///     Row::Insert(((-0.5,-0.4),(0.7,0.8)), 101)
+///   ]).await?;
+///   let mut results = db.query(&((0.0,0.0),(1.0,1.0)))?;
+///   while let Some(result) = results.next().await {
+///     println!("{:?}", result?);
+///   }
+///   Ok(())
+/// }
+/// ```
+pub struct AsyncDB<P,V> where P: Point, V: Value {
+  db: DB<Mirror,OpenMirror,P,V>,
+  cache: Arc<Mutex<Cache>>,
+  stores: Stores
+}
+
+impl<P,V> AsyncDB<P,V> where P: Point, V: Value {
+  /// Open a database with the default configuration from `open_store`, a
+  /// function that receives a string path as an argument and returns a Result
+  /// with an `AsyncRandomAccess` store.
+  pub async fn open<A,U> (open_store: U) -> Result<Self,Error>
+  where A: AsyncRandomAccess+'static,
+  U: Fn(&str) -> Result<A,Error>+Send+'static {
+    Self::open_from_fields(open_store, SetupFields::default()).await
+  }
+
+  /// Open a database from `open_store` with the configuration in `fields`.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{AsyncDB,AsyncRandomAccess,SetupFields};
+  /// # use failure::Error;
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// # async fn run<A,U> (storage: U) -> Result<(),Error>
+  /// # where A: AsyncRandomAccess+'static,
+  /// # U: Fn(&str) -> Result<A,Error>+Send+'static {
+  /// let db: AsyncDB<P,V> = AsyncDB::open_from_fields(storage, SetupFields {
+  ///   branch_factor: 5,
+  ///   max_data_size: 3_000,
+  ///   base_size: 1_000,
+  ///   ..SetupFields::default()
+  /// }).await?;
+  /// # Ok(()) }
+  /// ```
+  pub async fn open_from_fields<A,U> (open_store: U, fields: SetupFields)
+  -> Result<Self,Error>
+  where A: AsyncRandomAccess+'static,
+  U: Fn(&str) -> Result<A,Error>+Send+'static {
+    let mut stores = Stores {
+      open_store: Box::new(move |name: &str| {
+        let store: Box<dyn AsyncRandomAccess> = Box::new(open_store(name)?);
+        Ok(store)
+      }),
+      stores: HashMap::new()
+    };
+    let cache = Arc::new(Mutex::new(Cache {
+      files: HashMap::new(),
+      ops: VecDeque::new(),
+      missing: vec![],
+      originals: HashMap::new(),
+      loading: true
+    }));
+    // the files a database reads while it is opened depend on the contents of
+    // the files before them, so open it again after loading each file it asks
+    // for until it has every one
+    let db = loop {
+      let mut setup = Setup::new(open_mirror(Arc::clone(&cache)));
+      setup.fields = fields.clone();
+      let err = match setup.build() {
+        Ok(db) => break db,
+        Err(e) => e
+      };
+      let missing = {
+        let mut c = cache.acquire()?;
+        if c.missing.is_empty() { return Err(err) }
+        // undo the writes of the attempt, such as rolling back an interrupted
+        // batch, which are made again once every file is loaded
+        let originals = std::mem::take(&mut c.originals);
+        c.files.extend(originals);
+        c.ops.clear();
+        std::mem::take(&mut c.missing)
+      };
+      for name in missing {
+        let bytes = stores.load(&name).await?;
+        cache.acquire()?.files.insert(name, bytes);
+      }
+    };
+    {
+      let mut c = cache.acquire()?;
+      c.loading = false;
+      c.originals.clear();
+    }
+    stores.flush(&cache).await?;
+    Ok(Self { db, cache, stores })
+  }
+
+  /// Write a collection of updates to the database. This works the same way
+  /// as `DB::batch()`.
+  ///
+  /// If `batch()` returns an error, drop this instance and open the database
+  /// again to roll back.
+  pub async fn batch (&mut self, rows: &[Row<P,V>]) -> Result<(),Error> {
+    self.db.batch(rows)?;
+    self.stores.flush(&self.cache).await
+  }
+
+  /// Query the database for every record that intersects `bbox`. The stream
+  /// yields the same `Result<(Point,Value,Location)>` items as `DB::query()`.
+  pub fn query<'b> (&self, bbox: &'b P::Bounds)
+  -> Result<QueryStream<'b,P,V>,Error> {
+    Ok(QueryStream { results: Box::new(self.db.query(bbox)?) })
+  }
+
+  /// Get the current location of the record with the id `id`. This works the
+  /// same way as `DB::locate()`.
+  pub async fn locate (&self, id: Id) -> Result<Option<Location>,Error> {
+    self.db.locate(id)
+  }
+}
+
+/// Stream of `Result<(Point,Value,Location)>` data returned by
+/// `AsyncDB::query()`.
+pub struct QueryStream<'b,P,V> where P: Point, V: Value {
+  results: Box<QueryIterator<'b,Mirror,P,V>>
+}
+
+impl<'b,P,V> Stream for QueryStream<'b,P,V> where P: Point, V: Value {
+  type Item = Result<(P,V,Location),Error>;
+  fn poll_next (self: Pin<&mut Self>, _cx: &mut Context)
+  -> Poll<Option<Self::Item>> {
+    Poll::Ready(self.get_mut().results.next())
+  }
+}
@@ -7,7 +7,7 @@ use desert::CountBytes;
 #[path="../ensure.rs"]
 #[macro_use] mod ensure;
 
-use eyros::{Setup,DB};
+use eyros::{Setup,DB,Acquire,ReadWrite};
 use failure::{Error,bail};
 use random_access_disk::RandomAccessDisk;
 use random_access_storage::RandomAccess;
@@ -39,13 +39,13 @@ fn main() -> Result<(),Error> {
     .base_size(1_000)
     .build()?;
   if args[2] == "info" {
-    let mut dstore = db.data_store.try_borrow_mut()?;
+    let dstore = db.data_store.read_lock()?;
     println!["# data\n{} bytes", dstore.bytes()?];
     println!["# staging\n{} bytes\n{} records",
       db.staging.bytes()?, db.staging.len()?];
     println!["# trees"];
     for (i,tree) in db.trees.iter().enumerate() {
-      let bytes = tree.try_borrow()?.bytes;
+      let bytes = tree.acquire()?.bytes;
       if bytes == 0 {
         println!["[{}] empty", i];
       } else {
@@ -85,16 +85,16 @@ fn main() -> Result<(),Error> {
     }
   } else if args[2] == "data" {
     let i = args[3].parse::<u64>()?;
-    let mut dstore = db.data_store.try_borrow_mut()?;
+    let dstore = db.data_store.read_lock()?;
     let points = dstore.list(i)?;
     for p in points {
       println!["{:?}", p];
     }
   } else if args[2] == "staging-data" {
-    for pv in db.staging.inserts.try_borrow()?.iter() {
+    for pv in db.staging.inserts.read_lock()?.iter() {
       println!["{:?}", pv];
     }
-    for loc in db.staging.deletes.try_borrow()?.iter() {
+    for loc in db.staging.deletes.read_lock()?.iter() {
       println!["{:?} [DELETE]", loc];
     }
   } else if args[2] == "time-query" {
@@ -141,9 +141,9 @@ pub struct Branch {
 fn read_branch<S,U> (db: &mut DB<S,U,P,V>, tree_i: usize,
 offset: u64, depth: usize) -> Result<Branch,Error>
 where S: RandomAccess<Error=Error>, U: (Fn(&str) -> Result<S,Error>) {
-  let len = db.trees[tree_i].try_borrow()?.store.len()? as u64;
+  let len = db.trees[tree_i].acquire()?.store.len()? as u64;
   let buf = read_block(
-    &mut db.trees[tree_i].try_borrow_mut()?.store, offset, len, 1024
+    &mut db.trees[tree_i].acquire()?.store, offset, len, 1024
   )?;
   let bf = db.fields.branch_factor;
   let n = bf*2-3;
@@ -0,0 +1,50 @@
+extern crate eyros;
+extern crate failure;
+extern crate random_access_disk;
+
+use eyros::{Setup,SetupFields,DB};
+use failure::{Error,bail};
+use random_access_disk::RandomAccessDisk;
+use std::path::PathBuf;
+use std::env;
+
+// this binary only migrates databases with this point and value type, which
+// the stored settings can't tell apart from other types of the same size.
+// databases with other point or value types can be migrated by calling
+// eyros::migrate() with those types, using this binary as an example
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+fn main() -> Result<(),Error> {
+  let args: Vec<String> = env::args().collect();
+  if args.len() < 3 {
+    bail!["usage: migrate SRCPATH DSTPATH \
+      [BRANCH_FACTOR MAX_DATA_SIZE BASE_SIZE]\n\
+      migrates databases with ((f32,f32),(f32,f32)) points and u32 values"];
+  }
+  let setting = |i: usize, default: usize| -> Result<usize,Error> {
+    Ok(match args.get(i) {
+      Some(arg) => arg.parse::<usize>()?,
+      None => default
+    })
+  };
+  let defaults = SetupFields::default();
+  let src = PathBuf::from(&args[1]);
+  let dst = PathBuf::from(&args[2]);
+  let db: DB<_,_,P,V> = eyros::migrate(
+    |name: &str| -> Result<RandomAccessDisk,Error> {
+      Ok(RandomAccessDisk::open(src.join(name))?)
+    },
+    Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+      Ok(RandomAccessDisk::open(dst.join(name))?)
+    })
+      .branch_factor(setting(3, defaults.branch_factor)?)
+      .max_data_size(setting(4, defaults.max_data_size)?)
+      .base_size(setting(5, defaults.base_size)?)
+  )?;
+  println!["migrated {} records to format {}",
+    db.count(&((-std::f32::INFINITY,-std::f32::INFINITY),
+      (std::f32::INFINITY,std::f32::INFINITY)))?,
+    eyros::FORMAT_VERSION];
+  Ok(())
+}
@@ -0,0 +1,157 @@
+use crate::{Spatial,Value,Id};
+use random_access_storage::RandomAccess;
+use desert::{ToBytes,FromBytes,CountBytes};
+use failure::{Error,ensure};
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::marker::PhantomData;
+
+// bytes to read from a run at a time while merging
+const READ_SIZE: u64 = 65_536;
+
+/// Position of the midpoint of `point` along a z-order curve.
+///
+/// Each coordinate is mapped to an integer that sorts in the same order as the
+/// float, so the curve covers every coordinate and the bounds of the rows are
+/// not needed before they are read.
+pub fn z_order<P> (point: &P) -> u64 where P: Spatial {
+  let coords: Vec<u64> = point.extents().iter().map(|(min,max)| {
+    let x = (min/2.0 + max/2.0).to_bits();
+    if x >> 63 == 1 { !x } else { x | (1 << 63) }
+  }).collect();
+  let mut key = 0u64;
+  for i in 0..64/coords.len().max(1) {
+    for c in coords.iter() {
+      key = (key << 1) | ((c >> (63-i)) & 1);
+    }
+  }
+  key
+}
+
+/// Sorted run of rows spilled to temporary storage by `db.bulk_load()`.
+///
+/// Rows are sorted by their position along a z-order curve so that rows read
+/// in order from the merged runs fill data blocks that cover small regions.
+/// Each row is written after a u32 byte length so that a run can be read back
+/// a piece at a time.
+pub struct Run<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  store: S,
+  offset: u64,
+  len: u64,
+  buf: Vec<u8>,
+  pos: usize,
+  _marker: PhantomData<(P,V)>
+}
+
+impl<S,P,V> Run<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  /// Sort `rows` and write them to `store`, replacing anything left in the
+  /// store from an earlier load.
+  pub fn write (mut store: S, rows: &mut Vec<(P,V,Id)>) -> Result<Self,Error> {
+    rows.sort_by_cached_key(|row| (z_order(&row.0),row.2));
+    let mut size = 0;
+    for row in rows.iter() {
+      size += 4 + row.count_bytes();
+    }
+    let mut buf = vec![0u8;size];
+    let mut offset = 0;
+    for row in rows.iter() {
+      let n = row.write_bytes(&mut buf[offset+4..])?;
+      (n as u32).write_bytes(&mut buf[offset..])?;
+      offset += 4 + n;
+    }
+    store.truncate(0)?;
+    store.write(0, &buf)?;
+    Ok(Self {
+      store,
+      offset: 0,
+      len: size as u64,
+      buf: vec![],
+      pos: 0,
+      _marker: PhantomData
+    })
+  }
+  /// Read the next row of the run.
+  pub fn next (&mut self) -> Result<Option<(P,V,Id)>,Error> {
+    if !self.fill(4)? { return Ok(None) }
+    let (_,size) = u32::from_bytes(&self.buf[self.pos..])?;
+    self.pos += 4;
+    ensure![self.fill(size as usize)?, "unexpected end of sorted run"];
+    let (_,row) = <(P,V,Id)>::from_bytes(&self.buf[self.pos..])?;
+    self.pos += size as usize;
+    Ok(Some(row))
+  }
+  // make sure at least `n` unread bytes are buffered. returns false at the end
+  // of the run
+  fn fill (&mut self, n: usize) -> Result<bool,Error> {
+    let available = self.buf.len() - self.pos;
+    if available >= n { return Ok(true) }
+    let remaining = self.len - self.offset;
+    if remaining == 0 { return Ok(false) }
+    let size = remaining.min(READ_SIZE.max((n - available) as u64));
+    let data = self.store.read(self.offset, size)?;
+    self.offset += size;
+    self.buf.drain(0..self.pos);
+    self.pos = 0;
+    self.buf.extend_from_slice(&data);
+    Ok(self.buf.len() >= n)
+  }
+  /// Truncate the temporary storage.
+  pub fn clear (&mut self) -> Result<(),Error> {
+    self.store.truncate(0)?;
+    self.store.sync_all()?;
+    Ok(())
+  }
+}
+
+/// Iterator over the rows of every run in z-order, holding only the next row
+/// of each run in memory.
+pub struct Merge<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  runs: Vec<Run<S,P,V>>,
+  heads: Vec<Option<(P,V,Id)>>,
+  heap: BinaryHeap<Reverse<(u64,Id,usize)>>
+}
+
+impl<S,P,V> Merge<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  pub fn new (mut runs: Vec<Run<S,P,V>>) -> Result<Self,Error> {
+    let mut heads = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (i,run) in runs.iter_mut().enumerate() {
+      let head = run.next()?;
+      if let Some(row) = &head {
+        heap.push(Reverse((z_order(&row.0),row.2,i)));
+      }
+      heads.push(head);
+    }
+    Ok(Self { runs, heads, heap })
+  }
+  fn next_row (&mut self) -> Result<Option<(P,V,Id)>,Error> {
+    let i = match self.heap.pop() {
+      None => return Ok(None),
+      Some(Reverse((_,_,i))) => i
+    };
+    let head = self.runs[i].next()?;
+    if let Some(row) = &head {
+      self.heap.push(Reverse((z_order(&row.0),row.2,i)));
+    }
+    Ok(std::mem::replace(&mut self.heads[i], head))
+  }
+  /// Truncate the temporary storage of every run.
+  pub fn clear (&mut self) -> Result<(),Error> {
+    for run in self.runs.iter_mut() {
+      run.clear()?;
+    }
+    Ok(())
+  }
+}
+
+impl<S,P,V> Iterator for Merge<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  type Item = Result<(P,V,Id),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    self.next_row().transpose()
+  }
+}
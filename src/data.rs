@@ -1,40 +1,63 @@
-use crate::{Point,Value,Location,read_block::read_block};
+use crate::{Point,Value,Location,Id,read_block::read_block,journal::Journal};
+use crate::aggregate::{Measure,Summary};
 use random_access_storage::RandomAccess;
 use failure::{Error,ensure,bail};
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::{Arc,Mutex,MutexGuard,RwLock,Weak};
+use crate::lock::{Acquire,ReadWrite};
 use lru::LruCache;
 use std::collections::HashMap;
 use desert::{FromBytes,ToBytes,CountBytes};
 
+/// Rows of data blocks as they were before a batch modified them in place,
+/// keyed by block offset.
+pub type Pinned<P,V> = HashMap<u64,Vec<(P,V,Location)>>;
+
+/// Names of the data and range stores in the set of files `files`. The meta
+/// file records which set is in use and compaction writes the other one.
+pub fn file_names (files: u8) -> (String,String) {
+  match files {
+    0 => ("data".to_string(), "range".to_string()),
+    _ => (format!("data{}",files), format!("range{}",files))
+  }
+}
+
 pub trait DataBatch<P,V> where P: Point, V: Value {
   fn batch (&mut self, rows: &Vec<&(P,V)>) -> Result<u64,Error>;
 }
 
+/// Writes the data blocks of a tree that is being built to the shared data
+/// store, either from rows or by merging existing blocks.
 pub struct DataMerge<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  data_store: Rc<RefCell<DataStore<S,P,V>>>
+  data_store: Arc<RwLock<DataStore<S,P,V>>>
 }
 
 impl<S,P,V> DataMerge<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  pub fn new (data_store: Rc<RefCell<DataStore<S,P,V>>>) -> Self {
+  pub fn new (data_store: Arc<RwLock<DataStore<S,P,V>>>) -> Self {
     Self { data_store }
   }
 }
 
+impl<S,P,V> DataBatch<P,(V,Id)> for DataMerge<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  fn batch (&mut self, rows: &Vec<&(P,(V,Id))>) -> Result<u64,Error> {
+    self.data_store.write_lock()?.batch(rows)
+  }
+}
+
 impl<S,P,V> DataBatch<P::Range,u64> for DataMerge<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   fn batch (&mut self, rows: &Vec<&(P::Range,u64)>) -> Result<u64,Error> {
     if rows.len() == 1 { // use existing address
       Ok(rows[0].1)
     } else { // combine addresses into a new block
-      let mut dstore = self.data_store.try_borrow_mut()?;
+      let mut dstore = self.data_store.write_lock()?;
       let max = dstore.max_data_size;
-      let mut combined: Vec<(P,V)> = vec![];
+      let mut combined: Vec<(P,(V,Id))> = vec![];
       for row in rows {
-        let pvs: Vec<(P,V)> = dstore.list(row.1)?.iter().map(|c| {
-          (c.0, c.1.clone())
+        let pvs: Vec<(P,(V,Id))> = dstore.list(row.1)?.iter().map(|c| {
+          (c.0, (c.1.clone(),(c.2).2))
         }).collect();
         combined.extend(pvs);
       }
@@ -44,82 +67,150 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
   }
 }
 
+/// Data blocks with their ranges and the id index. Methods that only read take
+/// `&self` so that queries can share the data store behind a read lock. Each
+/// store is locked only while it is read or written and blocks are parsed
+/// after the lock is released.
 //#[derive(Debug,Clone)]
 pub struct DataStore<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  store: S,
+  store: Mutex<S>,
+  name: String,
   range: DataRange<S,P>,
-  list_cache: LruCache<u64,Vec<(P,V,Location)>>,
+  ids: DataIds<S>,
+  list_cache: BlockCache<Vec<(P,V,Location)>>,
+  pins: Mutex<Vec<Weak<Mutex<Pinned<P,V>>>>>,
+  // projection of values for the block summaries written with each block
+  measure: Option<fn(&V) -> f64>,
   pub max_data_size: usize
 }
 
-impl<S,P,V> DataBatch<P,V> for DataStore<S,P,V>
+impl<S,P,V> DataBatch<P,(V,Id)> for DataStore<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  fn batch (&mut self, rows: &Vec<&(P,V)>) -> Result<u64,Error> {
+  fn batch (&mut self, rows: &Vec<&(P,(V,Id))>) -> Result<u64,Error> {
     ensure![rows.len() <= self.max_data_size,
       "data size limit exceeded in data merge"];
-    let bitfield_len = (rows.len()+7)/8;
-    let mut len = 6 + bitfield_len;
-    for row in rows.iter() {
-      len += row.count_bytes();
-    }
-    let mut data = vec![0u8;len];
-    let mut offset = 0;
-    offset += (len as u32).write_bytes(&mut data[offset..])?;
-    offset += (bitfield_len as u16).write_bytes(&mut data[offset..])?;
-    for (i,_row) in rows.iter().enumerate() {
-      data[6+i/8] |= 1<<(i%8);
-    }
-    offset += bitfield_len;
-    for row in rows.iter() {
-      offset += row.write_bytes(&mut data[offset..])?;
-    }
-    let store_offset = self.store.len()?;
-    self.store.write(store_offset, &data)?;
+    let data = encode(rows)?;
+    let store_offset = {
+      let mut store = self.store.acquire()?;
+      let offset = store.len()?;
+      store.write(offset, &data)?;
+      offset
+    };
     let bbox = match P::bounds(&rows.iter().map(|(p,_)| *p).collect()) {
       None => bail!["failed to calculate bounds"],
       Some(bbox) => bbox
     };
-    self.range.write(&(store_offset,P::bounds_to_range(bbox),rows.len() as u64))?;
+    let summary = self.summary(rows.iter().map(|(_,(v,_))| v))?;
+    self.range.write(&(store_offset,bbox,rows.len() as u64), &summary)?;
+    self.ids.write(&rows.iter().map(|(_,(_,id))| *id).collect(), store_offset)?;
     Ok(store_offset)
   }
 }
 
+// serialize `rows` as a data block with every bit in the bitfield set
+fn encode<P,V> (rows: &Vec<&(P,(V,Id))>) -> Result<Vec<u8>,Error>
+where P: Point, V: Value {
+  let bitfield_len = (rows.len()+7)/8;
+  let mut len = 6 + bitfield_len;
+  for row in rows.iter() {
+    len += row.count_bytes();
+  }
+  let mut data = vec![0u8;len];
+  let mut offset = 0;
+  offset += (len as u32).write_bytes(&mut data[offset..])?;
+  offset += (bitfield_len as u16).write_bytes(&mut data[offset..])?;
+  for (i,_row) in rows.iter().enumerate() {
+    data[6+i/8] |= 1<<(i%8);
+  }
+  offset += bitfield_len;
+  for row in rows.iter() {
+    offset += row.write_bytes(&mut data[offset..])?;
+  }
+  Ok(data)
+}
+
 impl<S,P,V> DataStore<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  pub fn open (store: S, range_store: S, max_data_size: usize,
-  bbox_cache_size: usize, list_cache_size: usize) -> Result<Self,Error> {
+  /// Open the data store from the data and range stores of the set of files
+  /// `files` and the id index in `ids_store`.
+  pub fn open (files: u8, store: S, range_store: S, ids_store: S,
+  max_data_size: usize, bbox_cache_size: usize, list_cache_size: usize,
+  measure: Option<fn(&V) -> f64>) -> Result<Self,Error> {
+    let (name,range_name) = file_names(files);
     Ok(Self {
-      store,
-      range: DataRange::new(range_store, bbox_cache_size),
-      list_cache: LruCache::new(list_cache_size),
+      store: Mutex::new(store),
+      name,
+      range: DataRange::new(range_store, range_name, bbox_cache_size),
+      ids: DataIds::new(ids_store),
+      list_cache: BlockCache::new(list_cache_size),
+      pins: Mutex::new(vec![]),
+      measure,
       max_data_size
     })
   }
+  /// Start keeping a copy of each data block before it is modified in place.
+  /// Copies are kept for as long as the returned map is alive.
+  pub fn pin (&self) -> Result<Arc<Mutex<Pinned<P,V>>>,Error> {
+    let pinned = Arc::new(Mutex::new(HashMap::new()));
+    self.pins.acquire()?.push(Arc::downgrade(&pinned));
+    Ok(pinned)
+  }
+  // copy the rows of `block` into every pin that doesn't have them yet
+  fn keep_pinned (&self, block: u64) -> Result<(),Error> {
+    let pins: Vec<Arc<Mutex<Pinned<P,V>>>> = {
+      let mut pins = self.pins.acquire()?;
+      pins.retain(|pin| pin.strong_count() > 0);
+      pins.iter().filter_map(|pin| pin.upgrade()).collect()
+    };
+    let mut rows = None;
+    for pin in pins {
+      let mut pinned = pin.acquire()?;
+      if pinned.contains_key(&block) { continue }
+      if rows.is_none() { rows = Some(self.list(block)?) }
+      pinned.insert(block, rows.clone().unwrap());
+    }
+    Ok(())
+  }
   pub fn commit (&mut self) -> Result<(),Error> {
-    self.store.sync_all()?;
+    self.store.acquire()?.sync_all()?;
+    self.range.store.acquire()?.sync_all()?;
+    self.ids.store.acquire()?.sync_all()?;
+    Ok(())
+  }
+  /// Save enough state in `journal` to discard blocks, ranges and id slots
+  /// appended after this call.
+  pub fn journal (&mut self, journal: &mut Journal<S>) -> Result<(),Error> {
+    journal.keep_len(&self.name, &*self.store.acquire()?)?;
+    journal.keep_len(&self.range.name, &*self.range.store.acquire()?)?;
+    journal.keep_len("ids", &*self.ids.store.acquire()?)?;
     Ok(())
   }
-  pub fn query (&mut self, offset: u64, bbox: &P::Bounds)
+  /// Save the bitfields that `delete()` will modify for `locations`.
+  pub fn journal_deletes (&mut self, journal: &mut Journal<S>,
+  locations: &Vec<Location>) -> Result<(),Error> {
+    let by_block = self.group_by_block(locations)?;
+    self.journal_clear(journal, &by_block)
+  }
+  pub fn query (&self, offset: u64, bbox: &P::Bounds)
   -> Result<Vec<(P,V,Location)>,Error> {
     let rows = self.list(offset)?;
     Ok(rows.iter().filter(|row| {
       row.0.overlaps(bbox)
     }).map(|row| { row.clone() }).collect())
   }
-  pub fn list (&mut self, offset: u64) -> Result<Vec<(P,V,Location)>,Error> {
-    match self.list_cache.get(&offset) {
-      Some(rows) => return Ok(rows.to_vec()),
-      None => {}
+  pub fn list (&self, offset: u64) -> Result<Vec<(P,V,Location)>,Error> {
+    if let Some(rows) = self.list_cache.get(offset)? {
+      return Ok(rows)
     }
     let buf = self.read(offset)?;
-    let rows = self.parse(&buf)?.iter().map(|row| {
-      (row.0,row.1.clone(),(offset+1,row.2))
+    let rows: Vec<(P,V,Location)> = self.parse(&buf)?.iter().map(|row| {
+      (row.0,row.1.clone(),(offset+1,row.3,row.2,0))
     }).collect();
-    self.list_cache.put(offset, rows);
-    Ok(self.list_cache.peek(&offset).unwrap().to_vec())
+    self.list_cache.put(offset, rows.clone())?;
+    Ok(rows)
   }
-  pub fn parse (&self, buf: &Vec<u8>) -> Result<Vec<(P,V,u32)>,Error> {
+  pub fn parse (&self, buf: &Vec<u8>) -> Result<Vec<(P,V,Id,u32)>,Error> {
     let mut results = vec![];
     let mut offset = 0;
     let bitfield_len = u16::from_be_bytes([buf[0],buf[1]]) as usize;
@@ -129,44 +220,174 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     let mut index = 0;
     while offset < buf.len() {
       if ((bitfield[index/8]>>(index%8))&1) == 1 {
-        let (size,pv) = <(P,V)>::from_bytes(&buf[offset..])?;
-        results.push((pv.0,pv.1,index as u32));
+        let (size,row) = <(P,V,Id)>::from_bytes(&buf[offset..])?;
+        results.push((row.0,row.1,row.2,index as u32));
         offset += size;
       } else {
-        offset += <(P,V)>::count_from_bytes(&buf[offset..])?;
+        offset += <(P,V,Id)>::count_from_bytes(&buf[offset..])?;
       }
       index += 1;
     }
     Ok(results)
   }
-  pub fn read (&mut self, offset: u64) -> Result<Vec<u8>,Error> {
-    let len = self.store.len()? as u64;
-    read_block(&mut self.store, offset, len, 1024)
+  pub fn read (&self, offset: u64) -> Result<Vec<u8>,Error> {
+    let mut store = self.store.acquire()?;
+    let len = store.len()? as u64;
+    read_block(&mut *store, offset, len, 1024)
+  }
+  /// Find the byte offset of the record at `index` in `block` if `row` can
+  /// overwrite it in place. The serialized size must match and the new point
+  /// must stay inside the bounds of the live records in the block, which keeps
+  /// the tree pivots and the stored range for the block valid.
+  pub fn replace_offset (&self, block: u64, index: u32, row: &(P,V,Id))
+  -> Result<Option<u64>,Error> {
+    let buf = self.read(block)?;
+    let bitfield_len = u16::from_be_bytes([buf[0],buf[1]]) as usize;
+    let mut offset = 2 + bitfield_len;
+    for _ in 0..index {
+      ensure![offset < buf.len(), "index {} past the end of block {}",
+        index, block];
+      offset += <(P,V,Id)>::count_from_bytes(&buf[offset..])?;
+    }
+    let size = <(P,V,Id)>::count_from_bytes(&buf[offset..])?;
+    if size != row.count_bytes() { return Ok(None) }
+    let mut points: Vec<P> = self.list(block)?.iter().map(|r| r.0).collect();
+    let bbox = P::bounds(&points);
+    points.push(row.0);
+    // P::Bounds doesn't implement PartialEq, so compare the serialized bounds
+    let within = match (bbox,P::bounds(&points)) {
+      (Some(a),Some(b)) => a.to_bytes()? == b.to_bytes()?,
+      _ => false
+    };
+    Ok(if within { Some(block+4+(offset as u64)) } else { None })
+  }
+  /// Save the bytes that `write_row()` will overwrite at `offset` in `block`
+  /// and the range entry of `block`.
+  pub fn journal_row (&mut self, journal: &mut Journal<S>, block: u64,
+  offset: u64, row: &(P,V,Id)) -> Result<(),Error> {
+    journal.keep_range(&self.name, &mut *self.store.acquire()?, offset,
+      row.count_bytes() as u64)?;
+    self.range.journal(journal, block)
+  }
+  /// Overwrite the record in `block` at the byte `offset` returned by
+  /// `replace_offset()`.
+  pub fn write_row (&mut self, block: u64, offset: u64, row: &(P,V,Id))
+  -> Result<(),Error> {
+    self.keep_pinned(block)?;
+    self.store.acquire()?.write(offset, &row.to_bytes()?)?;
+    // the point stays inside of the stored bounds of the block
+    self.list_cache.pop(block)?;
+    let summary = self.block_summary(block)?;
+    self.range.set_summary(block, &summary)
   }
-  // todo: replace() similar to delete but with an additional array of
-  // replacement candidates
   pub fn delete (&mut self, locations: &Vec<Location>) -> Result<(),Error> {
-    let mut by_block: HashMap<u64,Vec<u32>> = HashMap::new();
-    for (block,index) in locations {
-      if *block == 0 { continue } // staging block
-      match by_block.get_mut(&(*block-1)) {
-        Some(indexes) => {
-          indexes.push(*index);
-        },
-        None => {
-          by_block.insert(*block-1, vec![*index]);
-        },
+    let by_block = self.group_by_block(locations)?;
+    self.clear(&by_block)
+  }
+  /// Find the indexes of the live records in `block` that overlap `bbox`.
+  /// Only the points are parsed: values and ids are skipped over.
+  pub fn overlapping (&self, block: u64, bbox: &P::Bounds)
+  -> Result<Vec<u32>,Error> {
+    let buf = self.read(block)?;
+    let bitfield_len = u16::from_be_bytes([buf[0],buf[1]]) as usize;
+    let bitfield: &[u8] = &buf[2..2+bitfield_len];
+    let mut offset = 2 + bitfield_len;
+    let mut index = 0;
+    let mut indexes = vec![];
+    while offset < buf.len() {
+      let live = ((bitfield[index/8]>>(index%8))&1) == 1;
+      if live {
+        let (size,point) = P::from_bytes(&buf[offset..])?;
+        if point.overlaps(bbox) { indexes.push(index as u32) }
+        offset += size;
+      } else {
+        offset += P::count_from_bytes(&buf[offset..])?;
+      }
+      offset += V::count_from_bytes(&buf[offset..])?;
+      offset += Id::count_from_bytes(&buf[offset..])?;
+      index += 1;
+    }
+    Ok(indexes)
+  }
+  /// Count the live records in `block` that overlap `bbox`. Blocks inside of
+  /// `bbox` are counted from their range entry without being read and only
+  /// the points are parsed for blocks on the boundary.
+  pub fn count (&self, block: u64, bbox: &P::Bounds) -> Result<u64,Error> {
+    Ok(match self.bbox(block)? {
+      None => 0,
+      Some((b,len)) if P::bounds_within(&b, bbox) => len,
+      Some(_) => self.overlapping(block, bbox)?.len() as u64
+    })
+  }
+  /// Summarize the measured values of the live records in `block` that
+  /// overlap `bbox`. Blocks inside of `bbox` are answered from the summary in
+  /// their range entry. Blocks written without a summary are scanned.
+  pub fn summarize (&self, block: u64, bbox: &P::Bounds)
+  -> Result<Summary,Error> where V: Measure {
+    let mut summary = Summary::default();
+    match self.range.get(block)? {
+      None => bail!["no range entry for data block at offset {}", block],
+      Some((_,0,_)) => {},
+      Some((b,len,s)) if P::bounds_within(&b, bbox) && s.count == len => {
+        summary = s;
+      },
+      Some(_) => {
+        for row in self.query(block, bbox)? {
+          summary.add(row.1.measure());
+        }
       }
     }
+    Ok(summary)
+  }
+  // summary of the measured values of `values` or an empty summary without a
+  // measure, which is only valid for blocks without live records
+  fn summary<'a,I> (&self, values: I) -> Result<Summary,Error>
+  where I: Iterator<Item=&'a V>, V: 'a {
+    let mut summary = Summary::default();
+    if let Some(measure) = self.measure {
+      for v in values { summary.add(measure(v)) }
+    }
+    Ok(summary)
+  }
+  fn block_summary (&self, block: u64) -> Result<Summary,Error> {
+    if self.measure.is_none() { return Ok(Summary::default()) }
+    let rows = self.list(block)?;
+    self.summary(rows.iter().map(|row| &row.1))
+  }
+  /// Find the data block that was last written with the record `id`, without
+  /// checking whether the record is still live.
+  pub fn block_of (&self, id: Id) -> Result<Option<u64>,Error> {
+    self.ids.get(id)
+  }
+  /// Save the bitfields and range entries that `clear()` will modify.
+  pub fn journal_clear (&mut self, journal: &mut Journal<S>,
+  by_block: &HashMap<u64,Vec<u32>>) -> Result<(),Error> {
+    for block in by_block.keys() {
+      {
+        let mut store = self.store.acquire()?;
+        let header = store.read(*block, 6)?;
+        let bitfield_len = u16::from_bytes(&header[4..])?.1 as u64;
+        journal.keep_range(&self.name, &mut *store, *block, 6+bitfield_len)?;
+      }
+      self.range.journal(journal, *block)?;
+    }
+    Ok(())
+  }
+  /// Clear the bits for each block offset and list of record indexes in
+  /// `by_block` to delete those records.
+  pub fn clear (&mut self, by_block: &HashMap<u64,Vec<u32>>)
+  -> Result<(),Error> {
     for (block,indexes) in by_block.iter() {
       let max_i = match indexes.iter().max() {
         Some(i) => *i as u64,
         None => bail!["indexes is an empty array"],
       };
+      self.keep_pinned(*block)?;
+      let mut store = self.store.acquire()?;
       let len = 7 + max_i/8; // indexes start at 0, unlike lengths
-      ensure![len <= self.store.len()?-block,
+      ensure![len <= store.len()?-block,
         "index length past the end of the block"];
-      let mut header = self.store.read(*block, len)?;
+      let mut header = store.read(*block, len)?;
       let block_size = u32::from_bytes(&header[0..])?.1 as u64;
       let bitfield_len = u16::from_bytes(&header[4..])?.1;
       ensure![len <= (bitfield_len as u64) + 6,
@@ -175,73 +396,400 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
         len, max_i, bitfield_len, block_size, *block
       ];
       ensure![len <= block_size, "data block is too small"];
+      let mut cleared = 0;
       for index in indexes.iter() {
         let i = *index as usize;
+        if (header[6+i/8]>>(i%8))&1 == 1 { cleared += 1 }
         header[6+i/8] &= 0xff - (1<<(i%8));
       }
-      self.store.write(block+6, &header[6..])?;
-      match self.list_cache.get_mut(block) {
-        Some(rows) => {
-          rows.retain(|row| !indexes.contains(&((row.2).1)));
-        },
-        None => {},
+      store.write(block+6, &header[6..])?;
+      drop(store);
+      self.list_cache.update(*block, |rows| {
+        rows.retain(|row| !indexes.contains(&((row.2).1)));
+      })?;
+      // the bounds of the remaining records stay inside the stored bounds
+      let summary = self.block_summary(*block)?;
+      self.range.remove(*block, cleared, &summary)?;
+    }
+    Ok(())
+  }
+  pub fn bytes (&self) -> Result<u64,Error> {
+    Ok(self.store.acquire()?.len()? as u64)
+  }
+  /// Find the block offset and the index within that block for a record id.
+  /// Records that are only in staging or that have been deleted return `None`.
+  pub fn locate (&self, id: Id) -> Result<Option<(u64,u32)>,Error> {
+    let block = match self.ids.get(id)? {
+      None => return Ok(None),
+      Some(block) => block
+    };
+    let rows = self.list(block)?;
+    Ok(rows.iter().find(|row| (row.2).2 == id).map(|row| (block,(row.2).1)))
+  }
+  /// Copy only the blocks in `blocks` into `store` and `range_store`, the
+  /// empty data and range stores of the set of files `files`, and switch to
+  /// them. Blocks without any live rows are dropped too. With `vacuum`, blocks
+  /// where deleted rows take up more than half of the block are rewritten
+  /// with only their live rows.
+  ///
+  /// The stores that were in use are not modified, so an interrupted rewrite
+  /// only needs to discard the new stores. Returns the new offset of each
+  /// block, or `None` for dropped blocks, along with the old data and range
+  /// stores.
+  pub fn rewrite (&mut self, blocks: &Vec<u64>, vacuum: bool, files: u8,
+  mut store: S, range_store: S)
+  -> Result<(HashMap<u64,Option<u64>>,S,S),Error> {
+    let mut sorted = blocks.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut moved = HashMap::new();
+    let mut ranges: Vec<((u64,P::Bounds,u64),Summary)> = vec![];
+    let mut offset = 0u64;
+    for block in sorted {
+      let buf = self.read(block)?;
+      let rows: Vec<(P,(V,Id))> = self.parse(&buf)?.into_iter()
+        .map(|(p,v,id,_)| (p,(v,id))).collect();
+      if rows.is_empty() {
+        moved.insert(block, None);
+        continue
+      }
+      let mut live = 6 + (rows.len()+7)/8;
+      for row in rows.iter() {
+        live += row.count_bytes();
       }
+      let data = if vacuum && live*2 < buf.len()+4 {
+        encode(&rows.iter().collect())?
+      } else {
+        let mut data = Vec::with_capacity(buf.len()+4);
+        data.extend(&((buf.len()+4) as u32).to_be_bytes());
+        data.extend(&buf);
+        data
+      };
+      store.write(offset, &data)?;
+      let bbox = match P::bounds(&rows.iter().map(|(p,_)| *p).collect()) {
+        None => bail!["invalid data at offset {}", block],
+        Some(bbox) => bbox
+      };
+      let summary = self.summary(rows.iter().map(|(_,(v,_))| v))?;
+      ranges.push(((offset,bbox,rows.len() as u64),summary));
+      moved.insert(block, Some(offset));
+      offset += data.len() as u64;
+    }
+    let (name,range_name) = file_names(files);
+    let old = std::mem::replace(&mut *self.store.acquire()?, store);
+    self.name = name;
+    // every cache is keyed by block offset
+    self.list_cache.clear()?;
+    let old_range = self.range.replace(range_store, range_name)?;
+    for (range,summary) in ranges.iter() {
+      self.range.write(range, summary)?;
     }
+    Ok((moved,old,old_range))
+  }
+  /// Return the number of bytes in the data store that are not part of the
+  /// blocks in `blocks`.
+  pub fn garbage (&self, blocks: &Vec<u64>) -> Result<u64,Error> {
+    let mut sorted = blocks.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut store = self.store.acquire()?;
+    let mut reachable = 0;
+    for block in sorted {
+      let buf = store.read(block, 4)?;
+      reachable += u32::from_bytes(&buf)?.1 as u64;
+    }
+    Ok(store.len()?.saturating_sub(reachable))
+  }
+  /// Rebuild the id index from the data blocks referenced by `blocks`.
+  pub fn reindex (&mut self, blocks: &Vec<u64>) -> Result<(),Error> {
+    self.ids.store.acquire()?.truncate(0)?;
+    for block in blocks.iter() {
+      let ids = self.list(*block)?.iter().map(|row| (row.2).2).collect();
+      self.ids.write(&ids, *block)?;
+    }
+    self.ids.store.acquire()?.sync_all()?;
     Ok(())
   }
-  pub fn bytes (&mut self) -> Result<u64,Error> {
-    Ok(self.store.len()? as u64)
+  // resolve locations by record id since rows move into new blocks when trees
+  // are merged
+  fn group_by_block (&self, locations: &Vec<Location>)
+  -> Result<HashMap<u64,Vec<u32>>,Error> {
+    let mut by_block: HashMap<u64,Vec<u32>> = HashMap::new();
+    for location in locations {
+      let (block,index) = match self.locate(location.2)? {
+        None => continue, // staging or already deleted
+        Some(r) => r
+      };
+      match by_block.get_mut(&block) {
+        Some(indexes) => {
+          indexes.push(index);
+        },
+        None => {
+          by_block.insert(block, vec![index]);
+        },
+      }
+    }
+    Ok(by_block)
   }
-  pub fn bbox (&mut self, offset: u64)
+  /// Get the bounds and the number of live records of the block at `offset`
+  /// from its range entry, or `None` if every record in the block was
+  /// deleted. The bounds may be larger than the bounds of the live records.
+  pub fn bbox (&self, offset: u64)
   -> Result<Option<(P::Bounds,u64)>,Error> {
-    match self.range.cache.get(&offset) {
-      None => {},
-      Some(r) => return Ok(Some(*r))
-    };
-    let rows = self.list(offset)?;
-    if rows.is_empty() {
-      return Ok(None);
+    Ok(match self.range.get(offset)? {
+      None => bail!["no range entry for data block at offset {}", offset],
+      Some((_,0,_)) => None,
+      Some((bbox,len,_)) => Some((bbox,len))
+    })
+  }
+}
+
+/// Index from record ids to the data block that holds each record. Each id has
+/// a fixed 8-byte slot containing the block offset plus one, or zero when the
+/// record has not been written to a data block.
+pub struct DataIds<S> where S: RandomAccess<Error=Error> {
+  pub store: Mutex<S>
+}
+
+impl<S> DataIds<S> where S: RandomAccess<Error=Error> {
+  pub fn new (store: S) -> Self {
+    Self { store: Mutex::new(store) }
+  }
+  pub fn get (&self, id: Id) -> Result<Option<u64>,Error> {
+    let offset = id*8;
+    let mut store = self.store.acquire()?;
+    if offset+8 > store.len()? { return Ok(None) }
+    let buf = store.read(offset, 8)?;
+    match u64::from_bytes(&buf)?.1 {
+      0 => Ok(None),
+      block => Ok(Some(block-1))
     }
-    let bbox = match P::bounds(&rows.iter().map(|(p,_,_)| *p).collect()) {
-      None => bail!["invalid data at offset {}", offset],
-      Some(bbox) => bbox
-    };
-    let result = (bbox,rows.len() as u64);
-    self.range.cache.put(offset, result.clone());
-    Ok(Some(result))
+  }
+  pub fn write (&self, ids: &Vec<Id>, block: u64) -> Result<(),Error> {
+    let mut store = self.store.acquire()?;
+    let mut sorted = ids.clone();
+    sorted.sort_unstable();
+    let slot = (block+1).to_be_bytes();
+    // write runs of consecutive ids together
+    let mut i = 0;
+    while i < sorted.len() {
+      let mut j = i+1;
+      while j < sorted.len() && sorted[j] == sorted[j-1]+1 { j += 1 }
+      let mut buf = Vec::with_capacity((j-i)*8);
+      for _ in i..j { buf.extend_from_slice(&slot) }
+      store.write(sorted[i]*8, &buf)?;
+      i = j;
+    }
+    Ok(())
   }
 }
 
+/// Bounds, number of live records and summary of each data block. Each block
+/// has an entry of `(block offset, bounds, length, summary)` and entries are in
+/// order of their block offsets. Points have a static size, so every entry
+/// does too. A summary is only valid if its count matches the length.
 pub struct DataRange<S,P>
 where S: RandomAccess<Error=Error>, P: Point {
-  pub store: S,
-  pub cache: LruCache<u64,(P::Bounds,u64)>
+  pub store: Mutex<S>,
+  pub name: String,
+  pub cache: BlockCache<(P::Bounds,u64,Summary)>,
+  entry_size: Mutex<Option<u64>>
 }
 
+// (count, sum, min, max) of a summary
+type SummaryBytes = (u64,f64,f64,f64);
+const SUMMARY_SIZE: u64 = 32;
+
 impl<S,P> DataRange<S,P>
 where S: RandomAccess<Error=Error>, P: Point {
-  pub fn new (store: S, cache_size: usize) -> Self {
+  pub fn new (store: S, name: String, cache_size: usize) -> Self {
     Self {
-      store,
-      cache: LruCache::new(cache_size)
+      store: Mutex::new(store),
+      name,
+      cache: BlockCache::new(cache_size),
+      entry_size: Mutex::new(None)
+    }
+  }
+  pub fn write (&self, b: &(u64,P::Bounds,u64), summary: &Summary)
+  -> Result<(),Error> {
+    let data = (b.0,b.1,b.2,to_tuple(summary)).to_bytes()?;
+    *self.entry_size.acquire()? = Some(data.len() as u64);
+    {
+      let mut store = self.store.acquire()?;
+      let offset = store.len()?;
+      store.write(offset, &data)?;
+    }
+    self.cache.put(b.0, (b.1,b.2,*summary))
+  }
+  /// Look up the entry for the block at `block`.
+  pub fn get (&self, block: u64)
+  -> Result<Option<(P::Bounds,u64,Summary)>,Error> {
+    if let Some(r) = self.cache.get(block)? {
+      return Ok(Some(r))
+    }
+    let buf = {
+      let mut store = self.store.acquire()?;
+      let (position,size) = match self.position(&mut *store, block)? {
+        None => return Ok(None),
+        Some(p) => p
+      };
+      store.read(position, size)?
+    };
+    let (_,(_,bbox,len,s)) =
+      <(u64,P::Bounds,u64,SummaryBytes)>::from_bytes(&buf)?;
+    let summary = Summary { count: s.0, sum: s.1, min: s.2, max: s.3 };
+    self.cache.put(block, (bbox,len,summary))?;
+    Ok(Some((bbox,len,summary)))
+  }
+  /// Subtract `cleared` deleted records from the length of the entry for
+  /// `block` and replace its summary with `summary`.
+  pub fn remove (&self, block: u64, cleared: u64, summary: &Summary)
+  -> Result<(),Error> {
+    let (bbox,len,_) = match self.get(block)? {
+      None => bail!["no range entry for data block at offset {}", block],
+      Some(r) => r
+    };
+    let len = len - cleared.min(len);
+    {
+      let mut store = self.store.acquire()?;
+      let (position,size) = self.position(&mut *store, block)?.unwrap();
+      store.write(position+size-SUMMARY_SIZE-8,
+        &(len,to_tuple(summary)).to_bytes()?)?;
     }
+    self.cache.put(block, (bbox,len,*summary))
   }
-  pub fn write (&mut self, b: &(u64,P::Range,u64)) -> Result<(),Error> {
-    let offset = self.store.len()?;
-    let data = b.to_bytes()?;
-    self.store.write(offset, &data)
+  /// Replace the summary of the entry for `block`.
+  pub fn set_summary (&self, block: u64, summary: &Summary)
+  -> Result<(),Error> {
+    let (bbox,len,_) = match self.get(block)? {
+      None => bail!["no range entry for data block at offset {}", block],
+      Some(r) => r
+    };
+    {
+      let mut store = self.store.acquire()?;
+      let (position,size) = self.position(&mut *store, block)?.unwrap();
+      store.write(position+size-SUMMARY_SIZE,
+        &to_tuple(summary).to_bytes()?)?;
+    }
+    self.cache.put(block, (bbox,len,*summary))
+  }
+  /// Save the entry for `block` that `remove()` or `set_summary()` will
+  /// modify.
+  pub fn journal (&self, journal: &mut Journal<S>, block: u64)
+  -> Result<(),Error> {
+    let mut store = self.store.acquire()?;
+    if let Some((position,size)) = self.position(&mut *store, block)? {
+      journal.keep_range(&self.name, &mut *store, position, size)?;
+    }
+    Ok(())
+  }
+  /// Switch to the empty store `store` named `name`, returning the store
+  /// that was in use.
+  pub fn replace (&mut self, store: S, name: String) -> Result<S,Error> {
+    let old = std::mem::replace(&mut *self.store.acquire()?, store);
+    self.name = name;
+    self.cache.clear()?;
+    Ok(old)
+  }
+  // binary search `store` for the byte position and size of the entry for
+  // `block`
+  fn position (&self, store: &mut S, block: u64)
+  -> Result<Option<(u64,u64)>,Error> {
+    let len = store.len()?;
+    if len == 0 { return Ok(None) }
+    let mut entry_size = self.entry_size.acquire()?;
+    let size = match *entry_size {
+      Some(size) => size,
+      None => {
+        let buf = store.read(0, len.min(4096))?;
+        let size = <(u64,P::Bounds,u64,SummaryBytes)>::count_from_bytes(&buf)?;
+        *entry_size = Some(size as u64);
+        size as u64
+      }
+    };
+    drop(entry_size);
+    let (mut lo, mut hi) = (0, len/size);
+    while lo < hi {
+      let mid = (lo+hi)/2;
+      let buf = store.read(mid*size, 8)?;
+      let offset = u64::from_bytes(&buf)?.1;
+      if offset == block {
+        return Ok(Some((mid*size,size)))
+      } else if offset < block {
+        lo = mid+1;
+      } else {
+        hi = mid;
+      }
+    }
+    Ok(None)
   }
-  pub fn list (&mut self) -> Result<Vec<(u64,P,u64)>,Error> {
-    let len = self.store.len()?;
-    // TODO: read in chunks instead of all at once
-    let buf = self.store.read(0, len)?;
+  pub fn list (&self) -> Result<Vec<(u64,P::Range,u64)>,Error> {
+    let buf = {
+      let mut store = self.store.acquire()?;
+      let len = store.len()?;
+      // TODO: read in chunks instead of all at once
+      store.read(0, len)?
+    };
+    let len = buf.len() as u64;
     let mut offset = 0usize;
-    let mut results: Vec<(u64,P,u64)> = vec![];
+    let mut results: Vec<(u64,P::Range,u64)> = vec![];
     while (offset as u64) < len {
-      let (size, result) = <(u64,P,u64)>::from_bytes(&buf[offset..])?;
-      results.push(result);
+      let (size, (block,bbox,len,_)) =
+        <(u64,P::Bounds,u64,SummaryBytes)>::from_bytes(&buf[offset..])?;
+      results.push((block,P::bounds_to_range(bbox),len));
       offset += size;
     }
     Ok(results)
   }
 }
+
+fn to_tuple (s: &Summary) -> SummaryBytes {
+  (s.count,s.sum,s.min,s.max)
+}
+
+const CACHE_SHARDS: usize = 16;
+
+/// LRU cache keyed by block offset. The cache is split into shards that are
+/// locked separately, so readers of different blocks rarely wait on each other.
+pub struct BlockCache<T> where T: Clone {
+  shards: Vec<Mutex<LruCache<u64,T>>>
+}
+
+impl<T> BlockCache<T> where T: Clone {
+  pub fn new (size: usize) -> Self {
+    let n = CACHE_SHARDS.min(size.max(1));
+    Self {
+      shards: (0..n).map(|i| {
+        Mutex::new(LruCache::new(size/n + if i < size%n { 1 } else { 0 }))
+      }).collect()
+    }
+  }
+  pub fn get (&self, block: u64) -> Result<Option<T>,Error> {
+    Ok(self.shard(block)?.get(&block).cloned())
+  }
+  pub fn put (&self, block: u64, value: T) -> Result<(),Error> {
+    self.shard(block)?.put(block, value);
+    Ok(())
+  }
+  pub fn pop (&self, block: u64) -> Result<(),Error> {
+    self.shard(block)?.pop(&block);
+    Ok(())
+  }
+  /// Modify the cached value for `block` with `f` if there is one.
+  pub fn update<F> (&self, block: u64, f: F) -> Result<(),Error>
+  where F: FnOnce(&mut T) {
+    if let Some(value) = self.shard(block)?.get_mut(&block) { f(value) }
+    Ok(())
+  }
+  pub fn clear (&self) -> Result<(),Error> {
+    for shard in self.shards.iter() {
+      shard.acquire()?.clear();
+    }
+    Ok(())
+  }
+  fn shard (&self, block: u64) -> Result<MutexGuard<'_,LruCache<u64,T>>,Error> {
+    // block offsets are byte offsets, so mix the bits before picking a shard
+    let h = block.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+    self.shards[(h as usize) % self.shards.len()].acquire()
+  }
+}
@@ -0,0 +1,225 @@
+use crate::{Point,Value,Id,QueryIterator};
+use crate::journal::Journal;
+use random_access_storage::RandomAccess;
+use desert::{ToBytes,FromBytes};
+use failure::{Error,ensure};
+use std::collections::{HashMap,VecDeque};
+
+/// Earlier version of a record kept for `db.query_as_of()` as
+/// `(point, value, id, inserted, deleted)` where `inserted` and `deleted` are
+/// the batch numbers of the insert and the delete.
+pub type Version<P,V> = (P,V,Id,u64,u64);
+
+// (batch number, first id, number of inserts, reinserted ids, versions deleted
+// by the batch as (point, value, id, inserted))
+type Record<P,V> = (u64,Id,u64,Vec<Id>,Vec<(P,V,Id,u64)>);
+
+// the history file starts with the offset of the oldest record that is kept
+// and the number of the latest batch
+const HEADER_LEN: u64 = 16;
+
+/// Version history for databases opened with `Setup::versioned(true)`.
+///
+/// Every batch in versioned mode is numbered. The batch number is separate
+/// from the database generation, which only changes when records move. Record
+/// ids are assigned in order, so the batch each record was inserted in is
+/// stored as the first id of each batch, along with the batch of records that
+/// were inserted again by a `Row::Replace`. Deleted and replaced records are
+/// copied into the history before their bits are cleared from the data blocks.
+///
+/// Each batch appends one record to the history file. Records of batches at or
+/// before the horizon are dropped by moving the start offset in the header
+/// past them. Once the dropped records take up more space than the records
+/// that are kept, the kept records are moved to the front of the file.
+pub struct History<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  store: S,
+  pub batch: u64,
+  pub horizon: u64,
+  // (first id, batch) for each batch that inserted records
+  inserts: Vec<(Id,u64)>,
+  reinserts: HashMap<Id,u64>,
+  pub versions: Vec<Version<P,V>>,
+  // (batch, offset) of each record in the file that is kept
+  offsets: VecDeque<(u64,u64)>,
+  end: u64
+}
+
+impl<S,P,V> History<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  pub fn open (store: S, horizon: u64) -> Result<Self,Error> {
+    let mut history = Self {
+      store,
+      batch: 0,
+      horizon,
+      inserts: vec![],
+      reinserts: HashMap::new(),
+      versions: vec![],
+      offsets: VecDeque::new(),
+      end: HEADER_LEN
+    };
+    if !history.store.is_empty()? {
+      let len = history.store.len()?;
+      let buf = history.store.read(0,len)?;
+      history.load_buffer(&buf)?;
+    }
+    Ok(history)
+  }
+  fn load_buffer (&mut self, buf: &[u8]) -> Result<(),Error> {
+    ensure![buf.len() as u64 >= HEADER_LEN, "unexpected history length"];
+    let (_,(start,batch)) = <(u64,u64)>::from_bytes(buf)?;
+    ensure![HEADER_LEN <= start && start <= buf.len() as u64,
+      "unexpected history start offset {}", start];
+    let mut offset = start as usize;
+    while offset < buf.len() {
+      let (size,record) = <Record<P,V>>::from_bytes(&buf[offset..])?;
+      self.offsets.push_back((record.0, offset as u64));
+      self.apply(record);
+      offset += size;
+    }
+    self.batch = batch;
+    self.end = buf.len() as u64;
+    Ok(())
+  }
+  // add the inserts, reinserts and deleted versions of a batch
+  fn apply (&mut self, record: Record<P,V>) {
+    let (batch,next_id,ninserts,reinserts,deleted) = record;
+    if ninserts > 0 {
+      self.inserts.push((next_id,batch));
+    }
+    for (p,v,id,inserted) in deleted {
+      self.versions.push((p,v,id,inserted,batch));
+    }
+    for id in reinserts {
+      self.reinserts.insert(id,batch);
+    }
+  }
+  /// Save what the next batch will modify to `journal`: the header and the
+  /// length of the file, or the whole file if the batch moves the records.
+  pub fn journal (&mut self, journal: &mut Journal<S>) -> Result<(),Error> {
+    if self.compacts(self.oldest_after(self.batch+1)) {
+      return journal.keep("history", &mut self.store)
+    }
+    journal.keep_len("history", &self.store)?;
+    if !self.store.is_empty()? {
+      journal.keep_range("history", &mut self.store, 0, HEADER_LEN)?;
+    }
+    Ok(())
+  }
+  /// Record and append a new batch where the ids from `next_id` up to
+  /// `next_id+ninserts` were inserted, the records in `reinserts` were
+  /// inserted again, and the records in `deleted` were deleted. Versions
+  /// deleted at or before the horizon are discarded.
+  pub fn push (&mut self, next_id: Id, ninserts: u64, reinserts: &[Id],
+  deleted: Vec<(P,V,Id)>) -> Result<(),Error> {
+    let batch = self.batch + 1;
+    let deleted: Vec<(P,V,Id,u64)> = deleted.into_iter()
+      .map(|(p,v,id)| { let inserted = self.inserted(id); (p,v,id,inserted) })
+      .collect();
+    let record: Record<P,V> = (batch,next_id,ninserts,reinserts.to_vec(),
+      deleted);
+    let oldest = self.oldest_after(batch);
+    let compact = self.compacts(oldest);
+    let bytes = record.to_bytes()?;
+    self.store.write(self.end, &bytes)?;
+    self.offsets.push_back((batch, self.end));
+    self.end += bytes.len() as u64;
+    self.batch = batch;
+    self.apply(record);
+    self.prune(oldest, compact)?;
+    self.store.sync_all()?;
+    Ok(())
+  }
+  // drop the records of batches at or before `oldest`
+  fn prune (&mut self, oldest: u64, compact: bool) -> Result<(),Error> {
+    while self.offsets.front().map_or(false, |(b,_)| *b <= oldest) {
+      self.offsets.pop_front();
+    }
+    self.inserts.retain(|(_,b)| *b > oldest);
+    self.reinserts.retain(|_,b| *b > oldest);
+    self.versions.retain(|version| version.4 > oldest);
+    let mut start = self.offsets.front().map_or(self.end, |(_,o)| *o);
+    if compact && start > HEADER_LEN {
+      let buf = self.store.read(start, self.end-start)?;
+      self.store.write(HEADER_LEN, &buf)?;
+      self.store.truncate(HEADER_LEN + buf.len() as u64)?;
+      let shift = start - HEADER_LEN;
+      for (_,offset) in self.offsets.iter_mut() {
+        *offset -= shift;
+      }
+      self.end -= shift;
+      start = HEADER_LEN;
+    }
+    self.store.write(0, &(start,self.batch).to_bytes()?)?;
+    Ok(())
+  }
+  // whether dropping the records at or before `oldest` leaves more dropped
+  // bytes than kept bytes at the front of the file
+  fn compacts (&self, oldest: u64) -> bool {
+    let start = self.offsets.iter().find(|(b,_)| *b > oldest)
+      .map_or(self.end, |(_,o)| *o);
+    start - HEADER_LEN > self.end - start
+  }
+  fn oldest_after (&self, batch: u64) -> u64 {
+    batch.saturating_sub(self.horizon)
+  }
+  /// Oldest batch number that can still be queried.
+  pub fn oldest (&self) -> u64 {
+    self.oldest_after(self.batch)
+  }
+  /// Number of the batch that inserted the current version of the record
+  /// `id`. Records inserted before versioning was enabled or by a batch
+  /// before the horizon are from batch 0.
+  pub fn inserted (&self, id: Id) -> u64 {
+    if let Some(b) = self.reinserts.get(&id) {
+      return *b
+    }
+    match self.inserts.binary_search_by(|(first,_)| first.cmp(&id)) {
+      Ok(i) => self.inserts[i].1,
+      Err(0) => 0,
+      Err(i) => self.inserts[i-1].1
+    }
+  }
+}
+
+/// Iterator of `Result<(Point,Value,Id)>` data returned by `db.query_as_of()`.
+pub struct AsOfIterator<'a,'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  history: &'a History<S,P,V>,
+  batch: u64,
+  live: QueryIterator<'b,S,P,V>,
+  versions: Vec<(P,V,Id)>
+}
+
+impl<'a,'b,S,P,V> AsOfIterator<'a,'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  pub fn new (history: &'a History<S,P,V>, live: QueryIterator<'b,S,P,V>,
+  bbox: &P::Bounds, batch: u64) -> Self {
+    let versions = history.versions.iter()
+      .filter(|(p,_,_,inserted,deleted)| {
+        *inserted <= batch && batch < *deleted && p.overlaps(bbox)
+      })
+      .map(|(p,v,id,_,_)| (*p,v.clone(),*id))
+      .collect();
+    Self { history, batch, live, versions }
+  }
+}
+
+impl<'a,'b,S,P,V> Iterator for AsOfIterator<'a,'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  type Item = Result<(P,V,Id),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    if let Some(row) = self.versions.pop() {
+      return Some(Ok(row));
+    }
+    loop {
+      let (p,v,loc) = match self.live.next()? {
+        Ok(row) => row,
+        Err(e) => return Some(Err(e))
+      };
+      if self.history.inserted(loc.2) <= self.batch {
+        return Some(Ok((p,v,loc.2)));
+      }
+    }
+  }
+}
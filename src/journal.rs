@@ -0,0 +1,168 @@
+use failure::{Error,bail,format_err};
+use std::convert::{TryFrom,TryInto};
+use random_access_storage::RandomAccess;
+use desert::{ToBytes,FromBytes};
+
+// (store name, original length, original bytes at offsets, name of a store
+// with a copy of the original contents or an empty name)
+type Entry = (Vec<u8>,u64,Vec<(u64,Vec<u8>)>,Vec<u8>);
+
+// bytes of the payload length and checksum before the payload
+const HEADER_LEN: usize = 12;
+
+/// Undo log that makes `db.batch()` atomic.
+///
+/// Before a batch modifies any store, the original length of each store and
+/// the original bytes of every region that will be overwritten are written to
+/// the journal store and synced. Once every store has been synced after the
+/// batch, the journal is truncated. A non-empty, complete journal found while
+/// opening the database means a batch was interrupted, so stores with a copy
+/// are restored from the copy, the saved regions are written back and each
+/// store is truncated to its original length.
+pub struct Journal<S> where S: RandomAccess<Error=Error> {
+  store: S,
+  entries: Vec<Entry>
+}
+
+impl<S> Journal<S> where S: RandomAccess<Error=Error> {
+  pub fn open (store: S) -> Result<Self,Error> {
+    Ok(Self { store, entries: vec![] })
+  }
+  /// Roll back an interrupted batch, if there is one. Stores are opened by
+  /// name with `open_store`.
+  pub fn recover<U> (&mut self, open_store: &U) -> Result<bool,Error>
+  where U: Fn(&str) -> Result<S,Error> {
+    if self.store.is_empty()? { return Ok(false) }
+    let len = self.store.len()?;
+    let buf = self.store.read(0, len)?;
+    let entries = match parse(&buf)? {
+      // the journal itself was not completely written, so none of the stores
+      // have been modified yet
+      None => {
+        self.clear()?;
+        return Ok(false)
+      },
+      Some(entries) => entries
+    };
+    for (name,length,patches,source) in entries.iter() {
+      let mut store = open_store(&String::from_utf8(name.clone())?)?;
+      if !source.is_empty() {
+        let mut copy = open_store(&String::from_utf8(source.clone())?)?;
+        if *length > 0 {
+          store.write(0, &copy.read(0, *length)?)?;
+        }
+        store.sync_all()?;
+        // nothing reads the copy after a restart
+        copy.truncate(0)?;
+        copy.sync_all()?;
+      }
+      // apply in reverse so the earliest saved copy of a region wins
+      for (offset,data) in patches.iter().rev() {
+        store.write(*offset, data)?;
+      }
+      if store.len()? > *length {
+        store.truncate(*length)?;
+      }
+      store.sync_all()?;
+    }
+    self.clear()?;
+    Ok(true)
+  }
+  /// Save the length of `store` so that appended data can be discarded.
+  pub fn keep_len<T> (&mut self, name: &str, store: &T) -> Result<(),Error>
+  where T: RandomAccess<Error=Error> {
+    let length = store.len()?;
+    self.entry(name, length);
+    Ok(())
+  }
+  /// Save the length and full contents of `store`.
+  pub fn keep<T> (&mut self, name: &str, store: &mut T) -> Result<(),Error>
+  where T: RandomAccess<Error=Error> {
+    let length = store.len()?;
+    let data = if length > 0 { store.read(0, length)? } else { vec![] };
+    self.entry(name, length).push((0,data));
+    Ok(())
+  }
+  /// Save the length of `store` and the name of the store `source`, which
+  /// holds a synced copy of the contents of `store` that is kept until the
+  /// journal is committed.
+  pub fn keep_copy<T> (&mut self, name: &str, store: &T, source: &str)
+  -> Result<(),Error> where T: RandomAccess<Error=Error> {
+    let length = store.len()?;
+    let i = self.entry_index(name, length);
+    if self.entries[i].3.is_empty() {
+      self.entries[i].3 = source.as_bytes().to_vec();
+    }
+    Ok(())
+  }
+  /// Save the length of `store` and `length` bytes starting at `offset`.
+  pub fn keep_range<T> (&mut self, name: &str, store: &mut T, offset: u64,
+  length: u64) -> Result<(),Error> where T: RandomAccess<Error=Error> {
+    let store_len = store.len()?;
+    let data = store.read(offset, length)?;
+    self.entry(name, store_len).push((offset,data));
+    Ok(())
+  }
+  /// Write and sync the journal. Stores may be modified after this returns.
+  pub fn begin (&mut self) -> Result<(),Error> {
+    let payload = self.entries.to_bytes()?;
+    let len = u64::try_from(payload.len()).map_err(|_| {
+      format_err!["journal of {} bytes is too long", payload.len()]
+    })?;
+    let mut buf = Vec::with_capacity(HEADER_LEN+payload.len());
+    buf.extend(&len.to_be_bytes());
+    buf.extend(&checksum(&payload).to_be_bytes());
+    buf.extend(&payload);
+    self.store.truncate(0)?;
+    self.store.write(0, &buf)?;
+    self.store.sync_all()?;
+    Ok(())
+  }
+  /// Discard the journal after every modified store has been synced.
+  pub fn commit (&mut self) -> Result<(),Error> {
+    self.clear()
+  }
+  fn clear (&mut self) -> Result<(),Error> {
+    self.entries.clear();
+    self.store.truncate(0)?;
+    self.store.sync_all()?;
+    Ok(())
+  }
+  fn entry (&mut self, name: &str, length: u64) -> &mut Vec<(u64,Vec<u8>)> {
+    let i = self.entry_index(name, length);
+    &mut self.entries[i].2
+  }
+  fn entry_index (&mut self, name: &str, length: u64) -> usize {
+    let bname = name.as_bytes();
+    match self.entries.iter().position(|e| e.0 == bname) {
+      Some(i) => i,
+      None => {
+        self.entries.push((bname.to_vec(),length,vec![],vec![]));
+        self.entries.len()-1
+      }
+    }
+  }
+}
+
+fn parse (buf: &[u8]) -> Result<Option<Vec<Entry>>,Error> {
+  if buf.len() < HEADER_LEN { return Ok(None) }
+  let len = u64::from_be_bytes(buf[0..8].try_into()?);
+  let sum = u32::from_be_bytes(buf[8..12].try_into()?);
+  let payload = &buf[HEADER_LEN..];
+  if payload.len() as u64 != len || checksum(payload) != sum {
+    return Ok(None)
+  }
+  let (size,entries) = <Vec<Entry>>::from_bytes(payload)?;
+  if size as u64 != len { bail!["unexpected journal length"] }
+  Ok(Some(entries))
+}
+
+// fnv-1a
+fn checksum (buf: &[u8]) -> u32 {
+  let mut h = 0x811c9dc5u32;
+  for b in buf {
+    h ^= *b as u32;
+    h = h.wrapping_mul(0x01000193);
+  }
+  h
+}
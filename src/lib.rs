@@ -15,9 +15,19 @@
 //! and query by bounding box. All features that intersect the bounding box are
 //! returned in the query results.
 //!
-//! This is an early release missing important features such as atomicity and
-//! concurrency. The data format is still in flux and will likely change in the
-//! future, requiring data migrations.
+//! A `DB` is `Send` and `Sync` when its storage is, so queries can run from many
+//! threads at once while writes need exclusive access, for example by sharing
+//! the database in an `Arc<RwLock<DB>>`.
+//!
+//! For async code, `AsyncDB` provides the same batch and query operations as
+//! futures and streams over storage that implements `AsyncRandomAccess`. It
+//! keeps a copy of the database files in memory and awaits the storage writes
+//! of each batch in order.
+//!
+//! This is an early release. The data format is still in flux and will likely
+//! change in the future. Each database records its format version, and databases
+//! in an older format can be rewritten with `eyros::migrate()` or the `migrate`
+//! binary.
 //!
 //! [bkd]: https://users.cs.duke.edu/~pankaj/publications/papers/bkd-sstd.pdf
 //! [interval]: http://www.dgp.toronto.edu/~jstewart/378notes/22intervals/
@@ -76,24 +86,24 @@
 //!
 //! ```sh
 //! $ cargo run --example polygons -q
-//! (((-0.014986515, -0.014986515), (-0.5801666, -0.5801663), 45.314373), 1518966744, (0, 200))
-//! (((-0.0892005, -0.015534878), (-0.65783, -0.65783), 3.6987066), 66257667, (0, 267))
-//! (((0.1931547, 0.1931547), (-0.6388786, -0.60205233), 67.85113), 2744609531, (0, 496))
-//! (((-0.28907382, -0.26248854), (-0.7761978, -0.77617484), 55.273056), 3622408505, (0, 651))
-//! (((-0.080417514, -0.080417514), (-0.60076225, -0.5929384), 29.592216), 722871034, (0, 784))
-//! (((0.14104307, 0.14104307), (-0.539363, -0.539363), 31.965792), 2866780128, (0, 933))
-//! (((-0.12689173, -0.12689173), (-0.56708515, -0.56643564), 65.072), 1858542500, (0, 983))
-//! (((-0.12520671, -0.1250745), (-0.6836084, -0.6836084), 93.58209), 3942792215, (0, 1019))
-//! (((0.026417613, 0.026417613), (-0.786397, -0.786397), 61.52451), 1197187917, (0, 1102))
-//! (((-0.18799019, -0.18799017), (-0.50418067, -0.50418067), 82.93134), 2811117540, (0, 1199))
-//! (((-0.34033966, -0.34033966), (-0.53603613, -0.53603613), 91.07471), 302136936, (0, 1430))
-//! (((-0.008744121, 0.54438573), (-0.73665094, -0.73665094), 69.67532), 719725479, (0, 1504))
-//! (((-0.38071227, -0.38071224), (-0.75237143, -0.75237143), 72.245895), 2200140390, (0, 1628))
-//! (((0.020396352, 0.020396352), (-0.7957357, -0.77274036), 40.785194), 2166765724, (0, 1708))
-//! (((0.117452025, 0.117452025), (-0.7027955, -0.7026706), 82.033394), 2451987859, (0, 1886))
-//! (((-0.11418259, -0.11418259), (-0.74327374, -0.74327374), 28.591274), 4283568770, (0, 1983))
-//! (((-0.19130886, -0.19130856), (-0.7012402, -0.7012042), 2.1106005), 4226013993, (0, 2048))
-//! (((-0.3000791, -0.3000791), (-0.7601782, -0.7601782), 24.528027), 2776778380, (0, 2349))
+//! (((-0.014986515, -0.014986515), (-0.5801666, -0.5801663), 45.314373), 1518966744, (0, 200, 200, 0))
+//! (((-0.0892005, -0.015534878), (-0.65783, -0.65783), 3.6987066), 66257667, (0, 267, 267, 0))
+//! (((0.1931547, 0.1931547), (-0.6388786, -0.60205233), 67.85113), 2744609531, (0, 496, 496, 0))
+//! (((-0.28907382, -0.26248854), (-0.7761978, -0.77617484), 55.273056), 3622408505, (0, 651, 651, 0))
+//! (((-0.080417514, -0.080417514), (-0.60076225, -0.5929384), 29.592216), 722871034, (0, 784, 784, 0))
+//! (((0.14104307, 0.14104307), (-0.539363, -0.539363), 31.965792), 2866780128, (0, 933, 933, 0))
+//! (((-0.12689173, -0.12689173), (-0.56708515, -0.56643564), 65.072), 1858542500, (0, 983, 983, 0))
+//! (((-0.12520671, -0.1250745), (-0.6836084, -0.6836084), 93.58209), 3942792215, (0, 1019, 1019, 0))
+//! (((0.026417613, 0.026417613), (-0.786397, -0.786397), 61.52451), 1197187917, (0, 1102, 1102, 0))
+//! (((-0.18799019, -0.18799017), (-0.50418067, -0.50418067), 82.93134), 2811117540, (0, 1199, 1199, 0))
+//! (((-0.34033966, -0.34033966), (-0.53603613, -0.53603613), 91.07471), 302136936, (0, 1430, 1430, 0))
+//! (((-0.008744121, 0.54438573), (-0.73665094, -0.73665094), 69.67532), 719725479, (0, 1504, 1504, 0))
+//! (((-0.38071227, -0.38071224), (-0.75237143, -0.75237143), 72.245895), 2200140390, (0, 1628, 1628, 0))
+//! (((0.020396352, 0.020396352), (-0.7957357, -0.77274036), 40.785194), 2166765724, (0, 1708, 1708, 0))
+//! (((0.117452025, 0.117452025), (-0.7027955, -0.7026706), 82.033394), 2451987859, (0, 1886, 1886, 0))
+//! (((-0.11418259, -0.11418259), (-0.74327374, -0.74327374), 28.591274), 4283568770, (0, 1983, 1983, 0))
+//! (((-0.19130886, -0.19130856), (-0.7012402, -0.7012042), 2.1106005), 4226013993, (0, 2048, 2048, 0))
+//! (((-0.3000791, -0.3000791), (-0.7601782, -0.7601782), 24.528027), 2776778380, (0, 2349, 2349, 0))
 //! ```
 //!
 //! The `coords` and `value` are the values that were written earlier: in this case,
@@ -102,7 +112,11 @@
 //! The `location` is used to quickly delete records without needing to perform
 //! additional lookups. You'll need to keep the `location` around from the result of
 //! a query when you intend to delete a record. Locations that begin with a `0` are
-//! stored in the staging cache, so their location may change after the next write.
+//! stored in the staging cache. The third element of a location is the record
+//! id, which stays the same when later writes move the record. The last element
+//! is the generation of the database when the location was returned. A location
+//! can only be used to delete a record until a later batch moves records
+//! around, after which `db.locate(id)` returns a current location.
 //!
 //! # mix example
 //!
@@ -182,25 +196,54 @@ mod data;
 mod read_block;
 mod pivots;
 mod write_cache;
+mod journal;
+mod lock;
+mod async_db;
+mod snapshot;
+mod nearest;
+mod aggregate;
+mod page;
+mod ordered;
+mod region;
+mod many;
+mod history;
+mod bulk;
+mod migrate;
 
 pub use crate::setup::{Setup,SetupFields};
+pub use crate::async_db::{AsyncDB,AsyncRandomAccess,QueryStream};
+pub use crate::snapshot::Snapshot;
+pub use crate::aggregate::{Aggregate,Measure,Summary};
+pub use crate::page::Token;
+pub use crate::ordered::OrderedIterator;
+pub use crate::region::{QueryRegion,Radius,Polygon,RegionIterator};
+pub use crate::many::{QueryManyIterator,Matched};
+pub use crate::history::{AsOfIterator,Version};
+pub use crate::migrate::migrate;
+use crate::history::History;
+use crate::bulk::{Run,Merge};
+use crate::nearest::Entry;
+use crate::read_block::read_block;
 use crate::staging::{Staging,StagingIterator};
 use crate::planner::plan;
-pub use crate::point::{Point,Scalar,Cursor,Block};
+pub use crate::point::{Point,Spatial,Predicate,Scalar,ToF64,Cursor,Block,Extents,
+  branch_regions,Distance,Euclidean};
 pub use crate::mix::{Mix,Mix2,Mix3,Mix4,Mix5,Mix6,Mix7,Mix8};
 #[doc(hidden)] pub use crate::tree::{Tree,TreeIterator,TreeOpts};
 #[doc(hidden)] pub use crate::branch::Branch;
 #[doc(hidden)] pub use crate::data::{DataStore,DataRange};
-use crate::meta::Meta;
+use crate::meta::{Meta,Config,fingerprint,fnv1a};
+pub use crate::meta::FORMAT_VERSION;
+use crate::journal::Journal;
+#[doc(hidden)] pub use crate::lock::{Acquire,ReadWrite};
 pub use order::{order,order_len};
 
 use random_access_storage::RandomAccess;
-use failure::{Error,format_err};
+use failure::{Error,format_err,bail,ensure};
 use desert::{ToBytes,FromBytes,CountBytes};
 use std::fmt::Debug;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::collections::HashSet;
+use std::sync::{Arc,Mutex,RwLock};
+use std::collections::{HashSet,HashMap,BinaryHeap};
 
 #[doc(hidden)]
 pub enum SubIterator<'b,S,P,V>
@@ -213,18 +256,45 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
 pub trait Value: Debug+Clone+ToBytes+FromBytes+CountBytes+'static {}
 impl<T> Value for T where T: Debug+Clone+ToBytes+FromBytes+CountBytes+'static {}
 
-/// Stores where a record is stored to avoid additional queries during deletes.
-/// Locations are only valid until the next `batch()`. There is no runtime check
-/// yet to ensure that batches will invalidate existing locations, so you will
-/// need to be careful of this yourself. Otherwise the wrong data could be
-/// deleted.
-pub type Location = (u64,u32);
+/// Durable identifier assigned to each record when it is inserted.
+///
+/// Record ids do not change when a record moves into a different data block
+/// during a `batch()`.
+pub type Id = u64;
 
-/// Container to insert or delete data for a `batch()`.
+/// Stores where a record is stored to avoid additional queries during deletes
+/// as `(block, index, id, generation)`.
+///
+/// The `block` and `index` describe where the record was stored when it was
+/// returned from a query and they change when later batches merge trees or
+/// flush the staging cache. Each of those batches increments the database
+/// generation, and `batch()` returns an error for a `Row::Delete` with a
+/// location from a different generation. Use `db.locate(id)` to get a current
+/// location for a record id, or `db.batch_relocate()` to look up stale
+/// locations while writing.
+pub type Location = (u64,u32,Id,u64);
+
+/// Container to insert, delete, or replace data for a `batch()`.
+///
+/// `Row::DeleteBounds(bbox)` deletes every record that intersects `bbox`.
+/// Records inserted by the same batch are not deleted.
 #[derive(Clone,Debug)]
 pub enum Row<P,V> where P: Point, V: Value {
   Insert(P,V),
-  Delete(Location)
+  Delete(Location),
+  Replace(Location,P,V),
+  DeleteBounds(P::Bounds)
+}
+
+// how the `Row::Replace` rows in a batch will be written
+struct Replacements<P,V> where P: Point, V: Value {
+  // (staging index, row)
+  staging: Vec<(usize,(P,V,Id))>,
+  // (block, byte offset, row) to overwrite in place
+  rows: Vec<(u64,u64,(P,V,Id))>,
+  // records to delete and insert again with the same id
+  tombstones: Vec<Location>,
+  reinserts: Vec<(P,V,Id)>
 }
 
 /// Top-level database API.
@@ -233,10 +303,17 @@ S: RandomAccess<Error=Error>,
 U: (Fn(&str) -> Result<S,Error>),
 P: Point, V: Value {
   open_store: U,
-  pub trees: Vec<Rc<RefCell<Tree<S,P,V>>>>,
+  pub trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
   pub staging: Staging<S,P,V>,
-  pub data_store: Rc<RefCell<DataStore<S,P,V>>>,
+  pub data_store: Arc<RwLock<DataStore<S,P,V>>>,
   meta: Meta<S>,
+  journal: Journal<S>,
+  history: Option<History<S,P,V>>,
+  // number of trees copied before they were rebuilt, used to name the copies
+  detached: u64,
+  // trees that read from the copies that the journal restores from, kept
+  // until the journal is committed so their copies aren't truncated
+  undo: Vec<Arc<Mutex<Tree<S,P,V>>>>,
   pub fields: SetupFields
 }
 
@@ -325,43 +402,244 @@ P: Point, V: Value {
   /// # }
   /// ```
   ///
-  /// Always open a database with the same settings. Things will break if you
-  /// change . There is no runtime check yet to ensure a database is opened with
-  /// the same configuration that it was created with.
+  /// The branch factor, base size, max data size, versioned mode and history
+  /// horizon are stored in the meta file when a database is created. When an
+  /// existing database is opened, the stored settings are used instead of the
+  /// ones in `setup`, so `db.fields` holds the settings the database actually
+  /// uses. Opening a database with a different point dimension or different
+  /// point or value types than it was created with is an error.
   pub fn open_from_setup(setup: Setup<S,U>) -> Result<Self,Error> {
-    let meta = Meta::open((setup.open_store)("meta")?)?;
+    Self::open_with_measure(setup, None)
+  }
+
+  /// Create a new database instance from `setup` like `open_from_setup()`
+  /// that also stores a summary of the measured values of the records in each
+  /// data block it writes. `db.aggregate()` answers blocks inside of its
+  /// bounds from these summaries instead of reading them.
+  pub fn open_measured_from_setup(setup: Setup<S,U>) -> Result<Self,Error>
+  where V: Measure {
+    Self::open_with_measure(setup, Some(<V as Measure>::measure))
+  }
+
+  fn open_with_measure(mut setup: Setup<S,U>,
+  measure: Option<fn(&V) -> f64>) -> Result<Self,Error> {
+    let mut journal = Journal::open((setup.open_store)("journal")?)?;
+    let recovered = journal.recover(&setup.open_store)?;
+    let mut meta = Meta::open((setup.open_store)("meta")?)?;
+    Self::configure(&mut meta, &mut setup.fields)?;
     let staging = Staging::open(
       (setup.open_store)("staging_inserts")?,
       (setup.open_store)("staging_deletes")?
     )?;
+    let (data_name,range_name) = data::file_names(meta.data_files);
     let data_store = DataStore::open(
-      (setup.open_store)("data")?,
-      (setup.open_store)("range")?,
+      meta.data_files,
+      (setup.open_store)(&data_name)?,
+      (setup.open_store)(&range_name)?,
+      (setup.open_store)("ids")?,
       setup.fields.max_data_size,
       setup.fields.bbox_cache_size,
-      setup.fields.data_list_cache_size
+      setup.fields.data_list_cache_size,
+      measure
     )?;
+    let history = match setup.fields.versioned {
+      true => Some(History::open(
+        (setup.open_store)("history")?,
+        setup.fields.history_horizon
+      )?),
+      false => None
+    };
     let mut db = Self {
       open_store: setup.open_store,
       staging,
-      data_store: Rc::new(RefCell::new(data_store)),
+      data_store: Arc::new(RwLock::new(data_store)),
       meta: meta,
+      journal,
+      history,
       trees: vec![],
+      detached: 0,
+      undo: vec![],
       fields: setup.fields
     };
     for i in 0..db.meta.mask.len() {
       db.create_tree(i)?;
     }
+    if recovered {
+      // the slots of records that moved to new blocks are overwritten without
+      // being journaled, so rebuild the id index from the live trees
+      let mut blocks = vec![];
+      for tree in db.trees.iter() {
+        blocks.extend(tree.acquire()?.data_blocks()?);
+      }
+      db.data_store.write_lock()?.reindex(&blocks)?;
+    }
     Ok(db)
   }
 
+  // adopt the settings stored in `meta`, or store the settings from `fields`
+  // if there are none yet
+  fn configure (meta: &mut Meta<S>, fields: &mut SetupFields)
+  -> Result<(),Error> {
+    let config = Config {
+      branch_factor: fields.branch_factor as u16,
+      base_size: fields.base_size as u64,
+      max_data_size: fields.max_data_size as u64,
+      dimension: P::dim() as u16,
+      fingerprint: fingerprint::<P,V>(fields.tag.as_deref()),
+      versioned: fields.versioned,
+      history_horizon: fields.history_horizon
+    };
+    let stored = match &meta.config {
+      Some(stored) => stored.clone(),
+      None => {
+        meta.config = Some(config);
+        return meta.save()
+      }
+    };
+    ensure![stored.dimension == config.dimension,
+      "database has {}-dimensional points but was opened with {} dimensions",
+      stored.dimension, config.dimension];
+    ensure![stored.fingerprint == config.fingerprint,
+      "database was created with different point or value types or a \
+      different tag than {}", format!("({},{})", std::any::type_name::<P>(),
+      std::any::type_name::<V>())];
+    fields.branch_factor = stored.branch_factor as usize;
+    fields.base_size = stored.base_size as usize;
+    fields.max_data_size = stored.max_data_size as usize;
+    fields.versioned = stored.versioned;
+    fields.history_horizon = stored.history_horizon;
+    Ok(())
+  }
+
   /// Write a collection of updates to the database. Each update can be a
-  /// `Row::Insert(point,value)` or a `Row::Delete(location)`.
+  /// `Row::Insert(point,value)`, a `Row::Delete(location)`, or a
+  /// `Row::Replace(location,point,value)`.
+  ///
+  /// A replaced record keeps its record id. When the new row has the same
+  /// serialized size and the point stays inside the bounds of its data block,
+  /// the record is overwritten in place and its location doesn't change.
+  /// Otherwise the old record is deleted and the new row is inserted again,
+  /// which starts a new generation.
+  ///
+  /// Batches are atomic: the original contents of every file a batch modifies
+  /// are saved to a journal first, so if the process exits partway through a
+  /// batch, the next time the database is opened it will be rolled back to
+  /// the state before the batch. If `batch()` returns an error, drop this
+  /// instance and open the database again to roll back.
   pub fn batch (&mut self, rows: &[Row<P,V>]) -> Result<(),Error> {
-    let inserts: Vec<(P,V)> = rows.iter()
+    self.write(rows, &vec![])
+  }
+
+  /// Write a collection of updates like `batch()`, but look up the current
+  /// location of a `Row::Delete` or `Row::Replace` with a location from an
+  /// older generation by its record id instead of rejecting it. Rows for
+  /// records that were deleted since are still an error.
+  pub fn batch_relocate (&mut self, rows: &[Row<P,V>]) -> Result<(),Error> {
+    let generation = self.meta.generation;
+    let mut updated = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+      updated.push(match row {
+        Row::Delete(loc) if loc.3 != generation => {
+          Row::Delete(self.current(loc)?)
+        },
+        Row::Replace(loc,p,v) if loc.3 != generation => {
+          Row::Replace(self.current(loc)?, p.clone(), v.clone())
+        },
+        row => row.clone()
+      });
+    }
+    self.write(&updated, &vec![])
+  }
+
+  // look up the current location of the record at the stale location `loc`
+  fn current (&self, loc: &Location) -> Result<Location,Error> {
+    match self.locate(loc.2)? {
+      Some(current) => Ok(current),
+      None => bail!["stale location {:?} from generation {} for record {} \
+        which was already deleted", loc, loc.3, loc.2]
+    }
+  }
+
+  /// Write updates from an iterator without collecting them first.
+  ///
+  /// Rows are read `base_size` at a time and each group is written like a
+  /// `batch()`, so the rows are staged or merged into the trees as soon as
+  /// the staging cache fills up and memory use depends on `base_size`
+  /// instead of on the number of rows.
+  ///
+  /// Each group is atomic, but the whole iterator is not: if an error is
+  /// returned, the groups before it have already been written. Locations in
+  /// `Row::Delete` and `Row::Replace` rows from the generation the call
+  /// started in stay valid while earlier groups merge trees.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Row};
+  /// # use failure::Error;
+  /// # use std::path::PathBuf;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # fn main () -> Result<(),Error> {
+  /// # let mut db: DB<_,_,((f32,f32),(f32,f32)),u32> = DB::open(storage)?;
+  /// let rows = (0..1_000_000u32).map(|i| {
+  ///   let x = ((i % 1_000) as f32)/500.0 - 1.0;
+  ///   let y = ((i / 1_000) as f32)/500.0 - 1.0;
+  ///   Row::Insert(((x,x+0.001),(y,y+0.001)),i)
+  /// });
+  /// db.batch_iter(rows)?;
+  /// # Ok(()) }
+  /// # fn storage(name:&str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn batch_iter<I> (&mut self, rows: I) -> Result<(),Error>
+  where I: IntoIterator<Item=Row<P,V>> {
+    let generation = self.meta.generation;
+    let size = self.fields.base_size.max(1);
+    let mut rows = rows.into_iter();
+    loop {
+      let group: Vec<Row<P,V>> = rows.by_ref().take(size).collect();
+      if group.is_empty() { return Ok(()) }
+      let group = self.relocate(group, generation)?;
+      self.write(&group, &vec![])?;
+    }
+  }
+
+  // update the locations in `rows` from `generation` to the current generation
+  fn relocate (&self, rows: Vec<Row<P,V>>, generation: u64)
+  -> Result<Vec<Row<P,V>>,Error> {
+    if self.meta.generation == generation { return Ok(rows) }
+    let mut updated = Vec::with_capacity(rows.len());
+    for row in rows {
+      match row {
+        Row::Delete(loc) if loc.3 == generation => {
+          // records deleted earlier in the same iterator have no location
+          if let Some(loc) = self.locate(loc.2)? {
+            updated.push(Row::Delete(loc));
+          }
+        },
+        Row::Replace(loc,p,v) if loc.3 == generation => {
+          match self.locate(loc.2)? {
+            Some(loc) => updated.push(Row::Replace(loc,p,v)),
+            None => bail!["replaced record {} was already deleted", loc.2]
+          }
+        },
+        row => updated.push(row)
+      }
+    }
+    Ok(updated)
+  }
+
+  // write a batch and delete the records at `direct` from their data blocks
+  // right away instead of staging the deletes
+  fn write (&mut self, rows: &[Row<P,V>], direct: &Vec<Location>)
+  -> Result<(),Error> {
+    let next_id = self.meta.next_id;
+    let mut inserts: Vec<(P,V,Id)> = rows.iter()
       .filter(|r| match r { Row::Insert(_p,_v) => true, _ => false })
-      .map(|r| match r {
-        Row::Insert(p,v) => (p.clone(),v.clone()),
+      .enumerate()
+      .map(|(i,r)| match r {
+        Row::Insert(p,v) => (p.clone(),v.clone(),next_id+(i as u64)),
         _ => panic!["unexpected non-insert row type"]
       })
       .collect();
@@ -372,36 +650,116 @@ P: Point, V: Value {
         _ => panic!["unexpected non-delete row type"]
       })
       .collect();
-    let n = (self.staging.inserts.try_borrow()?.len()+inserts.len()) as u64;
-    let ndel = (self.staging.deletes.try_borrow()?.len()+deletes.len()) as u64;
+    let ninserts = inserts.len() as u64;
+    let replaces: Vec<(Location,P,V)> = rows.iter()
+      .filter(|r| match r { Row::Replace(_loc,_p,_v) => true, _ => false })
+      .map(|r| match r {
+        Row::Replace(loc,p,v) => (*loc,p.clone(),v.clone()),
+        _ => panic!["unexpected non-replace row type"]
+      })
+      .collect();
+    for loc in deletes.iter().chain(replaces.iter().map(|r| &r.0)) {
+      if loc.3 != self.meta.generation {
+        bail!["stale location {:?} from generation {} (current generation {})",
+          loc, loc.3, self.meta.generation];
+      }
+    }
+    let replace = self.plan_replace(&replaces, &deletes)?;
+    inserts.extend_from_slice(&replace.reinserts);
+    let bounds: Vec<P::Bounds> = rows.iter()
+      .filter(|r| match r { Row::DeleteBounds(_bbox) => true, _ => false })
+      .map(|r| match r {
+        Row::DeleteBounds(bbox) => *bbox,
+        _ => panic!["unexpected non-delete-bounds row type"]
+      })
+      .collect();
+    let cleared = self.plan_delete_bounds(&bounds, &mut deletes)?;
+    let deleted = self.deleted_rows(&deletes, &cleared, direct, &replaces)?;
+    let n = (self.staging.inserts.read_lock()?.len()+inserts.len()) as u64;
+    let ndel = (self.staging.deletes.read_lock()?.len()+deletes.len()) as u64;
     let base = self.fields.base_size as u64;
     if ndel >= base && n <= base {
-      deletes.extend_from_slice(&self.staging.deletes.try_borrow()?);
-      let mut dstore = self.data_store.try_borrow_mut()?;
-      dstore.delete(&deletes)?;
-      dstore.commit()?;
+      deletes.extend_from_slice(&self.staging.deletes.read_lock()?);
+      self.staging.journal(&mut self.journal)?;
+      self.meta.journal(&mut self.journal)?;
+      self.journal_history()?;
+      self.data_store.write_lock()?
+        .journal_deletes(&mut self.journal, &deletes)?;
+      self.journal_replace(&replace)?;
+      self.journal_direct(&cleared, direct)?;
+      self.journal.begin()?;
+      self.apply_replace(&replace)?;
+      self.apply_direct(&cleared, direct)?;
+      {
+        let mut dstore = self.data_store.write_lock()?;
+        dstore.delete(&deletes)?;
+        dstore.commit()?;
+      }
       self.staging.batch(&inserts, &vec![])?;
-      self.staging.delete(&deletes)?;
+      if self.staging.delete(&deletes)? {
+        self.meta.generation += 1;
+      }
       self.staging.clear_deletes()?;
       self.staging.commit()?;
+      self.meta.next_id += ninserts;
+      self.meta.save()?;
+      self.save_history(next_id, ninserts, &replaces, deleted)?;
+      self.commit_journal()?;
       return Ok(())
     } else if n <= base {
+      self.staging.journal(&mut self.journal)?;
+      self.meta.journal(&mut self.journal)?;
+      self.journal_history()?;
+      self.journal_replace(&replace)?;
+      self.journal_direct(&cleared, direct)?;
+      self.journal.begin()?;
+      self.apply_replace(&replace)?;
+      self.apply_direct(&cleared, direct)?;
       self.staging.batch(&inserts, &deletes)?;
       self.staging.commit()?;
+      self.meta.next_id += ninserts;
+      self.meta.save()?;
+      self.save_history(next_id, ninserts, &replaces, deleted)?;
+      self.commit_journal()?;
       return Ok(())
     }
     let count = (n/base)*base;
     let rem = n - count;
     let mut mask = vec![];
     for tree in self.trees.iter_mut() {
-      mask.push(!tree.try_borrow_mut()?.is_empty()?);
+      mask.push(!tree.acquire()?.is_empty()?);
     }
     let p = plan(
       &bits::num_to_bits(n/base),
       &mask
     );
+    for (i,_,trees) in p.iter() {
+      for t in trees.iter() {
+        self.create_tree(*t)?;
+      }
+      self.create_tree(*i)?;
+    }
+    for (i,_,trees) in p.iter() {
+      for t in trees.iter().chain(vec![i]) {
+        self.detach_tree(*t)?;
+      }
+    }
+    deletes.extend_from_slice(&self.staging.deletes.read_lock()?);
+    {
+      self.staging.journal(&mut self.journal)?;
+      self.meta.journal(&mut self.journal)?;
+      self.journal_history()?;
+      let mut dstore = self.data_store.write_lock()?;
+      dstore.journal(&mut self.journal)?;
+      dstore.journal_deletes(&mut self.journal, &deletes)?;
+    }
+    self.journal_replace(&replace)?;
+    self.journal_direct(&cleared, direct)?;
+    self.journal.begin()?;
+    self.apply_replace(&replace)?;
+    self.apply_direct(&cleared, direct)?;
     let mut offset = 0;
-    let slen = self.staging.inserts.try_borrow()?.len();
+    let slen = self.staging.inserts.read_lock()?.len();
     for (i,staging,trees) in p {
       let mut irows: Vec<(usize,usize)> = vec![];
       for j in staging {
@@ -409,25 +767,21 @@ P: Point, V: Value {
         irows.push((offset,offset+size));
         offset += size;
       }
-      for t in trees.iter() {
-        self.create_tree(*t)?;
-      }
-      self.create_tree(i)?;
       for _ in self.meta.mask.len()..i+1 {
         self.meta.mask.push(false);
       }
-      let mut srows: Vec<(P,V)> = vec![];
+      let mut srows: Vec<(P,V,Id)> = vec![];
       for (i,j) in irows {
         for k in i..j {
           srows.push(
-            if k < slen { self.staging.inserts.try_borrow()?[k].clone() }
+            if k < slen { self.staging.inserts.read_lock()?[k].clone() }
             else { inserts[k-slen].clone() }
           );
         }
       }
       if trees.is_empty() {
         self.meta.mask[i] = true;
-        self.trees[i].try_borrow_mut()?.build(&srows)?;
+        self.trees[i].acquire()?.build(&srows)?;
       } else {
         self.meta.mask[i] = true;
         for t in trees.iter() {
@@ -441,50 +795,282 @@ P: Point, V: Value {
     let mut rem_rows = vec![];
     for k in offset..n as usize {
       rem_rows.push(
-        if k < slen { self.staging.inserts.try_borrow()?[k].clone() }
+        if k < slen { self.staging.inserts.read_lock()?[k].clone() }
         else { inserts[k-slen].clone() }
       );
     }
     ensure_eq!(rem_rows.len(), rem as usize,
       "unexpected number of remaining rows (expected {}, actual {})",
       rem, rem_rows.len());
-    deletes.extend_from_slice(&self.staging.deletes.try_borrow()?);
     self.staging.clear()?;
     self.staging.batch(&rem_rows, &vec![])?;
     self.staging.delete(&deletes)?;
     self.staging.commit()?;
-    if !deletes.is_empty() {
-      let mut dstore = self.data_store.try_borrow_mut()?;
-      dstore.delete(&deletes)?;
+    {
+      let mut dstore = self.data_store.write_lock()?;
+      if !deletes.is_empty() {
+        dstore.delete(&deletes)?;
+      }
       dstore.commit()?;
     }
+    self.meta.next_id += ninserts;
+    self.meta.generation += 1;
     self.meta.save()?;
+    self.save_history(next_id, ninserts, &replaces, deleted)?;
+    self.commit_journal()?;
+    self.collect_garbage()
+  }
+
+  // copy the records that a batch deletes or replaces before they are removed
+  // so that they can be kept in the version history
+  fn deleted_rows (&mut self, deletes: &Vec<Location>,
+  cleared: &HashMap<u64,Vec<u32>>, direct: &Vec<Location>,
+  replaces: &Vec<(Location,P,V)>) -> Result<Vec<(P,V,Id)>,Error> {
+    if self.history.is_none() { return Ok(vec![]) }
+    let mut locations: Vec<Location> = deletes.iter().chain(direct.iter())
+      .cloned().collect();
+    for (loc,_,_) in replaces.iter() {
+      if let Some(current) = self.locate(loc.2)? {
+        locations.push(current);
+      }
+    }
+    // records with staged deletes were already deleted by an earlier batch
+    let mut ids: HashSet<Id> = self.staging.delete_set.read_lock()?.clone();
+    let mut rows = vec![];
+    let inserts = self.staging.inserts.read_lock()?;
+    let dstore = self.data_store.read_lock()?;
+    for (block,indexes) in cleared.iter() {
+      for (p,v,loc) in dstore.list(*block)? {
+        if indexes.contains(&loc.1) && ids.insert(loc.2) {
+          rows.push((p,v,loc.2));
+        }
+      }
+    }
+    for loc in locations.iter() {
+      if !ids.insert(loc.2) { continue }
+      let row = match loc.0 {
+        0 => inserts.iter().find(|row| row.2 == loc.2).cloned(),
+        block => dstore.list(block-1)?.into_iter()
+          .find(|row| (row.2).2 == loc.2)
+          .map(|(p,v,_)| (p,v,loc.2))
+      };
+      if let Some(row) = row { rows.push(row) }
+    }
+    Ok(rows)
+  }
+
+  fn journal_history (&mut self) -> Result<(),Error> {
+    match self.history.as_mut() {
+      Some(history) => history.journal(&mut self.journal),
+      None => Ok(())
+    }
+  }
+
+  fn save_history (&mut self, next_id: Id, ninserts: u64,
+  replaces: &Vec<(Location,P,V)>, deleted: Vec<(P,V,Id)>)
+  -> Result<(),Error> {
+    if let Some(history) = self.history.as_mut() {
+      let reinserts: Vec<Id> = replaces.iter().map(|r| (r.0).2).collect();
+      history.push(next_id, ninserts, &reinserts, deleted)?;
+    }
+    Ok(())
+  }
+
+  fn plan_replace (&mut self, replaces: &Vec<(Location,P,V)>,
+  deletes: &Vec<Location>) -> Result<Replacements<P,V>,Error> {
+    let mut replace = Replacements {
+      staging: vec![],
+      rows: vec![],
+      tombstones: vec![],
+      reinserts: vec![]
+    };
+    let mut ids: HashSet<Id> = deletes.iter().map(|loc| loc.2).collect();
+    for (loc,p,v) in replaces.iter() {
+      if !ids.insert(loc.2) {
+        bail!["record {} is deleted or replaced more than once in a batch",
+          loc.2];
+      }
+      let row = (*p,v.clone(),loc.2);
+      let current = match self.locate(loc.2)? {
+        None => bail!["record {} to replace was not found", loc.2],
+        Some(current) => current
+      };
+      if current.0 == 0 {
+        replace.staging.push((current.1 as usize,row));
+        continue;
+      }
+      let block = current.0-1;
+      let r = self.data_store.read_lock()?
+        .replace_offset(block, current.1, &row)?;
+      match r {
+        Some(offset) => replace.rows.push((block,offset,row)),
+        None => {
+          replace.tombstones.push(current);
+          replace.reinserts.push(row);
+        }
+      }
+    }
+    Ok(replace)
+  }
+
+  fn journal_replace (&mut self, replace: &Replacements<P,V>)
+  -> Result<(),Error> {
+    if replace.rows.is_empty() && replace.tombstones.is_empty() {
+      return Ok(())
+    }
+    let mut dstore = self.data_store.write_lock()?;
+    dstore.journal_deletes(&mut self.journal, &replace.tombstones)?;
+    for (block,offset,row) in replace.rows.iter() {
+      dstore.journal_row(&mut self.journal, *block, *offset, row)?;
+    }
+    Ok(())
+  }
+
+  fn apply_replace (&mut self, replace: &Replacements<P,V>)
+  -> Result<(),Error> {
+    if !replace.staging.is_empty() {
+      self.staging.replace(&replace.staging)?;
+    }
+    if replace.rows.is_empty() && replace.tombstones.is_empty() {
+      return Ok(())
+    }
+    let mut dstore = self.data_store.write_lock()?;
+    for (block,offset,row) in replace.rows.iter() {
+      dstore.write_row(*block, *offset, row)?;
+    }
+    if !replace.tombstones.is_empty() {
+      // these records are inserted again, so they move to a new location
+      dstore.delete(&replace.tombstones)?;
+      self.meta.generation += 1;
+    }
+    dstore.commit()?;
+    Ok(())
+  }
+
+  // find the records that intersect each bbox in `bounds`: staged records are
+  // added to `deletes` and records in data blocks are returned as record
+  // indexes for each block so their bits can be cleared directly
+  fn plan_delete_bounds (&mut self, bounds: &Vec<P::Bounds>,
+  deletes: &mut Vec<Location>) -> Result<HashMap<u64,Vec<u32>>,Error> {
+    let mut by_block: HashMap<u64,Vec<u32>> = HashMap::new();
+    let generation = self.meta.generation;
+    for bbox in bounds.iter() {
+      for result in self.staging.query(bbox) {
+        let (_,_,loc) = result?;
+        deletes.push((loc.0,loc.1,loc.2,generation));
+      }
+      for tree in self.trees.iter() {
+        let blocks = tree.acquire()?.query_blocks(bbox)?;
+        let dstore = self.data_store.read_lock()?;
+        for block in blocks {
+          let indexes = dstore.overlapping(block, bbox)?;
+          if indexes.is_empty() { continue }
+          by_block.entry(block).or_insert(vec![]).extend(indexes);
+        }
+      }
+    }
+    for indexes in by_block.values_mut() {
+      indexes.sort_unstable();
+      indexes.dedup();
+    }
+    Ok(by_block)
+  }
+
+  fn journal_direct (&mut self, by_block: &HashMap<u64,Vec<u32>>,
+  direct: &Vec<Location>) -> Result<(),Error> {
+    let mut dstore = self.data_store.write_lock()?;
+    dstore.journal_clear(&mut self.journal, by_block)?;
+    if !direct.is_empty() {
+      dstore.journal_deletes(&mut self.journal, direct)?;
+    }
+    Ok(())
+  }
+
+  // delete records from their data blocks without staging the deletes
+  fn apply_direct (&mut self, by_block: &HashMap<u64,Vec<u32>>,
+  direct: &Vec<Location>) -> Result<(),Error> {
+    if by_block.is_empty() && direct.is_empty() { return Ok(()) }
+    let mut dstore = self.data_store.write_lock()?;
+    dstore.clear(by_block)?;
+    if !direct.is_empty() {
+      dstore.delete(direct)?;
+    }
+    dstore.commit()?;
     Ok(())
   }
 
   fn create_tree (&mut self, index: usize) -> Result<(),Error> {
     for i in self.trees.len()..index+1 {
       let store = (self.open_store)(&format!("tree{}",i))?;
-      self.trees.push(Rc::new(RefCell::new(Tree::open(TreeOpts {
-        store,
-        index,
-        data_store: Rc::clone(&self.data_store),
-        branch_factor: self.fields.branch_factor,
-        max_data_size: self.fields.max_data_size,
-      })?)));
+      let tree = self.open_tree(store, i)?;
+      self.trees.push(tree);
     }
     Ok(())
   }
 
-  /// Query the database for all records that intersect the bounding box.
+  fn open_tree (&self, store: S, index: usize)
+  -> Result<Arc<Mutex<Tree<S,P,V>>>,Error> {
+    Ok(Arc::new(Mutex::new(Tree::open(TreeOpts {
+      store,
+      index,
+      data_store: Arc::clone(&self.data_store),
+      branch_factor: self.fields.branch_factor,
+      max_data_size: self.fields.max_data_size,
+    })?)))
+  }
+
+  // save tree `index` before the tree file is rebuilt. a tree that snapshots
+  // or query iterators still read is copied and they keep reading from the
+  // copy, which the journal restores the tree from if the batch is
+  // interrupted. the journal saves the bytes of any other tree
+  fn detach_tree (&mut self, index: usize) -> Result<(),Error> {
+    let tname = format!("tree{}",index);
+    if Arc::strong_count(&self.trees[index]) == 1 {
+      let mut tree = self.trees[index].acquire()?;
+      return self.journal.keep(&tname, &mut tree.store)
+    }
+    let name = format!("tree{}_snapshot{}", index, self.detached);
+    self.detached += 1;
+    let copy = (self.open_store)(&name)?;
+    let store = {
+      let mut tree = self.trees[index].acquire()?;
+      self.journal.keep_copy(&tname, &tree.store, &name)?;
+      let store = tree.detach(copy)?;
+      tree.truncate_copy(false);
+      store
+    };
+    let tree = self.open_tree(store, index)?;
+    self.undo.push(std::mem::replace(&mut self.trees[index], tree));
+    Ok(())
+  }
+
+  // discard the journal and the copies of the trees it restores from
+  fn commit_journal (&mut self) -> Result<(),Error> {
+    self.journal.commit()?;
+    for tree in self.undo.drain(..) {
+      if Arc::strong_count(&tree) == 1 {
+        // nothing else reads the copy
+        tree.acquire()?.store.truncate(0)?;
+      } else {
+        tree.acquire()?.truncate_copy(true);
+      }
+    }
+    Ok(())
+  }
+
+  /// Delete every record that intersects the bounding box `bbox`.
   ///
-  /// The bounding box is a 2-tuple of n-tuples (for an n-dimensional point
-  /// type) representing the `(min,max)` coordinates. In 2d with a conventional
-  /// x-y cartesian grid, the first bbox point would be the "bottom-left" (or
-  /// west-south) and the second point would be the "top-right" (or east-north).
+  /// This is the same as a `batch()` with a single `Row::DeleteBounds(bbox)`.
+  /// Records in the trees are deleted by clearing their bits in the data
+  /// blocks without reading their values.
+  pub fn delete_bbox (&mut self, bbox: &P::Bounds) -> Result<(),Error> {
+    self.batch(&[Row::DeleteBounds(*bbox)])
+  }
+
+  /// Delete every record that intersects the bounding box `bbox` and where
+  /// `pred(point,value)` returns `true`.
   ///
-  /// You will receive an iterator of `Result<(P,V,Location),Error>` results
-  /// that you can step through like this:
+  /// For example, to delete the records with a value of `5` in a region:
   ///
   /// ```rust,no_run
   /// # use eyros::DB;
@@ -494,10 +1080,7 @@ P: Point, V: Value {
   /// # fn main () -> Result<(),Error> {
   /// # let mut db: DB<_,_,((f32,f32),(f32,f32)),u32> = DB::open(storage)?;
   /// let bbox = ((-0.5,-0.8),(0.3,-0.5));
-  /// for result in db.query(&bbox)? {
-  ///   let (point,value,location) = result?;
-  ///   // ...
-  /// }
+  /// db.delete_where(&bbox, |_point,value| *value == 5)?;
   /// # Ok(()) }
   /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
   /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
@@ -506,69 +1089,1122 @@ P: Point, V: Value {
   /// # }
   /// ```
   ///
-  /// If you want to delete records, you will need to use the `Location` records
-  /// you get from a query. However, these locations are only valid until the
-  /// next `.batch()`.
-  pub fn query<'b> (&mut self, bbox: &'b P::Bounds)
-  -> Result<QueryIterator<'b,S,P,V>,Error> {
-    let mut mask: Vec<bool> = vec![];
-    for tree in self.trees.iter_mut() {
-      mask.push(!tree.try_borrow_mut()?.is_empty()?);
+  /// The matching records in the trees are deleted from their data blocks as
+  /// part of this write instead of being staged.
+  pub fn delete_where<F> (&mut self, bbox: &P::Bounds, pred: F)
+  -> Result<(),Error> where F: Fn(&P,&V) -> bool {
+    let generation = self.meta.generation;
+    let mut rows: Vec<Row<P,V>> = vec![];
+    for result in self.staging.query(bbox) {
+      let (p,v,loc) = result?;
+      if pred(&p,&v) {
+        rows.push(Row::Delete((loc.0,loc.1,loc.2,generation)));
+      }
     }
-    let mut queries = Vec::with_capacity(1+self.trees.len());
-    queries.push(SubIterator::Staging(self.staging.query(bbox)));
-    for (i,tree) in self.trees.iter_mut().enumerate() {
-      if !mask[i] { continue }
-      queries.push(SubIterator::Tree(Tree::query(Rc::clone(tree),bbox)?));
+    let mut direct: Vec<Location> = vec![];
+    for tree in self.trees.iter() {
+      let blocks = tree.acquire()?.query_blocks(bbox)?;
+      let dstore = self.data_store.read_lock()?;
+      for block in blocks {
+        for (p,v,loc) in dstore.query(block, bbox)? {
+          if pred(&p,&v) { direct.push(loc) }
+        }
+      }
     }
-    QueryIterator::new(queries, Rc::clone(&self.staging.delete_set))
+    if rows.is_empty() && direct.is_empty() { return Ok(()) }
+    self.write(&rows, &direct)
   }
-}
 
-/// Iterator of `Result<(Point,Value,Location)>` data returned by `db.query()`.
-pub struct QueryIterator<'b,S,P,V> where
-S: RandomAccess<Error=Error>, P: Point, V: Value {
-  index: usize,
-  queries: Vec<SubIterator<'b,S,P,V>>,
-  deletes: Rc<RefCell<HashSet<Location>>>
-}
+  /// Reclaim the space used by deleted records in the data store and return
+  /// the number of bytes reclaimed.
+  ///
+  /// Deletes only clear the bit for each record in its data block, so the data
+  /// store does not shrink on its own. Vacuuming rewrites the blocks that are
+  /// mostly deleted with only their live records, drops blocks without any
+  /// live records, copies the remaining blocks into new data files without
+  /// gaps, and updates the trees to point at the new offsets. Records move to new locations, so
+  /// this starts a new generation.
+  ///
+  /// Drop any snapshots and query iterators before calling `vacuum()`.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let mut db: DB<_,_,P,V> = DB::open(storage)?;
+  /// db.delete_bbox(&((-0.5,-0.8),(0.3,-0.5)))?;
+  /// let bytes = db.vacuum()?;
+  /// println!("reclaimed {} bytes", bytes);
+  /// # Ok(()) }
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn vacuum (&mut self) -> Result<u64,Error> {
+    let blocks = self.data_blocks()?;
+    self.rewrite(&blocks, true)
+  }
 
-impl<'b,S,P,V> QueryIterator<'b,S,P,V> where
-S: RandomAccess<Error=Error>, P: Point, V: Value {
-  pub fn new (queries: Vec<SubIterator<'b,S,P,V>>,
-  deletes: Rc<RefCell<HashSet<Location>>>) -> Result<Self,Error> {
-    Ok(Self { deletes, queries, index: 0 })
+  /// Return the number of bytes in the data store that no tree references.
+  ///
+  /// Merging trees combines small data blocks into new blocks and leaves the
+  /// original blocks behind. Those blocks are found by tracing every data
+  /// block that the trees reference.
+  pub fn garbage (&self) -> Result<u64,Error> {
+    let blocks = self.data_blocks()?;
+    self.data_store.read_lock()?.garbage(&blocks)
   }
-}
 
-impl<'b,S,P,V> Iterator for QueryIterator<'b,S,P,V> where
-S: RandomAccess<Error=Error>, P: Point, V: Value {
-  type Item = Result<(P,V,Location),Error>;
-  fn next (&mut self) -> Option<Self::Item> {
-    while !self.queries.is_empty() {
-      let len = self.queries.len();
-      {
-        let q = &mut self.queries[self.index];
-        let next = match q {
-          SubIterator::Tree(x) => {
-            let result = x.next();
-            match &result {
-              Some(Ok((_,_,loc))) => {
-                if iwrap![self.deletes.try_borrow()].contains(loc) {
-                  self.index = (self.index+1) % len;
-                  continue;
-                }
-              },
-              _ => {}
-            };
-            result
-          },
-          SubIterator::Staging(x) => x.next()
-        };
-        match next {
-          Some(result) => {
+  /// Copy the data blocks that the trees reference into a compacted data
+  /// store, dropping every other block, and return the number of bytes
+  /// reclaimed. Unlike `vacuum()`, the contents of each block are copied as
+  /// they are. Records move to new locations, so this starts a new generation.
+  ///
+  /// This runs automatically after a batch that merges trees when more than
+  /// the `Setup::gc_ratio()` fraction of the data store is garbage. Drop any
+  /// snapshots and query iterators before calling `compact()`.
+  pub fn compact (&mut self) -> Result<u64,Error> {
+    let blocks = self.data_blocks()?;
+    self.rewrite(&blocks, false)
+  }
+
+  /// Merge every tree and all of the staged records into a single tree and
+  /// return the number of tree levels that were collapsed.
+  ///
+  /// Batches that don't fit in staging merge only some of the trees, so the
+  /// database accumulates trees of different sizes over time and queries
+  /// visit each of them along with the staged records. Data blocks that are at
+  /// least half full are kept as they are and the records in sparser blocks
+  /// are written into full blocks. Records move to new locations, so this
+  /// starts a new generation.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let mut db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let levels = db.optimize()?;
+  /// println!("collapsed {} tree levels", levels);
+  /// # Ok(()) }
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn optimize (&mut self) -> Result<usize,Error> {
+    let mut levels = vec![];
+    let mut n = 0;
+    for (i,tree) in self.trees.iter().enumerate() {
+      let mut tree = tree.acquire()?;
+      if tree.is_empty()? { continue }
+      levels.push(i);
+      n += tree.unbuild()?.iter().map(|(_,_,len)| *len).sum::<u64>();
+    }
+    if levels.is_empty() && self.staging.len()? == 0 { return Ok(0) }
+    let rows: Vec<(P,V,Id)> = {
+      let delete_set = self.staging.delete_set.read_lock()?;
+      self.staging.inserts.read_lock()?.iter()
+        .filter(|row| !delete_set.contains(&row.2))
+        .cloned().collect()
+    };
+    n += rows.len() as u64;
+    // the level for a tree of this size from the binary counter of trees
+    let bits = bits::num_to_bits(n/(self.fields.base_size as u64));
+    let dst = bits.len().saturating_sub(1);
+    self.create_tree(dst)?;
+    for i in levels.iter().chain(std::iter::once(&dst)) {
+      if *i == dst && levels.contains(&dst) { continue }
+      self.detach_tree(*i)?;
+    }
+    let deletes = self.staging.deletes.read_lock()?.clone();
+    self.staging.journal(&mut self.journal)?;
+    self.meta.journal(&mut self.journal)?;
+    self.journal_history()?;
+    {
+      let mut dstore = self.data_store.write_lock()?;
+      dstore.journal(&mut self.journal)?;
+      dstore.journal_deletes(&mut self.journal, &deletes)?;
+    }
+    self.journal.begin()?;
+    if !deletes.is_empty() {
+      self.data_store.write_lock()?.delete(&deletes)?;
+    }
+    let leftover = Tree::optimize(&mut self.trees, dst, &levels, &rows)?;
+    self.data_store.write_lock()?.commit()?;
+    self.staging.clear()?;
+    self.staging.batch(&leftover, &vec![])?;
+    self.staging.commit()?;
+    for _ in self.meta.mask.len()..dst+1 {
+      self.meta.mask.push(false);
+    }
+    let built = !self.trees[dst].acquire()?.is_empty()?;
+    for (i,m) in self.meta.mask.iter_mut().enumerate() {
+      *m = i == dst && built;
+    }
+    self.meta.generation += 1;
+    self.meta.save()?;
+    self.commit_journal()?;
+    self.collect_garbage()?;
+    Ok(levels.len().saturating_sub(built as usize))
+  }
+
+  /// Load records from an iterator that may be much larger than memory into
+  /// a new tree.
+  ///
+  /// Records are read in runs of `Setup::bulk_run_size()` records. Each run is
+  /// sorted along a z-order curve and written to temporary storage named
+  /// `bulk0`, `bulk1`, and so on. The sorted runs are then merged and written
+  /// to data blocks in that order, so only one run, the next record of each
+  /// run, and the bounds of each data block are held in memory at a time.
+  /// The tree is built over the data blocks at the first free level that
+  /// holds at least as many records.
+  ///
+  /// Records are assigned ids in the order they are read. Like `batch()`,
+  /// the load is atomic. Staged records are not changed, and a load with no
+  /// more than `base_size` records is written as a regular batch.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use std::path::PathBuf;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # fn main () -> Result<(),Error> {
+  /// # let mut db: DB<_,_,((f32,f32),(f32,f32)),u32> = DB::open(storage)?;
+  /// let rows = (0..10_000_000u32).map(|i| {
+  ///   let x = ((i % 4_000) as f32)/2_000.0 - 1.0;
+  ///   let y = ((i / 4_000) as f32)/1_250.0 - 1.0;
+  ///   (((x,x),(y,y)),i)
+  /// });
+  /// db.bulk_load(rows)?;
+  /// # Ok(()) }
+  /// # fn storage(name:&str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn bulk_load<I> (&mut self, rows: I) -> Result<(),Error>
+  where I: IntoIterator<Item=(P,V)>, P: Spatial {
+    self.try_bulk_load(rows.into_iter().map(Ok))
+  }
+
+  // bulk_load() for rows that can fail to be read. every row is read before
+  // anything is written to the database, so nothing is loaded if a row fails
+  pub(crate) fn try_bulk_load<I> (&mut self, mut rows: I) -> Result<(),Error>
+  where I: Iterator<Item=Result<(P,V),Error>>, P: Spatial {
+    let next_id = self.meta.next_id;
+    let run_size = self.fields.bulk_run_size.max(2);
+    let base = self.fields.base_size as u64;
+    let mut runs: Vec<Run<S,P,V>> = vec![];
+    let mut n = 0u64;
+    loop {
+      let chunk: Result<Vec<(P,V,Id)>,Error> = rows.by_ref().take(run_size)
+        .enumerate()
+        .map(|(i,row)| row.map(|(p,v)| (p,v,next_id+n+(i as u64))))
+        .collect();
+      let mut chunk = match chunk {
+        Ok(chunk) => chunk,
+        Err(e) => {
+          for run in runs.iter_mut() { run.clear()? }
+          return Err(e)
+        }
+      };
+      if chunk.is_empty() { break }
+      n += chunk.len() as u64;
+      if runs.is_empty() && chunk.len() < run_size && n <= base {
+        let inserts: Vec<Row<P,V>> = chunk.into_iter()
+          .map(|(p,v,_)| Row::Insert(p,v))
+          .collect();
+        return self.batch(&inserts)
+      }
+      let store = (self.open_store)(&format!("bulk{}",runs.len()))?;
+      runs.push(Run::write(store, &mut chunk)?);
+    }
+    if runs.is_empty() { return Ok(()) }
+    let mut dst = bits::num_to_bits(n/base).len().saturating_sub(1);
+    while self.meta.mask.get(dst).cloned().unwrap_or(false) {
+      dst += 1;
+    }
+    self.create_tree(dst)?;
+    self.detach_tree(dst)?;
+    self.meta.journal(&mut self.journal)?;
+    self.journal_history()?;
+    self.data_store.write_lock()?.journal(&mut self.journal)?;
+    self.journal.begin()?;
+    let mut merge = Merge::new(runs)?;
+    self.trees[dst].acquire()?.build_from_iter(&mut merge)?;
+    self.data_store.write_lock()?.commit()?;
+    for _ in self.meta.mask.len()..dst+1 {
+      self.meta.mask.push(false);
+    }
+    self.meta.mask[dst] = true;
+    self.meta.next_id += n;
+    self.meta.generation += 1;
+    self.meta.save()?;
+    self.save_history(next_id, n, &vec![], vec![])?;
+    self.commit_journal()?;
+    merge.clear()
+  }
+
+  // offsets of every data block reachable from the trees
+  fn data_blocks (&self) -> Result<Vec<u64>,Error> {
+    let mut blocks = vec![];
+    for tree in self.trees.iter() {
+      blocks.extend(tree.acquire()?.data_blocks()?);
+    }
+    Ok(blocks)
+  }
+
+  // whether snapshots, query iterators, or detached trees still read from the
+  // data store. every tree holds two references to the data store.
+  fn is_shared (&self) -> bool {
+    self.trees.iter().any(|tree| Arc::strong_count(tree) > 1)
+      || Arc::strong_count(&self.data_store) > 1 + 2*self.trees.len()
+  }
+
+  // compact the data store after a merge if enough of it is garbage
+  fn collect_garbage (&mut self) -> Result<(),Error> {
+    if self.is_shared() { return Ok(()) }
+    let blocks = self.data_blocks()?;
+    let (garbage,len) = {
+      let dstore = self.data_store.read_lock()?;
+      (dstore.garbage(&blocks)?, dstore.bytes()?)
+    };
+    if garbage == 0 || (garbage as f64) <= (len as f64)*self.fields.gc_ratio {
+      return Ok(())
+    }
+    self.rewrite(&blocks, false)?;
+    Ok(())
+  }
+
+  // rewrite the data store with only `blocks` and point the trees at the new
+  // block offsets
+  fn rewrite (&mut self, blocks: &Vec<u64>, vacuum: bool)
+  -> Result<u64,Error> {
+    ensure![!self.is_shared(),
+      "data store rewritten while snapshots or queries are reading it"];
+    let before = self.data_store.read_lock()?.bytes()?;
+    // the blocks are copied into the other set of data and range files, which
+    // the meta file only points at once they are synced
+    let files = (self.meta.data_files+1)%2;
+    let (data_name,range_name) = data::file_names(files);
+    let mut store = (self.open_store)(&data_name)?;
+    let mut range_store = (self.open_store)(&range_name)?;
+    store.truncate(0)?;
+    range_store.truncate(0)?;
+    self.meta.journal(&mut self.journal)?;
+    self.journal.keep_len(&data_name, &store)?;
+    self.journal.keep_len(&range_name, &range_store)?;
+    for (i,tree) in self.trees.iter().enumerate() {
+      let mut tree = tree.acquire()?;
+      tree.journal_remap(&mut self.journal, &format!("tree{}",i))?;
+    }
+    self.journal.begin()?;
+    let (moved,mut old,mut old_range) = self.data_store.write_lock()?
+      .rewrite(blocks, vacuum, files, store, range_store)?;
+    let mut live = vec![];
+    for tree in self.trees.iter() {
+      let mut tree = tree.acquire()?;
+      tree.remap_blocks(&moved)?;
+      live.extend(tree.data_blocks()?);
+    }
+    let after = {
+      let mut dstore = self.data_store.write_lock()?;
+      dstore.reindex(&live)?;
+      dstore.commit()?;
+      dstore.bytes()?
+    };
+    self.meta.data_files = files;
+    self.meta.generation += 1;
+    self.meta.save()?;
+    self.commit_journal()?;
+    // nothing points at the old files anymore
+    old.truncate(0)?;
+    old.sync_all()?;
+    old_range.truncate(0)?;
+    old_range.sync_all()?;
+    Ok(before - after)
+  }
+
+  /// Query the database for all records that intersect the bounding box.
+  ///
+  /// The bounding box is a 2-tuple of n-tuples (for an n-dimensional point
+  /// type) representing the `(min,max)` coordinates. In 2d with a conventional
+  /// x-y cartesian grid, the first bbox point would be the "bottom-left" (or
+  /// west-south) and the second point would be the "top-right" (or east-north).
+  ///
+  /// You will receive an iterator of `Result<(P,V,Location),Error>` results
+  /// that you can step through like this:
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use std::path::PathBuf;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # fn main () -> Result<(),Error> {
+  /// # let mut db: DB<_,_,((f32,f32),(f32,f32)),u32> = DB::open(storage)?;
+  /// let bbox = ((-0.5,-0.8),(0.3,-0.5));
+  /// for result in db.query(&bbox)? {
+  ///   let (point,value,location) = result?;
+  ///   // ...
+  /// }
+  /// # Ok(()) }
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  ///
+  /// If you want to delete records, you will need to use the `Location` records
+  /// you get from a query. Locations are only valid until the next batch that
+  /// moves records, after which `db.locate(id)` returns a current location.
+  ///
+  /// Queries only need a shared reference, so several threads can query the
+  /// same database at once. The trees, the staging records and the data store
+  /// each have their own lock that is only held while that part is read. An
+  /// iterator that is still open during a `batch()` can return records written
+  /// or deleted by that batch. Query a `db.snapshot()` for results that stay
+  /// consistent across batches.
+  pub fn query<'b> (&self, bbox: &'b P::Bounds)
+  -> Result<QueryIterator<'b,S,P,V>,Error> {
+    self.query_with(bbox, Predicate::Overlaps)
+  }
+
+  /// Query for records that have the relation `predicate` to `bbox`: records
+  /// that overlap `bbox` like `db.query()`, records entirely within `bbox` or
+  /// records that entirely contain `bbox`. The predicate is also used to skip
+  /// the branches that can't hold any matching records.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Predicate};
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// // longitude, latitude, time
+  /// # type P = (f32,f32,(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// // events that lasted the whole window
+  /// let bbox = ((-0.5,-0.8,100.0),(0.3,-0.5,200.0));
+  /// for result in db.query_with(&bbox, Predicate::Contains)? {
+  ///   println!("{:?}", result?);
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  ///
+  /// Scalar elements only contain a bounding box with the same min and max in
+  /// that dimension, so use the same min and max for those dimensions when
+  /// querying with `Predicate::Contains`.
+  pub fn query_with<'b> (&self, bbox: &'b P::Bounds, predicate: Predicate)
+  -> Result<QueryIterator<'b,S,P,V>,Error> {
+    let mut mask: Vec<bool> = vec![];
+    for tree in self.trees.iter() {
+      mask.push(!tree.acquire()?.is_empty()?);
+    }
+    let mut queries = Vec::with_capacity(1+self.trees.len());
+    queries.push(SubIterator::Staging(
+      self.staging.query(bbox).predicate(predicate)
+    ));
+    for (i,tree) in self.trees.iter().enumerate() {
+      if !mask[i] { continue }
+      queries.push(SubIterator::Tree(
+        Tree::query(Arc::clone(tree),bbox)?.predicate(predicate)
+      ));
+    }
+    QueryIterator::new(
+      queries,
+      Arc::clone(&self.staging.delete_set),
+      self.meta.generation
+    )
+  }
+
+  /// Query for records that intersect any of the boxes in `bboxes`.
+  ///
+  /// Each tree is traversed once, descending into the branches that any of
+  /// the boxes intersect. Records are returned once along with the indexes
+  /// into `bboxes` of every box they intersect.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// // a view that crosses the antimeridian
+  /// let bboxes = vec![
+  ///   ((170.0,-10.0),(180.0,10.0)),
+  ///   ((-180.0,-10.0),(-175.0,10.0))
+  /// ];
+  /// for result in db.query_many(&bboxes)? {
+  ///   let (point,value,location,matched) = result?;
+  ///   // ...
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn query_many<'b> (&self, bboxes: &'b [P::Bounds])
+  -> Result<QueryManyIterator<'b,S,P,V>,Error> {
+    let generation = self.meta.generation;
+    let all: Vec<usize> = (0..bboxes.len()).collect();
+    let mut staged = vec![];
+    {
+      let deletes = self.staging.delete_set.read_lock()?;
+      let inserts = self.staging.inserts.read_lock()?;
+      for (i,(p,v,id)) in inserts.iter().enumerate() {
+        if deletes.contains(id) { continue }
+        let m = many::matches(p, bboxes, &all);
+        if m.is_empty() { continue }
+        staged.push((*p,v.clone(),(0,i as u32,*id,generation),m));
+      }
+    }
+    let mut trees = vec![];
+    for tree in self.trees.iter() {
+      if !tree.acquire()?.is_empty()? { trees.push(Arc::clone(tree)) }
+    }
+    Ok(QueryManyIterator::new(
+      trees,
+      Arc::clone(&self.data_store),
+      Arc::clone(&self.staging.delete_set),
+      staged,
+      bboxes,
+      self.fields.branch_factor,
+      generation
+    ))
+  }
+
+  /// Query for records that intersect `region`, such as a `Polygon` or a
+  /// `Radius` around a point. Branches and data blocks are skipped when the
+  /// region doesn't intersect the space that contains them.
+  ///
+  /// Use `db.query()` for bounding boxes, which is faster.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Polygon,Radius};
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let triangle = Polygon::new(vec![(-0.5,-0.5),(0.5,-0.5),(0.0,0.5)]);
+  /// for result in db.query_region(&triangle)? {
+  ///   println!("{:?}", result?);
+  /// }
+  /// let circle = Radius::new((0.2,0.3), 0.1);
+  /// for result in db.query_region(&circle)? {
+  ///   println!("{:?}", result?);
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn query_region<'r,R> (&self, region: &'r R)
+  -> Result<RegionIterator<'r,S,P,V,R>,Error>
+  where R: QueryRegion, P: Spatial {
+    let generation = self.meta.generation;
+    let mut staged = vec![];
+    {
+      let deletes = self.staging.delete_set.read_lock()?;
+      let inserts = self.staging.inserts.read_lock()?;
+      for (i,(p,v,id)) in inserts.iter().enumerate() {
+        if deletes.contains(id) || !region.overlaps(&p.extents()) { continue }
+        staged.push((*p,v.clone(),(0,i as u32,*id,generation)));
+      }
+    }
+    let mut trees = vec![];
+    for tree in self.trees.iter() {
+      if !tree.acquire()?.is_empty()? { trees.push(Arc::clone(tree)) }
+    }
+    Ok(RegionIterator::new(
+      trees,
+      Arc::clone(&self.data_store),
+      Arc::clone(&self.staging.delete_set),
+      staged,
+      region,
+      self.fields.branch_factor,
+      generation
+    ))
+  }
+
+  /// Query for records that intersect `bbox` in increasing order along the
+  /// dimension `dim`. Intervals are ordered by their lower bound.
+  ///
+  /// Each tree is read in the order of its pivots along `dim` and the trees
+  /// are merged as they are read, so results are returned without sorting
+  /// the whole result set in memory.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// // longitude, latitude, time
+  /// # type P = (f32,f32,(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let bbox = ((-0.5,-0.8,0.0),(0.3,-0.5,100.0));
+  /// // oldest first
+  /// for result in db.query_ordered(&bbox, 2)? {
+  ///   let (point,value,location) = result?;
+  ///   // ...
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn query_ordered (&self, bbox: &P::Bounds, dim: usize)
+  -> Result<OrderedIterator<S,P,V>,Error> where P: Spatial {
+    ensure![dim < P::dim(), "dimension {} out of bounds for {} dimensions",
+      dim, P::dim()];
+    let generation = self.meta.generation;
+    let mut staged = vec![];
+    {
+      let deletes = self.staging.delete_set.read_lock()?;
+      let inserts = self.staging.inserts.read_lock()?;
+      for (i,(p,v,id)) in inserts.iter().enumerate() {
+        if deletes.contains(id) || !p.overlaps(bbox) { continue }
+        staged.push((*p,v.clone(),(0,i as u32,*id,generation)));
+      }
+    }
+    let mut trees = vec![];
+    for tree in self.trees.iter() {
+      if !tree.acquire()?.is_empty()? { trees.push(Arc::clone(tree)) }
+    }
+    Ok(OrderedIterator::new(
+      trees,
+      Arc::clone(&self.data_store),
+      Arc::clone(&self.staging.delete_set),
+      staged,
+      bbox,
+      dim,
+      self.fields.branch_factor,
+      generation
+    ))
+  }
+
+  /// Query for up to `limit` records that intersect `bbox`, starting from the
+  /// position in `token` or from the beginning when `token` is `None`.
+  ///
+  /// Returns the records along with a token for the next page, or `None` after
+  /// the last page. Tokens can be serialized with `token.to_bytes()` to resume
+  /// the query later. Tokens are rejected with an error once a batch has
+  /// changed the trees or when they are used with a different `bbox`. Records
+  /// inserted into staging in the meantime are still returned.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let bbox = ((-0.5,-0.8),(0.3,-0.5));
+  /// let mut token = None;
+  /// loop {
+  ///   let (records,next) = db.query_page(&bbox, 100, token.as_ref())?;
+  ///   println!("{} records", records.len());
+  ///   if next.is_none() { break }
+  ///   token = next;
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn query_page (&self, bbox: &P::Bounds, limit: usize,
+  token: Option<&Token>) -> Result<(Vec<(P,V,Location)>,Option<Token>),Error> {
+    let generation = self.meta.generation;
+    let bbox_hash = fnv1a(&bbox.to_bytes()?);
+    let mut t = match token {
+      None => Token::new(generation, bbox_hash),
+      Some(t) => {
+        ensure![t.generation == generation,
+          "token from generation {} is stale at generation {}",
+          t.generation, generation];
+        ensure![t.bbox == bbox_hash, "token is from a query with another bbox"];
+        t.clone()
+      }
+    };
+    let deletes = self.staging.delete_set.read_lock()?.clone();
+    let mut results = Vec::with_capacity(limit);
+    let bf = self.fields.branch_factor;
+    while t.tree < self.trees.len() {
+      if let Some((block,next)) = t.block {
+        let rows = self.data_store.read_lock()?.query(block, bbox)?;
+        t.block = None;
+        for (p,v,loc) in rows {
+          if loc.1 < next || deletes.contains(&loc.2) { continue }
+          if results.len() >= limit {
+            t.block = Some((block,loc.1));
+            return Ok((results,Some(t)));
+          }
+          results.push((p,v,(loc.0,loc.1,loc.2,generation)));
+        }
+      } else if let Some(block) = t.blocks.pop() {
+        t.block = Some((block,0));
+      } else if let Some((cursor,depth)) = t.cursors.pop() {
+        let buf = {
+          let mut tree = self.trees[t.tree].acquire()?;
+          let len = tree.store.len()?;
+          if cursor >= len { continue }
+          read_block(&mut tree.store, cursor, len, 1024)?
+        };
+        let (cursors,blocks) = P::query_branch(&buf, bbox, bf, depth)?;
+        t.cursors.extend(cursors);
+        t.blocks.extend(blocks);
+      } else {
+        t.tree += 1;
+        t.cursors = vec![(0,0)];
+      }
+    }
+    let inserts = self.staging.inserts.read_lock()?;
+    while t.staging < inserts.len() {
+      let (p,v,id) = &inserts[t.staging];
+      if !deletes.contains(id) && p.overlaps(bbox) {
+        if results.len() >= limit { return Ok((results,Some(t))) }
+        results.push((*p,v.clone(),(0,t.staging as u32,*id,generation)));
+      }
+      t.staging += 1;
+    }
+    Ok((results,None))
+  }
+
+  /// Count the records that intersect `bbox` without decoding their values.
+  ///
+  /// Data blocks entirely inside of `bbox` are counted from the row count kept
+  /// for each block. Only the blocks on the boundary of `bbox` are read, and
+  /// only the points in those blocks are parsed.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let n = db.count(&((-0.5,-0.8),(0.3,-0.5)))?;
+  /// println!("{} records", n);
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn count (&self, bbox: &P::Bounds) -> Result<u64,Error> {
+    let deletes = self.staging.delete_set.read_lock()?.clone();
+    let mut count = self.staging.inserts.read_lock()?.iter()
+      .filter(|(p,_,id)| !deletes.contains(id) && p.overlaps(bbox))
+      .count() as u64;
+    let mut blocks = vec![];
+    for tree in self.trees.iter() {
+      blocks.extend(tree.acquire()?.query_blocks(bbox)?);
+    }
+    let dstore = self.data_store.read_lock()?;
+    // blocks with staged deletes are scanned to skip the deleted records
+    let mut dirty = HashSet::new();
+    for id in deletes.iter() {
+      if let Some(block) = dstore.block_of(*id)? { dirty.insert(block); }
+    }
+    for block in blocks {
+      count += if dirty.contains(&block) {
+        dstore.query(block, bbox)?.iter()
+          .filter(|row| !deletes.contains(&(row.2).2)).count() as u64
+      } else {
+        dstore.count(block, bbox)?
+      };
+    }
+    Ok(count)
+  }
+
+  /// Compute the minimum, maximum, sum or count of the measured values of the
+  /// records that intersect `bbox`. Minimums and maximums are `None` when no
+  /// records match.
+  ///
+  /// Data blocks entirely inside of `bbox` are answered from a summary that is
+  /// stored with the block. Blocks on the boundary of `bbox` are scanned.
+  /// Summaries are written with each block by databases opened with
+  /// `Setup::build_measured()`. Blocks written without a summary are scanned
+  /// until `db.compact()` rewrites them from a measured database.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Setup,Aggregate};
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = Setup::new(storage).build_measured()?;
+  /// let bbox = ((-0.5,-0.8),(0.3,-0.5));
+  /// let sum = db.aggregate(&bbox, Aggregate::Sum)?.unwrap();
+  /// let count = db.aggregate(&bbox, Aggregate::Count)?.unwrap();
+  /// println!("mean: {}", sum / count);
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn aggregate (&self, bbox: &P::Bounds, agg: Aggregate)
+  -> Result<Option<f64>,Error> where V: Measure {
+    Ok(self.summarize(bbox)?.get(agg))
+  }
+
+  /// Compute the count, sum, minimum and maximum of the measured values of the
+  /// records that intersect `bbox` all at once.
+  pub fn summarize (&self, bbox: &P::Bounds) -> Result<Summary,Error>
+  where V: Measure {
+    let deletes = self.staging.delete_set.read_lock()?.clone();
+    let mut summary = Summary::default();
+    for (p,v,id) in self.staging.inserts.read_lock()?.iter() {
+      if !deletes.contains(id) && p.overlaps(bbox) {
+        summary.add(v.measure());
+      }
+    }
+    let mut blocks = vec![];
+    for tree in self.trees.iter() {
+      blocks.extend(tree.acquire()?.query_blocks(bbox)?);
+    }
+    let dstore = self.data_store.read_lock()?;
+    // blocks with staged deletes are scanned to skip the deleted records
+    let mut dirty = HashSet::new();
+    for id in deletes.iter() {
+      if let Some(block) = dstore.block_of(*id)? { dirty.insert(block); }
+    }
+    for block in blocks {
+      if dirty.contains(&block) {
+        for row in dstore.query(block, bbox)? {
+          if !deletes.contains(&(row.2).2) { summary.add(row.1.measure()) }
+        }
+      } else {
+        summary.merge(&dstore.summarize(block, bbox)?);
+      }
+    }
+    Ok(summary)
+  }
+
+  /// Find the `k` records nearest to `point` in order of increasing distance
+  /// as measured by `P::Distance`, which is the Euclidean distance for the
+  /// built-in point types. Intervals are measured from their nearest edge, so
+  /// records that overlap `point` are at a distance of `0`.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::DB;
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let db: DB<_,_,P,V> = DB::open(storage)?;
+  /// for (point,value,location) in db.nearest(&((0.5,0.5),(-0.2,-0.2)), 10)? {
+  ///   println!("{:?} {:?} {:?}", point, value, location);
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn nearest (&self, point: &P, k: usize)
+  -> Result<Vec<(P,V,Location)>,Error> where P: Spatial {
+    self.nearest_by(point, k, &P::Distance::default())
+  }
+
+  /// Find the `k` records nearest to `point` in order of increasing distance
+  /// as measured by the metric `distance`.
+  ///
+  /// Branches and data blocks are visited nearest first, using the pivots in
+  /// the branches and the bounds of the data blocks, so only the parts of the
+  /// trees that can hold one of the nearest records are read.
+  pub fn nearest_by<D> (&self, point: &P, k: usize, distance: &D)
+  -> Result<Vec<(P,V,Location)>,Error> where P: Spatial, D: Distance {
+    let target = point.extents();
+    let generation = self.meta.generation;
+    let mut results = vec![];
+    if k == 0 { return Ok(results) }
+    let mut queue: BinaryHeap<Entry<P,V>> = BinaryHeap::new();
+    {
+      let deletes = self.staging.delete_set.read_lock()?;
+      let inserts = self.staging.inserts.read_lock()?;
+      for (i,(p,v,id)) in inserts.iter().enumerate() {
+        if deletes.contains(id) { continue }
+        let d = distance.distance(&target, &p.extents());
+        queue.push(Entry::Record(d, (*p,v.clone(),(0,i as u32,*id,generation))));
+      }
+    }
+    let unbounded: Extents = vec![(std::f64::NEG_INFINITY,std::f64::INFINITY);
+      target.len()];
+    for (t,tree) in self.trees.iter().enumerate() {
+      if tree.acquire()?.is_empty()? { continue }
+      queue.push(Entry::Branch(0.0, t, (0,0), unbounded.clone()));
+    }
+    let bf = self.fields.branch_factor;
+    while let Some(entry) = queue.pop() {
+      match entry {
+        Entry::Record(_,row) => {
+          results.push(row);
+          if results.len() >= k { break }
+        },
+        Entry::Branch(_,t,(offset,depth),region) => {
+          let buf = {
+            let mut tree = self.trees[t].acquire()?;
+            let len = tree.store.len()?;
+            if offset >= len { continue }
+            read_block(&mut tree.store, offset, len, 1024)?
+          };
+          let (cursors,blocks) = P::nearest_branch(&buf, &region, bf, depth)?;
+          for (cursor,r) in cursors {
+            queue.push(Entry::Branch(distance.distance(&target,&r), t, cursor, r));
+          }
+          let dstore = self.data_store.read_lock()?;
+          for (block,r) in blocks {
+            // empty blocks have no bounds
+            if let Some((bbox,_)) = dstore.bbox(block)? {
+              let d = distance.distance(&target, &r)
+                .max(distance.distance(&target, &P::bounds_extents(&bbox)));
+              queue.push(Entry::Block(d, block));
+            }
+          }
+        },
+        Entry::Block(_,block) => {
+          let deletes = self.staging.delete_set.read_lock()?;
+          for (p,v,loc) in self.data_store.read_lock()?.list(block)? {
+            if deletes.contains(&loc.2) { continue }
+            let d = distance.distance(&target, &p.extents());
+            queue.push(Entry::Record(d, (p,v,(loc.0,loc.1,loc.2,generation))));
+          }
+        }
+      }
+    }
+    Ok(results)
+  }
+
+  /// Take a read-only snapshot of the trees, the staging records and the
+  /// staged deletes. Queries on the snapshot keep returning the same results
+  /// while later batches are written.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Row};
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let mut db: DB<_,_,P,V> = DB::open(storage)?;
+  /// let snapshot = db.snapshot()?;
+  /// let bbox = ((-0.5,-0.8),(0.3,-0.5));
+  /// let results = snapshot.query(&bbox)?;
+  /// db.batch(&vec![Row::Insert(((0.0,0.0),(-0.6,-0.6)), 100)])?;
+  /// for result in results {
+  ///   // doesn't include the record inserted after the snapshot
+  ///   println!("{:?}", result?);
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn snapshot (&self) -> Result<Snapshot<S,P,V>,Error> {
+    let mut trees = vec![];
+    for tree in self.trees.iter() {
+      if !tree.acquire()?.is_empty()? {
+        trees.push(Arc::clone(tree));
+      }
+    }
+    Ok(Snapshot {
+      trees,
+      inserts: Arc::new(RwLock::new(
+        self.staging.inserts.read_lock()?.clone())),
+      delete_set: Arc::new(RwLock::new(
+        self.staging.delete_set.read_lock()?.clone())),
+      pinned: self.data_store.read_lock()?.pin()?,
+      generation: self.meta.generation
+    })
+  }
+
+  /// Query for records that overlapped `bbox` right after the batch with the
+  /// batch number `batch` was written. Results are `(point, value, id)`
+  /// because earlier versions of records no longer have a location.
+  ///
+  /// The database must be opened with `Setup::versioned(true)`. Every batch
+  /// in versioned mode gets the next batch number. Batch numbers are separate
+  /// from the generation in a `Location`, which only changes when records
+  /// move. Batch 0 is the database before the first versioned batch and
+  /// `db.batch_number()` is the number of the most recent batch. Batches older
+  /// than the history horizon return an error.
+  ///
+  /// ```rust,no_run
+  /// # use eyros::{DB,Setup,Row};
+  /// # use failure::Error;
+  /// # use random_access_disk::RandomAccessDisk;
+  /// # use std::path::PathBuf;
+  /// # fn main () -> Result<(),Error> {
+  /// # type P = ((f32,f32),(f32,f32));
+  /// # type V = u32;
+  /// let mut db: DB<_,_,P,V> = Setup::new(storage)
+  ///   .versioned(true)
+  ///   .history_horizon(500)
+  ///   .build()?;
+  /// db.batch(&[Row::Insert(((0.1,0.2),(0.3,0.4)),5)])?;
+  /// let before = db.batch_number().unwrap();
+  /// let bbox = ((-1.0,-1.0),(1.0,1.0));
+  /// let deletes: Vec<Row<P,V>> = db.query(&bbox)?
+  ///   .map(|result| result.map(|(_,_,loc)| Row::Delete(loc)))
+  ///   .collect::<Result<_,Error>>()?;
+  /// db.batch(&deletes)?;
+  /// for result in db.query_as_of(&bbox, before)? {
+  ///   println!("{:?}", result?);
+  /// }
+  /// # Ok(()) }
+  /// #
+  /// # fn storage(name: &str) -> Result<RandomAccessDisk,Error> {
+  /// #   let mut p = PathBuf::from("/tmp/eyros-db/");
+  /// #   p.push(name);
+  /// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+  /// # }
+  /// ```
+  pub fn query_as_of<'a,'b> (&'a self, bbox: &'b P::Bounds, batch: u64)
+  -> Result<AsOfIterator<'a,'b,S,P,V>,Error> {
+    let history = match &self.history {
+      None => bail!["query_as_of() requires Setup::versioned(true)"],
+      Some(history) => history
+    };
+    ensure![batch <= history.batch,
+      "batch {} is past the latest batch {}", batch, history.batch];
+    ensure![batch >= history.oldest(),
+      "batch {} is older than the history horizon at batch {}",
+      batch, history.oldest()];
+    Ok(AsOfIterator::new(history, self.query(bbox)?, bbox, batch))
+  }
+
+  /// Number of the most recent batch in versioned mode, or `None` if the
+  /// database was not opened with `Setup::versioned(true)`.
+  pub fn batch_number (&self) -> Option<u64> {
+    self.history.as_ref().map(|history| history.batch)
+  }
+
+  /// Get the current location of the record with the id `id`, or `None` if
+  /// there is no such record or it was deleted.
+  ///
+  /// Use this to refresh a `Location` from an earlier generation before
+  /// deleting its record.
+  pub fn locate (&self, id: Id) -> Result<Option<Location>,Error> {
+    let generation = self.meta.generation;
+    if self.staging.delete_set.read_lock()?.contains(&id) {
+      return Ok(None);
+    }
+    if let Some(index) = self.staging.locate(id)? {
+      return Ok(Some((0,index,id,generation)));
+    }
+    let r = self.data_store.read_lock()?.locate(id)?;
+    Ok(r.map(|(block,index)| (block+1,index,id,generation)))
+  }
+}
+
+/// Iterator of `Result<(Point,Value,Location)>` data returned by `db.query()`.
+pub struct QueryIterator<'b,S,P,V> where
+S: RandomAccess<Error=Error>, P: Point, V: Value {
+  index: usize,
+  queries: Vec<SubIterator<'b,S,P,V>>,
+  deletes: Arc<RwLock<HashSet<Id>>>,
+  generation: u64
+}
+
+impl<'b,S,P,V> QueryIterator<'b,S,P,V> where
+S: RandomAccess<Error=Error>, P: Point, V: Value {
+  pub fn new (queries: Vec<SubIterator<'b,S,P,V>>,
+  deletes: Arc<RwLock<HashSet<Id>>>, generation: u64) -> Result<Self,Error> {
+    Ok(Self { deletes, queries, generation, index: 0 })
+  }
+}
+
+impl<'b,S,P,V> Iterator for QueryIterator<'b,S,P,V> where
+S: RandomAccess<Error=Error>, P: Point, V: Value {
+  type Item = Result<(P,V,Location),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    while !self.queries.is_empty() {
+      let len = self.queries.len();
+      {
+        let q = &mut self.queries[self.index];
+        let next = match q {
+          SubIterator::Tree(x) => {
+            let result = x.next();
+            match &result {
+              Some(Ok((_,_,loc))) => {
+                if iwrap![self.deletes.read_lock()].contains(&loc.2) {
+                  self.index = (self.index+1) % len;
+                  continue;
+                }
+              },
+              _ => {}
+            };
+            result
+          },
+          SubIterator::Staging(x) => x.next()
+        };
+        match next {
+          Some(Ok((p,v,loc))) => {
+            self.index = (self.index+1) % len;
+            return Some(Ok((p,v,(loc.0,loc.1,loc.2,self.generation))));
+          },
+          Some(Err(e)) => {
             self.index = (self.index+1) % len;
-            return Some(result);
+            return Some(Err(e));
           },
           None => {}
         }
@@ -0,0 +1,29 @@
+use failure::{Error,format_err};
+use std::sync::{Mutex,MutexGuard,RwLock,RwLockReadGuard,RwLockWriteGuard};
+
+// Lock guards with poisoned locks converted into errors. A lock is poisoned
+// when a thread panics while holding it.
+
+pub trait Acquire<T> {
+  fn acquire (&self) -> Result<MutexGuard<'_,T>,Error>;
+}
+
+impl<T> Acquire<T> for Mutex<T> {
+  fn acquire (&self) -> Result<MutexGuard<'_,T>,Error> {
+    self.lock().map_err(|e| format_err!["{}", e])
+  }
+}
+
+pub trait ReadWrite<T> {
+  fn read_lock (&self) -> Result<RwLockReadGuard<'_,T>,Error>;
+  fn write_lock (&self) -> Result<RwLockWriteGuard<'_,T>,Error>;
+}
+
+impl<T> ReadWrite<T> for RwLock<T> {
+  fn read_lock (&self) -> Result<RwLockReadGuard<'_,T>,Error> {
+    self.read().map_err(|e| format_err!["{}", e])
+  }
+  fn write_lock (&self) -> Result<RwLockWriteGuard<'_,T>,Error> {
+    self.write().map_err(|e| format_err!["{}", e])
+  }
+}
@@ -0,0 +1,105 @@
+use crate::{Point,Value,Location,Id,Tree,DataStore,Cursor,Block};
+use crate::read_block::read_block;
+use crate::lock::{Acquire,ReadWrite};
+use random_access_storage::RandomAccess;
+use failure::Error;
+use std::collections::{HashMap,HashSet};
+use std::sync::{Arc,Mutex,RwLock};
+
+/// Record along with the indexes of the boxes it matched, as returned by
+/// `db.query_many()`.
+pub type Matched<P,V> = (P,V,Location,Vec<usize>);
+
+/// Iterator of `Result<(Point,Value,Location,Vec<usize>)>` data returned by
+/// `db.query_many()`.
+pub struct QueryManyIterator<'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>,
+  deletes: Arc<RwLock<HashSet<Id>>>,
+  bboxes: &'b [P::Bounds],
+  branch_factor: usize,
+  generation: u64,
+  // cursors and blocks paired with the indexes of the boxes that reach them
+  cursors: Vec<(usize,Cursor,Vec<usize>)>,
+  blocks: Vec<(Block,Vec<usize>)>,
+  queue: Vec<Matched<P,V>>
+}
+
+impl<'b,S,P,V> QueryManyIterator<'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  pub fn new (trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>, deletes: Arc<RwLock<HashSet<Id>>>,
+  staged: Vec<Matched<P,V>>, bboxes: &'b [P::Bounds], branch_factor: usize,
+  generation: u64) -> Self {
+    let all: Vec<usize> = (0..bboxes.len()).collect();
+    let cursors = (0..trees.len()).map(|t| (t,(0,0),all.clone())).collect();
+    Self {
+      trees,
+      data_store,
+      deletes,
+      bboxes,
+      branch_factor,
+      generation,
+      cursors,
+      blocks: vec![],
+      queue: staged
+    }
+  }
+}
+
+/// Return the indexes in `boxes` of the bounding boxes in `bboxes` that
+/// `point` overlaps.
+pub fn matches<P> (point: &P, bboxes: &[P::Bounds], boxes: &[usize])
+-> Vec<usize> where P: Point {
+  boxes.iter().filter(|i| point.overlaps(&bboxes[**i])).cloned().collect()
+}
+
+impl<'b,S,P,V> Iterator for QueryManyIterator<'b,S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  type Item = Result<Matched<P,V>,Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(row) = self.queue.pop() {
+        return Some(Ok(row));
+      }
+      if let Some((block,boxes)) = self.blocks.pop() {
+        let rows = iwrap![iwrap![self.data_store.read_lock()].list(block)];
+        let deletes = iwrap![self.deletes.read_lock()];
+        for (p,v,loc) in rows {
+          if deletes.contains(&loc.2) { continue }
+          let m = matches(&p, self.bboxes, &boxes);
+          if m.is_empty() { continue }
+          self.queue.push((p,v,(loc.0,loc.1,loc.2,self.generation),m));
+        }
+        continue
+      }
+      let (t,(offset,depth),boxes) = match self.cursors.pop() {
+        None => return None,
+        Some(c) => c
+      };
+      let buf = {
+        let mut tree = iwrap![self.trees[t].acquire()];
+        let len = iwrap![tree.store.len()];
+        if offset >= len { continue }
+        iwrap![read_block(&mut tree.store, offset, len, 1024)]
+      };
+      // merge the cursors and blocks reached by each box
+      let mut cursors: HashMap<Cursor,Vec<usize>> = HashMap::new();
+      let mut blocks: HashMap<Block,Vec<usize>> = HashMap::new();
+      for i in boxes {
+        let (c,b) = iwrap![
+          P::query_branch(&buf, &self.bboxes[i], self.branch_factor, depth)
+        ];
+        for cursor in c {
+          cursors.entry(cursor).or_insert_with(Vec::new).push(i);
+        }
+        for block in b {
+          blocks.entry(block).or_insert_with(Vec::new).push(i);
+        }
+      }
+      self.cursors.extend(cursors.into_iter().map(|(c,bx)| (t,c,bx)));
+      self.blocks.extend(blocks);
+    }
+  }
+}
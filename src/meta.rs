@@ -1,12 +1,82 @@
 use failure::{Error,bail};
 //use std::mem::size_of;
 use random_access_storage::RandomAccess;
+use crate::journal::Journal;
+use std::convert::TryInto;
+use crate::{Point,Value};
+
+/// Version of the on-disk format written by this version of eyros. Databases
+/// in an older format can be rewritten with `eyros::migrate()`.
+///
+/// The version is bumped with every change to the layout of any file, and
+/// `migrate()` reads every earlier version. Format 2 added block summaries to
+/// the range entries, the settings for versioned mode, the set of data files
+/// in use and per-batch history records, and changed the type fingerprint and
+/// the journal header.
+pub const FORMAT_VERSION: u16 = 2;
+// every meta file since format 1 starts with this header and the format version
+const MAGIC: [u8;5] = *b"eyros";
+const HEADER_LEN: usize = 7;
+// bytes of the settings stored after the mask
+const CONFIG_LEN: usize = 35;
+
+/// Settings a database was created with. These are stored in the meta file so
+/// that a database can't be opened with settings that don't match its files.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Config {
+  pub branch_factor: u16,
+  pub base_size: u64,
+  pub max_data_size: u64,
+  pub dimension: u16,
+  pub fingerprint: u64,
+  pub versioned: bool,
+  pub history_horizon: u64
+}
 
 #[derive(Debug)]
 pub struct Meta<S> where S: RandomAccess<Error=Error> {
   store: S,
   pub mask: Vec<bool>,
-  pub branch_factor: u16
+  pub next_id: u64,
+  pub generation: u64,
+  // set of data and range files in use, which compaction alternates between
+  pub data_files: u8,
+  // None for new databases and for meta files written before the settings
+  // were stored
+  pub config: Option<Config>
+}
+
+/// FNV-1a hash of the layout of `P` and `V` to tell apart databases written
+/// with different point or value types: the number of dimensions, the size of
+/// the pivot element for each dimension, the size of a point, the size of a
+/// value that is all zero bytes and `tag`. Unlike type names, these don't
+/// change between compiler versions. Types with the same layout, like `u32`
+/// and `i32`, are only told apart by `tag`.
+pub fn fingerprint<P,V> (tag: Option<&str>) -> u64 where P: Point, V: Value {
+  let zeros = [0u8;1024];
+  let mut facts = vec![P::dim() as u64];
+  for level in 0..P::dim() {
+    facts.push(P::count_bytes_at(&zeros, level).unwrap_or(0) as u64);
+  }
+  facts.push(P::count_from_bytes(&zeros).unwrap_or(0) as u64);
+  facts.push(V::count_from_bytes(&zeros).unwrap_or(0) as u64);
+  let mut bytes: Vec<u8> = facts.iter().flat_map(|x| x.to_be_bytes().to_vec())
+    .collect();
+  if let Some(tag) = tag {
+    bytes.push(1);
+    bytes.extend(tag.as_bytes());
+  }
+  fnv1a(&bytes)
+}
+
+/// 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a (bytes: &[u8]) -> u64 {
+  let mut hash = 0xcbf29ce484222325u64;
+  for b in bytes {
+    hash ^= *b as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
 }
 
 impl<S> Meta<S> where S: RandomAccess<Error=Error> {
@@ -14,7 +84,10 @@ impl<S> Meta<S> where S: RandomAccess<Error=Error> {
     let mut meta = Self {
       store,
       mask: vec![],
-      branch_factor: 9
+      next_id: 0,
+      generation: 0,
+      data_files: 0,
+      config: None
     };
     if !meta.store.is_empty()? {
       let len = meta.store.len()?;
@@ -24,29 +97,65 @@ impl<S> Meta<S> where S: RandomAccess<Error=Error> {
     Ok(meta)
   }
   pub fn save (&mut self) -> Result<(),Error> {
-    let mut bytes = vec![];
-    bytes.extend(&self.branch_factor.to_be_bytes());
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(&FORMAT_VERSION.to_be_bytes());
+    let bf = self.config.as_ref().map(|c| c.branch_factor).unwrap_or(0);
+    bytes.extend(&bf.to_be_bytes());
+    bytes.extend(&self.next_id.to_be_bytes());
+    bytes.extend(&self.generation.to_be_bytes());
+    bytes.push(self.data_files);
     bytes.extend(&(self.mask.len() as u32).to_be_bytes());
     let mbytes: Vec<u8> = (0..(self.mask.len()+7)/8).map(|i| {
       let mut b = 0u8;
-      for j in 0..8 {
-        b += (self.mask[i] as u8)*(1<<j);
+      for j in 0..8.min(self.mask.len()-i*8) {
+        b += (self.mask[i*8+j] as u8)*(1<<j);
       }
       b
     }).collect();
     bytes.extend(&mbytes);
+    if let Some(config) = &self.config {
+      bytes.extend(&config.base_size.to_be_bytes());
+      bytes.extend(&config.max_data_size.to_be_bytes());
+      bytes.extend(&config.dimension.to_be_bytes());
+      bytes.extend(&config.fingerprint.to_be_bytes());
+      bytes.push(config.versioned as u8);
+      bytes.extend(&config.history_horizon.to_be_bytes());
+    }
     self.store.write(0, &bytes)?;
+    self.store.sync_all()?;
     Ok(())
   }
-  fn load_buffer(&mut self, buf: &Vec<u8>) -> Result<(),Error> {
-    self.branch_factor = u16::from_be_bytes([buf[0],buf[1]]);
+  pub fn journal (&mut self, journal: &mut Journal<S>) -> Result<(),Error> {
+    journal.keep("meta", &mut self.store)
+  }
+  fn load_buffer(&mut self, buf: &[u8]) -> Result<(),Error> {
+    match format_version(buf) {
+      Some(FORMAT_VERSION) => {},
+      Some(version) => bail!("unsupported format version {} (expected {})",
+        version, FORMAT_VERSION),
+      None => bail!("database is in the format of eyros 2.0.0 or earlier. \
+        Rewrite it in the current format with eyros::migrate()")
+    }
+    let buf = &buf[HEADER_LEN..];
+    if buf.len() < 23 {
+      bail!("unexpected buffer length");
+    }
+    let branch_factor = u16::from_be_bytes([buf[0],buf[1]]);
+    self.next_id = u64::from_be_bytes([
+      buf[2],buf[3],buf[4],buf[5],buf[6],buf[7],buf[8],buf[9]
+    ]);
+    self.generation = u64::from_be_bytes([
+      buf[10],buf[11],buf[12],buf[13],buf[14],buf[15],buf[16],buf[17]
+    ]);
+    self.data_files = buf[18];
     self.mask.clear();
-    let len = u32::from_be_bytes([buf[2],buf[3],buf[4],buf[5]]) as usize;
-    if (len+7)/8+6 != buf.len() {
+    let len = u32::from_be_bytes([buf[19],buf[20],buf[21],buf[22]]) as usize;
+    let end = (len+7)/8+23;
+    if end != buf.len() && end+CONFIG_LEN != buf.len() {
       bail!("unexpected buffer length");
     }
     for i in 0..(len+7)/8 {
-      let b = buf[i+6];
+      let b = buf[i+23];
       for j in 0..8 {
         if i*8+j >= len { break }
         self.mask.push((b>>j)&1 == 1);
@@ -55,6 +164,25 @@ impl<S> Meta<S> where S: RandomAccess<Error=Error> {
     if self.mask.len() != len {
       bail!("mask has unexpected length");
     }
+    self.config = match end == buf.len() {
+      true => None,
+      false => Some(Config {
+        branch_factor,
+        base_size: u64::from_be_bytes(buf[end..end+8].try_into()?),
+        max_data_size: u64::from_be_bytes(buf[end+8..end+16].try_into()?),
+        dimension: u16::from_be_bytes(buf[end+16..end+18].try_into()?),
+        fingerprint: u64::from_be_bytes(buf[end+18..end+26].try_into()?),
+        versioned: buf[end+26] != 0,
+        history_horizon: u64::from_be_bytes(buf[end+27..end+35].try_into()?)
+      })
+    };
     Ok(())
   }
 }
+
+/// Format version from the header of the meta file contents `buf`, or `None`
+/// for databases written before the format was versioned.
+pub fn format_version (buf: &[u8]) -> Option<u16> {
+  if buf.len() < HEADER_LEN || buf[0..5] != MAGIC { return None }
+  Some(u16::from_be_bytes([buf[5],buf[6]]))
+}
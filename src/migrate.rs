@@ -0,0 +1,249 @@
+use crate::{DB,Setup,Point,Spatial,Value,Id,Location};
+use crate::meta::{format_version,FORMAT_VERSION};
+use crate::tree::data_pointers;
+use crate::read_block::read_block;
+use crate::lock::Acquire;
+use random_access_storage::RandomAccess;
+use desert::FromBytes;
+use std::convert::TryInto;
+use failure::{Error,bail,ensure};
+use std::collections::{HashSet,VecDeque};
+
+// location of a record in format 0: (block offset + 1 or 0 for staging, index)
+type Location0 = (u64,u32);
+
+/// Rewrite the database in an older on-disk format from the storage function
+/// `open_src` into a new database created from `setup`, returning the new
+/// database.
+///
+/// Format 0 is the format written by eyros 2.0.0 and earlier, before the
+/// format was versioned. Those databases didn't store their settings, so
+/// `setup` must have the same branch factor the old database was written with
+/// to read its trees. Format 1 databases store their branch factor, but not
+/// the block summaries, the settings for versioned mode or the set of data
+/// files of format 2. The records of every tree and the staging area are
+/// loaded into the new database with `db.bulk_load()` and staged deletes are
+/// applied. Records get new ids in the order they are read and the version
+/// history of a versioned database is not kept.
+///
+/// The old database is only read. The new database must be empty. Every
+/// record is read before any are written, so nothing is loaded into the new
+/// database if the old database can't be read.
+///
+/// ```rust,no_run
+/// # use eyros::{DB,Setup};
+/// # use failure::Error;
+/// # use random_access_disk::RandomAccessDisk;
+/// # use std::path::PathBuf;
+/// # fn main () -> Result<(),Error> {
+/// type P = ((f32,f32),(f32,f32));
+/// type V = u32;
+/// let db: DB<_,_,P,V> = eyros::migrate(
+///   |name: &str| storage("/tmp/eyros-db/", name),
+///   Setup::new(|name: &str| storage("/tmp/eyros-db-migrated/", name))
+///     .branch_factor(5)
+/// )?;
+/// # Ok(()) }
+/// # fn storage(dir: &str, name: &str) -> Result<RandomAccessDisk,Error> {
+/// #   let mut p = PathBuf::from(dir);
+/// #   p.push(name);
+/// #   Ok(RandomAccessDisk::builder(p).auto_sync(false).build()?)
+/// # }
+/// ```
+pub fn migrate<S,U,T,W,P,V> (open_src: U, setup: Setup<T,W>)
+-> Result<DB<T,W,P,V>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error>,
+T: RandomAccess<Error=Error>, W: Fn(&str) -> Result<T,Error>,
+P: Spatial, V: Value {
+  let mut meta = open_src("meta")?;
+  ensure![!meta.is_empty()?, "no database to migrate"];
+  let len = meta.len()?;
+  let format = match format_version(&meta.read(0, len)?) {
+    None => 0,
+    Some(1) => 1,
+    Some(FORMAT_VERSION) => bail!["database is already in format {}",
+      FORMAT_VERSION],
+    Some(version) => bail!["unsupported format version {}", version]
+  };
+  let mut reader = Reader::<S,P,V>::open(&open_src, format,
+    setup.fields.branch_factor)?;
+  let mut db: DB<T,W,P,V> = setup.build()?;
+  let mut empty = db.meta.next_id == 0 && db.staging.len()? == 0;
+  for tree in db.trees.iter() {
+    empty = empty && tree.acquire()?.is_empty()?;
+  }
+  ensure![empty, "database to migrate into is not empty"];
+  db.try_bulk_load(&mut reader)?;
+  Ok(db)
+}
+
+// reader for the live records of a database in format 0 or 1.
+//
+// in format 0, data blocks and staged inserts hold (point,value) rows, staged
+// deletes are locations without record ids, and the meta file is
+// [branch factor: u16][tree mask length: u32][tree mask bitfield]. the stored
+// branch factor and mask bits were never updated, so trees are found by their
+// file contents instead.
+//
+// in format 1, data blocks and staged inserts hold (point,value,id) rows,
+// staged deletes are full locations and the meta file is
+// [magic: "eyros"][format version: u16][branch factor: u16]
+// [next record id: u64][generation: u64][tree mask length: u32]
+// [tree mask bitfield] followed by settings that aren't needed to read it.
+// staged deletes are matched by record id since records keep their id when
+// they move
+struct Reader<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  format: u16,
+  data: S,
+  blocks: Vec<u64>,
+  inserts: Vec<(P,V,Option<Id>)>,
+  deletes: HashSet<Location0>,
+  deleted_ids: HashSet<Id>,
+  rows: VecDeque<(P,V)>,
+  block: usize,
+  staged: usize
+}
+
+impl<S,P,V> Reader<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  fn open<U> (open_src: &U, format: u16, branch_factor: usize)
+  -> Result<Self,Error> where U: Fn(&str) -> Result<S,Error> {
+    let mut meta = open_src("meta")?;
+    let len = meta.len()?;
+    let buf = meta.read(0, len)?;
+    let (branch_factor,trees) = match format {
+      0 => {
+        ensure![buf.len() >= 6, "unexpected meta length"];
+        let ntrees = u32::from_be_bytes(buf[2..6].try_into()?) as usize;
+        ensure![(ntrees+7)/8+6 == buf.len(), "unexpected meta length"];
+        (branch_factor, (0..ntrees).collect())
+      },
+      _ => {
+        ensure![buf.len() >= 29, "unexpected meta length"];
+        let bf = u16::from_be_bytes(buf[7..9].try_into()?) as usize;
+        let ntrees = u32::from_be_bytes(buf[25..29].try_into()?) as usize;
+        ensure![(ntrees+7)/8+29 <= buf.len(), "unexpected meta length"];
+        let trees: Vec<usize> = (0..ntrees)
+          .filter(|i| (buf[29+i/8]>>(i%8))&1 == 1)
+          .collect();
+        (bf, trees)
+      }
+    };
+    let mut blocks = vec![];
+    for i in trees {
+      let mut store = open_src(&format!("tree{}",i))?;
+      for (_,block) in data_pointers::<S,P>(&mut store, branch_factor)? {
+        blocks.push(block);
+      }
+    }
+    blocks.sort_unstable();
+    blocks.dedup();
+    let mut istore = open_src("staging_inserts")?;
+    let mut dstore = open_src("staging_deletes")?;
+    let (inserts,deletes,deleted_ids) = match format {
+      0 => (
+        read_all::<S,(P,V)>(&mut istore)?.into_iter()
+          .map(|(p,v)| (p,v,None)).collect(),
+        read_all::<S,Location0>(&mut dstore)?.into_iter().collect(),
+        HashSet::new()
+      ),
+      _ => (
+        read_all::<S,(P,V,Id)>(&mut istore)?.into_iter()
+          .map(|(p,v,id)| (p,v,Some(id))).collect(),
+        HashSet::new(),
+        read_all::<S,Location>(&mut dstore)?.into_iter()
+          .map(|loc| loc.2).collect()
+      )
+    };
+    Ok(Self {
+      format,
+      data: open_src("data")?,
+      blocks,
+      inserts,
+      deletes,
+      deleted_ids,
+      rows: VecDeque::new(),
+      block: 0,
+      staged: 0
+    })
+  }
+  // whether a staged delete removes the record at `index` of the data block
+  // at `block+1`, or of staging for a `block` of 0, with the record id `id`
+  fn deleted (&self, block: u64, index: usize, id: Option<Id>) -> bool {
+    match id {
+      Some(id) => self.deleted_ids.contains(&id),
+      None => self.deletes.contains(&(block,index as u32))
+    }
+  }
+  fn next_row (&mut self) -> Result<Option<(P,V)>,Error> {
+    loop {
+      if let Some(row) = self.rows.pop_front() { return Ok(Some(row)) }
+      if self.block < self.blocks.len() {
+        let offset = self.blocks[self.block];
+        self.block += 1;
+        self.read_block(offset)?;
+        continue
+      }
+      while self.staged < self.inserts.len() {
+        let i = self.staged;
+        self.staged += 1;
+        let (p,v,id) = &self.inserts[i];
+        if !self.deleted(0, i, *id) {
+          return Ok(Some((*p,v.clone())))
+        }
+      }
+      return Ok(None)
+    }
+  }
+  // queue the rows of the data block at `offset` that weren't deleted
+  fn read_block (&mut self, offset: u64) -> Result<(),Error> {
+    let len = self.data.len()?;
+    let buf = read_block(&mut self.data, offset, len, 1024)?;
+    let bitfield_len = u16::from_be_bytes([buf[0],buf[1]]) as usize;
+    let bitfield = &buf[2..2+bitfield_len];
+    let mut pos = 2+bitfield_len;
+    let mut index = 0;
+    while pos < buf.len() {
+      let (size,(p,v)) = <(P,V)>::from_bytes(&buf[pos..])?;
+      pos += size;
+      let id = match self.format {
+        0 => None,
+        _ => {
+          let (size,id) = Id::from_bytes(&buf[pos..])?;
+          pos += size;
+          Some(id)
+        }
+      };
+      let live = ((bitfield[index/8]>>(index%8))&1) == 1
+        && !self.deleted(offset+1, index, id);
+      if live { self.rows.push_back((p,v)) }
+      index += 1;
+    }
+    Ok(())
+  }
+}
+
+impl<S,P,V> Iterator for Reader<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  type Item = Result<(P,V),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    self.next_row().transpose()
+  }
+}
+
+// parse every record in `store`, which holds records one after another
+fn read_all<S,T> (store: &mut S) -> Result<Vec<T>,Error>
+where S: RandomAccess<Error=Error>, T: FromBytes {
+  let mut records = vec![];
+  if store.is_empty()? { return Ok(records) }
+  let len = store.len()?;
+  let buf = store.read(0, len)?;
+  let mut offset = 0;
+  while offset < buf.len() {
+    let (size,record) = T::from_bytes(&buf[offset..])?;
+    records.push(record);
+    offset += size;
+  }
+  Ok(records)
+}
@@ -1,4 +1,6 @@
-use crate::{Point,Cursor,Block,order,order_len};
+use crate::{Point,Spatial,Predicate,Cursor,Block,Extents,branch_regions};
+use crate::{ToF64,Euclidean};
+use crate::{order,order_len};
 use failure::{Error,bail};
 use std::mem::size_of;
 
@@ -229,7 +231,17 @@ macro_rules! impl_mix {
         }))+
       }
 
-      fn query_branch (buf: &[u8], bbox: &Self::Bounds, bf: usize, level: usize)
+      fn bounds_within (bbox: &Self::Bounds, outer: &Self::Bounds) -> bool {
+        true $(&& (outer.0).$i <= (bbox.0).$i && (bbox.1).$i <= (outer.1).$i)+
+      }
+
+      fn query_branch (buf: &[u8], bbox: &Self::Bounds, bf: usize,
+      level: usize) -> Result<(Vec<Cursor>,Vec<Block>),Error> {
+        Self::query_branch_with(buf, bbox, Predicate::Overlaps, bf, level)
+      }
+
+      fn query_branch_with (buf: &[u8], bbox: &Self::Bounds,
+      predicate: Predicate, bf: usize, level: usize)
       -> Result<(Vec<Cursor>,Vec<Block>),Error> {
         let mut cursors = vec![];
         let mut blocks = vec![];
@@ -259,7 +271,11 @@ macro_rules! impl_mix {
           let cmp = match dim {
             $($i => {
               let pivot = pivots.$i[i];
-              ((bbox.0).$i <= pivot, pivot <= (bbox.1).$i)
+              let (lo,hi) = match predicate {
+                Predicate::Contains => ((bbox.1).$i,(bbox.0).$i),
+                _ => ((bbox.0).$i,(bbox.1).$i)
+              };
+              (lo <= pivot, pivot <= hi)
             },)+
             _ => panic!["dimension not expected"]
           };
@@ -369,6 +385,41 @@ macro_rules! impl_mix {
         unimplemented![]
       }
     }
+
+    impl<$($T),+> Spatial for $M<$($T),+> where ($(($T,$T)),+): Point,
+    $($T: ToBytes+FromBytes+CountBytes+Copy+Debug+PartialOrd
+    +Add<Output=$T>+Div<Output=$T>+From<u8>+ToF64),+ {
+      type Distance = Euclidean;
+
+      fn extents (&self) -> Extents {
+        vec![$(match self.$v {
+          Mix::Scalar(x) => (x.to_f64(),x.to_f64()),
+          Mix::Interval(x0,x1) => (x0.to_f64(),x1.to_f64())
+        }),+]
+      }
+
+      fn bounds_extents (bbox: &Self::Bounds) -> Extents {
+        vec![$(((bbox.0).$i.to_f64(),(bbox.1).$i.to_f64())),+]
+      }
+
+      fn nearest_branch (buf: &[u8], region: &Extents, bf: usize,
+      level: usize) -> Result<(Vec<(Cursor,Extents)>,Vec<(Block,Extents)>),Error> {
+        let n = order_len(bf);
+        let mut pivots = Vec::with_capacity(n);
+        let mut offset = 0;
+        for _i in 0..n {
+          match level % Self::dim() {
+            $($i => {
+              let (size,pivot) = $T::from_bytes(&buf[offset..])?;
+              pivots.push(pivot.to_f64());
+              offset += size;
+            },)+
+            _ => panic!["dimension not expected"]
+          }
+        }
+        branch_regions(buf, &pivots, offset, region, bf, level)
+      }
+    }
   }
 }
 
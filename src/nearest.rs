@@ -0,0 +1,41 @@
+use crate::{Point,Value,Location,Block,Cursor,Extents};
+use std::cmp::Ordering;
+
+// Entries in the best-first search, ordered so that a max-heap pops the
+// nearest entry first. Records come before branches and blocks at the same
+// distance.
+pub enum Entry<P,V> where P: Point, V: Value {
+  Record(f64,(P,V,Location)),
+  Block(f64,Block),
+  Branch(f64,usize,Cursor,Extents)
+}
+
+impl<P,V> Entry<P,V> where P: Point, V: Value {
+  fn key (&self) -> (f64,u8) {
+    match self {
+      Entry::Record(d,_) => (*d,0),
+      Entry::Block(d,_) => (*d,1),
+      Entry::Branch(d,_,_,_) => (*d,1)
+    }
+  }
+}
+
+impl<P,V> PartialEq for Entry<P,V> where P: Point, V: Value {
+  fn eq (&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+impl<P,V> Eq for Entry<P,V> where P: Point, V: Value {}
+
+impl<P,V> PartialOrd for Entry<P,V> where P: Point, V: Value {
+  fn partial_cmp (&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<P,V> Ord for Entry<P,V> where P: Point, V: Value {
+  fn cmp (&self, other: &Self) -> Ordering {
+    let (a,b) = (self.key(),other.key());
+    b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(b.1.cmp(&a.1))
+  }
+}
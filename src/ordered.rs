@@ -0,0 +1,114 @@
+use crate::{Spatial,Value,Location,Id,Tree,DataStore,Extents};
+use crate::nearest::Entry;
+use crate::point::separation;
+use crate::read_block::read_block;
+use crate::lock::{Acquire,ReadWrite};
+use random_access_storage::RandomAccess;
+use failure::Error;
+use std::collections::{BinaryHeap,HashSet};
+use std::sync::{Arc,Mutex,RwLock};
+
+/// Iterator of `Result<(Point,Value,Location)>` data returned by
+/// `db.query_ordered()`, in increasing order of the lower bound of each record
+/// along one dimension.
+///
+/// Branches and data blocks from every tree share one queue ordered by the
+/// lower bound of the region that contains them, so the trees are merged
+/// while they are read instead of being sorted in memory.
+pub struct OrderedIterator<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>,
+  deletes: Arc<RwLock<HashSet<Id>>>,
+  bbox: P::Bounds,
+  region: Extents,
+  dim: usize,
+  branch_factor: usize,
+  generation: u64,
+  queue: BinaryHeap<Entry<P,V>>
+}
+
+impl<S,P,V> OrderedIterator<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  pub fn new (trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>, deletes: Arc<RwLock<HashSet<Id>>>,
+  staged: Vec<(P,V,Location)>, bbox: &P::Bounds, dim: usize,
+  branch_factor: usize, generation: u64) -> Self {
+    let mut queue = BinaryHeap::new();
+    for row in staged {
+      queue.push(Entry::Record(row.0.extents()[dim].0, row));
+    }
+    let unbounded: Extents = vec![(std::f64::NEG_INFINITY,std::f64::INFINITY);
+      P::dim()];
+    for t in 0..trees.len() {
+      queue.push(Entry::Branch(std::f64::NEG_INFINITY, t, (0,0),
+        unbounded.clone()));
+    }
+    Self {
+      trees,
+      data_store,
+      deletes,
+      bbox: *bbox,
+      region: P::bounds_extents(bbox),
+      dim,
+      branch_factor,
+      generation,
+      queue
+    }
+  }
+}
+
+fn overlaps (a: &[(f64,f64)], b: &[(f64,f64)]) -> bool {
+  a.iter().zip(b.iter()).all(|(x,y)| separation(*x,*y) == 0.0)
+}
+
+impl<S,P,V> Iterator for OrderedIterator<S,P,V>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value {
+  type Item = Result<(P,V,Location),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    let dim = self.dim;
+    while let Some(entry) = self.queue.pop() {
+      match entry {
+        Entry::Record(_,row) => return Some(Ok(row)),
+        Entry::Branch(_,t,(offset,depth),region) => {
+          let buf = {
+            let mut tree = iwrap![self.trees[t].acquire()];
+            let len = iwrap![tree.store.len()];
+            if offset >= len { continue }
+            iwrap![read_block(&mut tree.store, offset, len, 1024)]
+          };
+          let (cursors,blocks) = iwrap![
+            P::nearest_branch(&buf, &region, self.branch_factor, depth)
+          ];
+          for (cursor,r) in cursors {
+            if !overlaps(&r, &self.region) { continue }
+            self.queue.push(Entry::Branch(r[dim].0, t, cursor, r));
+          }
+          let dstore = iwrap![self.data_store.read_lock()];
+          for (block,r) in blocks {
+            if !overlaps(&r, &self.region) { continue }
+            // empty blocks have no bounds
+            if let Some((bbox,_)) = iwrap![dstore.bbox(block)] {
+              let b = P::bounds_extents(&bbox);
+              if !overlaps(&b, &self.region) { continue }
+              self.queue.push(Entry::Block(r[dim].0.max(b[dim].0), block));
+            }
+          }
+        },
+        Entry::Block(_,block) => {
+          let rows = {
+            let dstore = iwrap![self.data_store.read_lock()];
+            iwrap![dstore.query(block, &self.bbox)]
+          };
+          let deletes = iwrap![self.deletes.read_lock()];
+          for (p,v,loc) in rows {
+            if deletes.contains(&loc.2) { continue }
+            let row = (p,v,(loc.0,loc.1,loc.2,self.generation));
+            self.queue.push(Entry::Record(p.extents()[dim].0, row));
+          }
+        }
+      }
+    }
+    None
+  }
+}
@@ -0,0 +1,93 @@
+use crate::{Cursor,Block};
+use desert::{ToBytes,FromBytes,CountBytes};
+use failure::Error;
+
+type TokenBytes = (u64,u64,u64,u64,Vec<(u64,u64)>,Vec<u64>,(u64,u32));
+
+/// Position of a paginated query from `db.query_page()`.
+///
+/// Tokens record the cursors and data blocks left to visit in each tree and
+/// the position in the staging records. Use `to_bytes()` and `from_bytes()`
+/// to send a token to a client and read it back. A token is only valid until
+/// the next batch that changes the trees and only for the bounding box of the
+/// query that issued it.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Token {
+  pub(crate) generation: u64,
+  // hash of the serialized bounding box of the query
+  pub(crate) bbox: u64,
+  pub(crate) tree: usize,
+  pub(crate) cursors: Vec<Cursor>,
+  pub(crate) blocks: Vec<Block>,
+  // data block in progress and the index of the next record to return
+  pub(crate) block: Option<(Block,u32)>,
+  pub(crate) staging: usize
+}
+
+impl Token {
+  pub(crate) fn new (generation: u64, bbox: u64) -> Self {
+    Self {
+      generation,
+      bbox,
+      tree: 0,
+      cursors: vec![(0,0)],
+      blocks: vec![],
+      block: None,
+      staging: 0
+    }
+  }
+  /// Generation of the database when the token was issued.
+  pub fn generation (&self) -> u64 {
+    self.generation
+  }
+  fn to_tuple (&self) -> TokenBytes {
+    (
+      self.generation,
+      self.bbox,
+      self.tree as u64,
+      self.staging as u64,
+      self.cursors.iter().map(|(c,depth)| (*c,*depth as u64)).collect(),
+      self.blocks.clone(),
+      match self.block {
+        None => (0,0),
+        Some((b,i)) => (b+1,i)
+      }
+    )
+  }
+}
+
+impl CountBytes for Token {
+  fn count_from_bytes (buf: &[u8]) -> Result<usize,Error> {
+    TokenBytes::count_from_bytes(buf)
+  }
+  fn count_bytes (&self) -> usize {
+    self.to_tuple().count_bytes()
+  }
+}
+
+impl ToBytes for Token {
+  fn to_bytes (&self) -> Result<Vec<u8>,Error> {
+    self.to_tuple().to_bytes()
+  }
+  fn write_bytes (&self, dst: &mut [u8]) -> Result<usize,Error> {
+    self.to_tuple().write_bytes(dst)
+  }
+}
+
+impl FromBytes for Token {
+  fn from_bytes (src: &[u8]) -> Result<(usize,Self),Error> {
+    let (size,t) = TokenBytes::from_bytes(src)?;
+    Ok((size, Self {
+      generation: t.0,
+      bbox: t.1,
+      tree: t.2 as usize,
+      staging: t.3 as usize,
+      cursors: t.4.iter().map(|(c,depth)| (*c,*depth as usize)).collect(),
+      blocks: t.5,
+      block: match t.6 {
+        (0,_) => None,
+        (b,i) => Some((b-1,i))
+      }
+    }))
+  }
+}
@@ -17,9 +17,25 @@ use desert::{ToBytes,FromBytes,CountBytes};
 ///
 /// Presently only types with static sizes are supported.
 
+/// Relation between a record and a bounding box for `db.query_with()`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Predicate {
+  /// The record intersects the bounding box. This is what `db.query()` uses.
+  Overlaps,
+  /// The record is entirely inside of the bounding box.
+  Within,
+  /// The record entirely contains the bounding box, for example an interval
+  /// that covers the whole range of the box.
+  Contains
+}
+
 pub type Cursor = (u64,usize);
 pub type Block = u64;
 
+/// The `(min,max)` extent of each dimension as `f64` values, as used for
+/// distance calculations. Scalar elements have the same min and max.
+pub type Extents = Vec<(f64,f64)>;
+
 pub trait Point: Copy+Clone+Debug+ToBytes+FromBytes+CountBytes {
   /// Bounding-box corresponding to `(min,max)` as used by `db.query(bbox)`.
   type Bounds: Copy+Clone+Debug+ToBytes+FromBytes+CountBytes;
@@ -49,6 +65,21 @@ pub trait Point: Copy+Clone+Debug+ToBytes+FromBytes+CountBytes {
   /// Return whether the current point intersects with a bounding box.
   fn overlaps (&self, bbox: &Self::Bounds) -> bool;
 
+  /// Return whether the bounding box `bbox` is entirely inside of `outer`.
+  fn bounds_within (bbox: &Self::Bounds, outer: &Self::Bounds) -> bool;
+
+  /// Return whether the current point has the relation `predicate` to a
+  /// bounding box.
+  fn matches (&self, bbox: &Self::Bounds, predicate: Predicate) -> bool {
+    match predicate {
+      Predicate::Overlaps => self.overlaps(bbox),
+      Predicate::Within => Self::bounds(&vec![*self])
+        .map_or(false, |b| Self::bounds_within(&b, bbox)),
+      Predicate::Contains => Self::bounds(&vec![*self])
+        .map_or(false, |b| Self::bounds_within(bbox, &b))
+    }
+  }
+
   /// Return the size in bytes of the pivot-form of the element corresponding to
   /// the tree depth `level`.
   fn pivot_bytes_at (&self, level: usize) -> usize;
@@ -64,6 +95,23 @@ pub trait Point: Copy+Clone+Debug+ToBytes+FromBytes+CountBytes {
   fn query_branch (buf: &[u8], bbox: &Self::Bounds, branch_factor: usize,
     level: usize) -> Result<(Vec<Cursor>,Vec<Block>),Error>;
 
+  /// Return the cursors and blocks like `query_branch()` for the records with
+  /// the relation `predicate` to `bbox`.
+  ///
+  /// Only branches that can hold records with the relation `predicate` to
+  /// `bbox` need to be returned. Records that contain `bbox` must reach past
+  /// both of its edges, so for `Predicate::Contains` the comparisons against
+  /// the pivots use the upper bound of `bbox` in place of the lower bound and
+  /// the lower bound in place of the upper bound.
+  ///
+  /// Every record with one of the relations also overlaps `bbox`, so the
+  /// default implementation returns the branches from `query_branch()`.
+  fn query_branch_with (buf: &[u8], bbox: &Self::Bounds, _predicate: Predicate,
+    branch_factor: usize, level: usize)
+    -> Result<(Vec<Cursor>,Vec<Block>),Error> {
+    Self::query_branch(buf, bbox, branch_factor, level)
+  }
+
   /// Return a bounding box for a set of coordinates, if possible.
   fn bounds (coords: &Vec<Self>) -> Option<Self::Bounds>;
 
@@ -83,6 +131,59 @@ pub trait Point: Copy+Clone+Debug+ToBytes+FromBytes+CountBytes {
     -> Result<String,Error>;
 }
 
+/// Points with coordinates that can be measured as `f64` values, which
+/// `db.nearest()`, `db.query_region()`, `db.query_ordered()` and
+/// `db.bulk_load()` use for distances and regions. This is implemented for the
+/// built-in point types.
+pub trait Spatial: Point {
+  /// Distance metric used by `db.nearest()`.
+  type Distance: Distance+Default;
+
+  /// Return the `(min,max)` extent of each element for distance calculations.
+  fn extents (&self) -> Extents;
+
+  /// Return the `(min,max)` extent of each dimension of a bounding box.
+  fn bounds_extents (bbox: &Self::Bounds) -> Extents;
+
+  /// Return every `(branch_offset,tree_depth)` cursor and data block in the
+  /// branch data in `buf` at the tree depth `level`, each paired with the
+  /// region that contains its records. The regions are narrowed from
+  /// `region`, the region of the branch itself, by the pivots.
+  fn nearest_branch (buf: &[u8], region: &Extents, branch_factor: usize,
+    level: usize) -> Result<(Vec<(Cursor,Extents)>,Vec<(Block,Extents)>),Error>;
+}
+
+/// Distance metric for `db.nearest_by()`.
+///
+/// The distance is measured between two sets of extents. Either set can be a
+/// record, the query point, or the region of space that contains a branch or a
+/// data block. The distance between two extents must never be greater than the
+/// distance between any pair of points inside of them, which is what allows
+/// branches and blocks to be skipped.
+pub trait Distance {
+  fn distance (&self, a: &[(f64,f64)], b: &[(f64,f64)]) -> f64;
+}
+
+/// Euclidean distance between the nearest edges of two extents. This is the
+/// metric the built-in point types use for `db.nearest()`.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Euclidean;
+
+impl Distance for Euclidean {
+  fn distance (&self, a: &[(f64,f64)], b: &[(f64,f64)]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x,y)| {
+      let gap = separation(*x,*y);
+      gap*gap
+    }).sum::<f64>().sqrt()
+  }
+}
+
+/// Distance between the nearest edges of two `(min,max)` extents in one
+/// dimension, or `0.0` if they overlap.
+pub fn separation (a: (f64,f64), b: (f64,f64)) -> f64 {
+  (a.0-b.1).max(b.0-a.1).max(0.0)
+}
+
 pub trait Num<T>: PartialOrd+Copy+ToBytes+FromBytes+CountBytes
   +Debug+Scalar+From<u8>+Div<T,Output=T>+Add<T,Output=T> {}
 impl<T> Num<T> for T where T: PartialOrd+Copy+ToBytes+FromBytes+CountBytes
@@ -104,6 +205,21 @@ impl Scalar for i16 {}
 impl Scalar for i32 {}
 impl Scalar for i64 {}
 
+/// Scalars that are converted to `f64` to measure distances for
+/// `db.nearest()`.
+pub trait ToF64: Copy {
+  fn to_f64 (&self) -> f64;
+}
+
+macro_rules! impl_to_f64 {
+  ($($T:ty),+) => {
+    $(impl ToF64 for $T {
+      fn to_f64 (&self) -> f64 { *self as f64 }
+    })+
+  }
+}
+impl_to_f64![f32,f64,u8,u16,u32,u64,i8,i16,i32,i64];
+
 trait Coord<T> {
   fn cmp (&self, other: &Self) -> Option<Ordering>;
   fn midpoint_upper (&self, other: &Self) -> Self;
@@ -181,6 +297,75 @@ impl<T> Coord<T> for (T,T) where T: Scalar+PartialOrd+Num<T> {
   }
 }
 
+trait Extent<T> {
+  fn extent (&self) -> (f64,f64);
+}
+
+impl<T> Extent<T> for T where T: Scalar+ToF64 {
+  fn extent (&self) -> (f64,f64) { (self.to_f64(),self.to_f64()) }
+}
+
+impl<T> Extent<T> for (T,T) where T: Scalar+ToF64 {
+  fn extent (&self) -> (f64,f64) { (self.0.to_f64(),self.1.to_f64()) }
+}
+
+/// Find every cursor and block in the branch data in `buf` along with the
+/// region that contains its records, for implementing
+/// `Spatial::nearest_branch()`. `pivots` are the pivots of the branch converted
+/// to `f64` and `d_start` is the byte offset of the data bitfield that follows
+/// them.
+///
+/// Each pivot splits the range of the current dimension: records to the left of
+/// a pivot are at or below it and records to the right are at or above it, so
+/// the range narrows while walking down the pivots.
+pub fn branch_regions (buf: &[u8], pivots: &[f64], d_start: usize,
+region: &Extents, bf: usize, level: usize)
+-> Result<(Vec<(Cursor,Extents)>,Vec<(Block,Extents)>),Error> {
+  let mut cursors = vec![];
+  let mut blocks = vec![];
+  let n = order::order_len(bf);
+  let dim = level % region.len();
+  let i_start = d_start + (n+bf+7)/8; // intersections
+  let b_start = i_start + n*size_of::<u64>(); // buckets
+  ensure_eq!(b_start+bf*size_of::<u64>(), buf.len(), "unexpected block length");
+  let mut push = |j: usize, offset: usize, lo: f64, hi: f64| {
+    let ptr = u64::from_be_bytes([
+      buf[offset+0], buf[offset+1], buf[offset+2], buf[offset+3],
+      buf[offset+4], buf[offset+5], buf[offset+6], buf[offset+7]
+    ]);
+    if ptr == 0 { return }
+    let mut r = region.clone();
+    r[dim] = (r[dim].0.max(lo), r[dim].1.min(hi));
+    if ((buf[d_start+j/8]>>(j%8))&1) == 1 {
+      blocks.push((ptr-1,r));
+    } else {
+      cursors.push(((ptr-1,level+1),r));
+    }
+  };
+  // neighbouring leaves share a bucket, which lies between both pivots
+  let mut buckets: Vec<(f64,f64)> = vec![(region[dim].0,region[dim].1);bf];
+  let mut bcursors = vec![(0,region[dim].0,region[dim].1)];
+  while let Some((c,lo,hi)) = bcursors.pop() {
+    let i = order::order(bf, c);
+    let pivot = pivots[i];
+    push(i, i_start+i*8, lo, hi);
+    if c*2+1 < n {
+      bcursors.push((c*2+1,lo,pivot));
+    } else {
+      buckets[i/2].1 = buckets[i/2].1.min(pivot);
+    }
+    if c*2+2 < n {
+      bcursors.push((c*2+2,pivot,hi));
+    } else {
+      buckets[i/2+1].0 = buckets[i/2+1].0.max(pivot);
+    }
+  }
+  for (i,(lo,hi)) in buckets.into_iter().enumerate() {
+    push(n+i, b_start+i*8, lo, hi);
+  }
+  Ok((cursors,blocks))
+}
+
 macro_rules! impl_point {
   (($($T:tt),+),($($U:tt),+),($($i:tt),+),$dim:expr) => {
     impl<$($T),+> Point for ($($U),+)
@@ -210,6 +395,9 @@ macro_rules! impl_point {
       fn overlaps (&self, bbox: &Self::Bounds) -> bool {
         $(Coord::overlaps(&self.$i, &(bbox.0).$i, &(bbox.1).$i) &&)+ true
       }
+      fn bounds_within (bbox: &Self::Bounds, outer: &Self::Bounds) -> bool {
+        $((outer.0).$i <= (bbox.0).$i && (bbox.1).$i <= (outer.1).$i &&)+ true
+      }
       fn pivot_bytes_at (&self, i: usize) -> usize {
         match i % $dim {
           $($i => size_of::<$T>(),)+
@@ -222,7 +410,12 @@ macro_rules! impl_point {
           _ => panic!("dimension out of bounds")
         }
       }
-      fn query_branch (buf: &[u8], bbox: &Self::Bounds, bf: usize, level: usize)
+      fn query_branch (buf: &[u8], bbox: &Self::Bounds, bf: usize,
+      level: usize) -> Result<(Vec<Cursor>,Vec<Block>),Error> {
+        Self::query_branch_with(buf, bbox, Predicate::Overlaps, bf, level)
+      }
+      fn query_branch_with (buf: &[u8], bbox: &Self::Bounds,
+      predicate: Predicate, bf: usize, level: usize)
       -> Result<(Vec<Cursor>,Vec<Block>),Error> {
         let mut cursors = vec![];
         let mut blocks = vec![];
@@ -254,10 +447,11 @@ macro_rules! impl_point {
           let cmp = match level % $dim {
             $($i => {
               let pivot = (pivots.$i)[i];
-              (
-                (bbox.0).$i <= pivot,
-                pivot <= (bbox.1).$i
-              )
+              let (lo,hi) = match predicate {
+                Predicate::Contains => ((bbox.1).$i,(bbox.0).$i),
+                _ => ((bbox.0).$i,(bbox.1).$i)
+              };
+              (lo <= pivot, pivot <= hi)
             },)+
             _ => panic!["dimension out of bounds"]
           };
@@ -342,6 +536,34 @@ macro_rules! impl_point {
         })
       }
     }
+
+    impl<$($T),+> Spatial for ($($U),+)
+    where $($T: Num<$T>+ToF64),+ {
+      type Distance = Euclidean;
+      fn extents (&self) -> Extents {
+        vec![$(Extent::extent(&self.$i)),+]
+      }
+      fn bounds_extents (bbox: &Self::Bounds) -> Extents {
+        vec![$(((bbox.0).$i.to_f64(),(bbox.1).$i.to_f64())),+]
+      }
+      fn nearest_branch (buf: &[u8], region: &Extents, bf: usize,
+      level: usize) -> Result<(Vec<(Cursor,Extents)>,Vec<(Block,Extents)>),Error> {
+        let n = order::order_len(bf);
+        let mut offset = 0;
+        let mut pivots = Vec::with_capacity(n);
+        for _i in 0..n {
+          match level % $dim {
+            $($i => {
+              let (size,x) = $T::from_bytes(&buf[offset..])?;
+              pivots.push(x.to_f64());
+              offset += size;
+            },)+
+            _ => panic!["dimension out of bounds"]
+          };
+        }
+        branch_regions(buf, &pivots, offset, region, bf, level)
+      }
+    }
   }
 }
 
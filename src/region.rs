@@ -0,0 +1,227 @@
+use crate::{Spatial,Value,Location,Id,Tree,DataStore,Extents,Cursor,Block};
+use crate::read_block::read_block;
+use crate::lock::{Acquire,ReadWrite};
+use random_access_storage::RandomAccess;
+use failure::Error;
+use std::collections::HashSet;
+use std::sync::{Arc,Mutex,RwLock};
+
+/// Region of space for `db.query_region()`.
+///
+/// Boxes and points are given as the `(min,max)` extent of each dimension as
+/// `f64` values. Dimensions beyond the extents returned by `bounds()` are not
+/// constrained by the region.
+pub trait QueryRegion {
+  /// Return the `(min,max)` extent of the region in each dimension.
+  fn bounds (&self) -> Extents;
+
+  /// Return whether the region intersects `bbox`. This is used to skip
+  /// branches and data blocks, so it may return `true` for boxes that are only
+  /// near the region but must never return `false` for boxes that intersect.
+  fn overlaps_box (&self, bbox: &[(f64,f64)]) -> bool;
+
+  /// Return whether the region intersects a record with the extents `point`.
+  /// Intervals have a different min and max.
+  fn overlaps (&self, point: &[(f64,f64)]) -> bool {
+    self.overlaps_box(point)
+  }
+}
+
+/// Circle of `radius` around `center` in the first two dimensions.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Radius {
+  pub center: (f64,f64),
+  pub radius: f64
+}
+
+impl Radius {
+  pub fn new (center: (f64,f64), radius: f64) -> Self {
+    Self { center, radius }
+  }
+}
+
+impl QueryRegion for Radius {
+  fn bounds (&self) -> Extents {
+    let (x,y) = self.center;
+    vec![(x-self.radius,x+self.radius),(y-self.radius,y+self.radius)]
+  }
+  fn overlaps_box (&self, bbox: &[(f64,f64)]) -> bool {
+    // distance from the center to the nearest point of the box
+    let dx = (bbox[0].0-self.center.0).max(self.center.0-bbox[0].1).max(0.0);
+    let dy = (bbox[1].0-self.center.1).max(self.center.1-bbox[1].1).max(0.0);
+    dx*dx + dy*dy <= self.radius*self.radius
+  }
+}
+
+/// Polygon in the first two dimensions with `points` as its vertices in
+/// order. The last vertex connects back to the first. Polygons may be concave
+/// and their edges are part of the polygon.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Polygon {
+  pub points: Vec<(f64,f64)>
+}
+
+impl Polygon {
+  pub fn new (points: Vec<(f64,f64)>) -> Self {
+    Self { points }
+  }
+  fn edges (&self) -> impl Iterator<Item=((f64,f64),(f64,f64))> + '_ {
+    let n = self.points.len();
+    (0..n).map(move |i| (self.points[i],self.points[(i+1)%n]))
+  }
+  /// Return whether `(x,y)` is inside of the polygon by the even-odd rule.
+  pub fn contains (&self, x: f64, y: f64) -> bool {
+    let mut inside = false;
+    for (a,b) in self.edges() {
+      if (a.1 > y) != (b.1 > y)
+      && x < a.0 + (y-a.1)/(b.1-a.1)*(b.0-a.0) {
+        inside = !inside;
+      }
+    }
+    inside
+  }
+}
+
+// whether the segments a0-a1 and b0-b1 touch or cross
+fn segments_intersect (a0: (f64,f64), a1: (f64,f64), b0: (f64,f64),
+b1: (f64,f64)) -> bool {
+  fn cross (o: (f64,f64), a: (f64,f64), b: (f64,f64)) -> f64 {
+    (a.0-o.0)*(b.1-o.1) - (a.1-o.1)*(b.0-o.0)
+  }
+  fn on_segment (p: (f64,f64), a: (f64,f64), b: (f64,f64)) -> bool {
+    a.0.min(b.0) <= p.0 && p.0 <= a.0.max(b.0)
+    && a.1.min(b.1) <= p.1 && p.1 <= a.1.max(b.1)
+  }
+  let d1 = cross(b0, b1, a0);
+  let d2 = cross(b0, b1, a1);
+  let d3 = cross(a0, a1, b0);
+  let d4 = cross(a0, a1, b1);
+  if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+  && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+    return true
+  }
+  (d1 == 0.0 && on_segment(a0, b0, b1))
+    || (d2 == 0.0 && on_segment(a1, b0, b1))
+    || (d3 == 0.0 && on_segment(b0, a0, a1))
+    || (d4 == 0.0 && on_segment(b1, a0, a1))
+}
+
+impl QueryRegion for Polygon {
+  fn bounds (&self) -> Extents {
+    let mut x = (std::f64::INFINITY,std::f64::NEG_INFINITY);
+    let mut y = (std::f64::INFINITY,std::f64::NEG_INFINITY);
+    for p in self.points.iter() {
+      x = (x.0.min(p.0),x.1.max(p.0));
+      y = (y.0.min(p.1),y.1.max(p.1));
+    }
+    vec![x,y]
+  }
+  fn overlaps_box (&self, bbox: &[(f64,f64)]) -> bool {
+    let ((x0,x1),(y0,y1)) = (bbox[0],bbox[1]);
+    // a vertex inside of the box
+    if self.points.iter().any(|p| {
+      x0 <= p.0 && p.0 <= x1 && y0 <= p.1 && p.1 <= y1
+    }) {
+      return true
+    }
+    // the box inside of the polygon
+    if self.contains(x0, y0) { return true }
+    // an edge crossing the box
+    let corners = [(x0,y0),(x1,y0),(x1,y1),(x0,y1)];
+    self.edges().any(|(a,b)| {
+      (0..4).any(|i| segments_intersect(a, b, corners[i], corners[(i+1)%4]))
+    })
+  }
+}
+
+/// Iterator of `Result<(Point,Value,Location)>` data returned by
+/// `db.query_region()`.
+pub struct RegionIterator<'r,S,P,V,R>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value, R: QueryRegion {
+  trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>,
+  deletes: Arc<RwLock<HashSet<Id>>>,
+  region: &'r R,
+  branch_factor: usize,
+  generation: u64,
+  cursors: Vec<(usize,Cursor,Extents)>,
+  blocks: Vec<Block>,
+  queue: Vec<(P,V,Location)>
+}
+
+impl<'r,S,P,V,R> RegionIterator<'r,S,P,V,R>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value, R: QueryRegion {
+  pub fn new (trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>, deletes: Arc<RwLock<HashSet<Id>>>,
+  staged: Vec<(P,V,Location)>, region: &'r R, branch_factor: usize,
+  generation: u64) -> Self {
+    let mut bounds: Extents = vec![(std::f64::NEG_INFINITY,std::f64::INFINITY);
+      P::dim()];
+    for (b,r) in bounds.iter_mut().zip(region.bounds().iter()) {
+      *b = *r;
+    }
+    let cursors = (0..trees.len()).map(|t| (t,(0,0),bounds.clone())).collect();
+    Self {
+      trees,
+      data_store,
+      deletes,
+      region,
+      branch_factor,
+      generation,
+      cursors,
+      blocks: vec![],
+      queue: staged
+    }
+  }
+}
+
+impl<'r,S,P,V,R> Iterator for RegionIterator<'r,S,P,V,R>
+where S: RandomAccess<Error=Error>, P: Spatial, V: Value, R: QueryRegion {
+  type Item = Result<(P,V,Location),Error>;
+  fn next (&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(row) = self.queue.pop() {
+        return Some(Ok(row));
+      }
+      if let Some(block) = self.blocks.pop() {
+        let rows = iwrap![iwrap![self.data_store.read_lock()].list(block)];
+        let deletes = iwrap![self.deletes.read_lock()];
+        for (p,v,loc) in rows {
+          if deletes.contains(&loc.2) || !self.region.overlaps(&p.extents()) {
+            continue
+          }
+          self.queue.push((p,v,(loc.0,loc.1,loc.2,self.generation)));
+        }
+        continue
+      }
+      let (t,(offset,depth),bounds) = match self.cursors.pop() {
+        None => return None,
+        Some(c) => c
+      };
+      let buf = {
+        let mut tree = iwrap![self.trees[t].acquire()];
+        let len = iwrap![tree.store.len()];
+        if offset >= len { continue }
+        iwrap![read_block(&mut tree.store, offset, len, 1024)]
+      };
+      let (cursors,blocks) = iwrap![
+        P::nearest_branch(&buf, &bounds, self.branch_factor, depth)
+      ];
+      for (cursor,r) in cursors {
+        if self.region.overlaps_box(&r) {
+          self.cursors.push((t,cursor,r));
+        }
+      }
+      let dstore = iwrap![self.data_store.read_lock()];
+      for (block,r) in blocks {
+        if !self.region.overlaps_box(&r) { continue }
+        // empty blocks have no bounds
+        if let Some((bbox,_)) = iwrap![dstore.bbox(block)] {
+          if self.region.overlaps_box(&P::bounds_extents(&bbox)) {
+            self.blocks.push(block);
+          }
+        }
+      }
+    }
+  }
+}
@@ -1,14 +1,37 @@
-use crate::{DB,Point,Value};
+use crate::{DB,Point,Value,Measure};
 use failure::Error;
 use random_access_storage::RandomAccess;
 
 /// Struct for reading database properties.
+#[derive(Clone)]
 pub struct SetupFields {
   pub max_data_size: usize,
   pub base_size: usize,
   pub branch_factor: usize,
   pub bbox_cache_size: usize,
-  pub data_list_cache_size: usize
+  pub data_list_cache_size: usize,
+  pub versioned: bool,
+  pub history_horizon: u64,
+  pub gc_ratio: f64,
+  pub bulk_run_size: usize,
+  pub tag: Option<String>
+}
+
+impl Default for SetupFields {
+  fn default () -> Self {
+    Self {
+      branch_factor: 5,
+      max_data_size: 3_000,
+      base_size: 9_000,
+      bbox_cache_size: 10_000,
+      data_list_cache_size: 16_000,
+      versioned: false,
+      history_horizon: 1_000,
+      gc_ratio: 0.5,
+      bulk_run_size: 1_000_000,
+      tag: None
+    }
+  }
 }
 
 /// Builder to configure and instantiate an eyros database.
@@ -53,13 +76,7 @@ U: (Fn(&str) -> Result<S,Error>) {
   pub fn new (open_store: U) -> Self {
     Self {
       open_store,
-      fields: SetupFields {
-        branch_factor: 5,
-        max_data_size: 3_000,
-        base_size: 9_000,
-        bbox_cache_size: 10_000,
-        data_list_cache_size: 16_000
-      }
+      fields: SetupFields::default()
     }
   }
   pub fn branch_factor (mut self, bf: usize) -> Self {
@@ -82,8 +99,49 @@ U: (Fn(&str) -> Result<S,Error>) {
     self.fields.data_list_cache_size = size;
     self
   }
+  /// Keep the version history of every record for `db.query_as_of()`.
+  /// Each batch records the batch number that its inserts and deletes
+  /// happened in, and deleted records are kept in the history instead of only
+  /// having their bits cleared in the data blocks.
+  pub fn versioned (mut self, versioned: bool) -> Self {
+    self.fields.versioned = versioned;
+    self
+  }
+  /// Number of batches of history to keep in versioned mode.
+  /// Versions of records deleted earlier than that are discarded.
+  pub fn history_horizon (mut self, horizon: u64) -> Self {
+    self.fields.history_horizon = horizon;
+    self
+  }
+  /// Compact the data store after a batch that merges trees when more than
+  /// this fraction of the data store is no longer referenced by any tree. Use
+  /// a ratio of 1.0 or more to only compact with `db.compact()`.
+  pub fn gc_ratio (mut self, ratio: f64) -> Self {
+    self.fields.gc_ratio = ratio;
+    self
+  }
+  /// Number of records that `db.bulk_load()` sorts in memory at a time
+  /// before writing them to temporary storage.
+  pub fn bulk_run_size (mut self, size: usize) -> Self {
+    self.fields.bulk_run_size = size;
+    self
+  }
+  /// Name for the point and value types of the database, which is stored with
+  /// its settings when the database is created. Opening the database with a
+  /// different tag fails. Without a tag, types are only told apart by their
+  /// number of dimensions and their sizes in bytes.
+  pub fn tag (mut self, tag: &str) -> Self {
+    self.fields.tag = Some(tag.to_string());
+    self
+  }
   pub fn build<P,V> (self) -> Result<DB<S,U,P,V>,Error>
   where P: Point, V: Value {
     DB::open_from_setup(self)
   }
+  /// Build a database that stores a summary of the measured values of each
+  /// data block it writes for `db.aggregate()`.
+  pub fn build_measured<P,V> (self) -> Result<DB<S,U,P,V>,Error>
+  where P: Point, V: Value+Measure {
+    DB::open_measured_from_setup(self)
+  }
 }
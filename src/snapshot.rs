@@ -0,0 +1,48 @@
+use crate::{Point,Value,Id,Tree,QueryIterator,SubIterator};
+use crate::staging::StagingIterator;
+use crate::data::Pinned;
+use failure::Error;
+use random_access_storage::RandomAccess;
+use std::collections::HashSet;
+use std::sync::{Arc,Mutex,RwLock};
+
+/// Read-only view of the database as it was when `db.snapshot()` was called.
+///
+/// Queries on a snapshot return the same results no matter how many batches
+/// are written after the snapshot was taken. Trees that a batch rebuilds while
+/// a snapshot still reads them are copied first, and data blocks that a batch
+/// modifies in place are kept in memory. The copies are reclaimed when the
+/// last snapshot that uses them is dropped.
+pub struct Snapshot<S,P,V> where
+S: RandomAccess<Error=Error>, P: Point, V: Value {
+  pub(crate) trees: Vec<Arc<Mutex<Tree<S,P,V>>>>,
+  pub(crate) inserts: Arc<RwLock<Vec<(P,V,Id)>>>,
+  pub(crate) delete_set: Arc<RwLock<HashSet<Id>>>,
+  pub(crate) pinned: Arc<Mutex<Pinned<P,V>>>,
+  pub(crate) generation: u64
+}
+
+impl<S,P,V> Snapshot<S,P,V> where
+S: RandomAccess<Error=Error>, P: Point, V: Value {
+  /// Query the snapshot for every record that intersects `bbox`. Locations in
+  /// the results are from the generation of the snapshot.
+  pub fn query<'b> (&self, bbox: &'b P::Bounds)
+  -> Result<QueryIterator<'b,S,P,V>,Error> {
+    let mut queries = Vec::with_capacity(1+self.trees.len());
+    queries.push(SubIterator::Staging(StagingIterator::new(
+      Arc::clone(&self.inserts),
+      Arc::clone(&self.delete_set),
+      bbox
+    )));
+    for tree in self.trees.iter() {
+      queries.push(SubIterator::Tree(
+        Tree::query(Arc::clone(tree), bbox)?.pin(Arc::clone(&self.pinned))
+      ));
+    }
+    QueryIterator::new(queries, Arc::clone(&self.delete_set), self.generation)
+  }
+  /// Get the generation of the database when the snapshot was taken.
+  pub fn generation (&self) -> u64 {
+    self.generation
+  }
+}
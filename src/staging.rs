@@ -1,22 +1,30 @@
-use crate::{Point,Value,Location,write_cache::WriteCache};
+use crate::{Point,Predicate,Value,Location,Id};
+use crate::{write_cache::WriteCache,journal::Journal};
 use failure::{Error};
 use random_access_storage::RandomAccess;
 use std::collections::HashSet;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::{Arc,RwLock};
+use crate::lock::ReadWrite;
 use desert::{FromBytes,ToBytes,CountBytes};
 
 pub struct StagingIterator<'b,P,V> where P: Point, V: Value {
-  inserts: Rc<RefCell<Vec<(P,V)>>>,
-  deletes: Rc<RefCell<HashSet<Location>>>,
+  inserts: Arc<RwLock<Vec<(P,V,Id)>>>,
+  deletes: Arc<RwLock<HashSet<Id>>>,
   bbox: &'b P::Bounds,
+  predicate: Predicate,
   index: u32
 }
 
 impl<'b,P,V> StagingIterator<'b,P,V> where P: Point, V: Value {
-  pub fn new (inserts: Rc<RefCell<Vec<(P,V)>>>,
-  deletes: Rc<RefCell<HashSet<Location>>>, bbox: &'b P::Bounds) -> Self {
-    Self { index: 0, bbox, inserts, deletes }
+  pub fn new (inserts: Arc<RwLock<Vec<(P,V,Id)>>>,
+  deletes: Arc<RwLock<HashSet<Id>>>, bbox: &'b P::Bounds) -> Self {
+    Self { index: 0, bbox, predicate: Predicate::Overlaps, inserts, deletes }
+  }
+  /// Return records with the relation `predicate` to the bounding box instead
+  /// of records that overlap it.
+  pub fn predicate (mut self, predicate: Predicate) -> Self {
+    self.predicate = predicate;
+    self
   }
 }
 
@@ -24,16 +32,16 @@ impl<'b,P,V> Iterator for StagingIterator<'b,P,V>
 where P: Point, V: Value {
   type Item = Result<(P,V,Location),Error>;
   fn next (&mut self) -> Option<Self::Item> {
-    let len = iwrap![self.inserts.try_borrow()].len();
+    let len = iwrap![self.inserts.read_lock()].len();
     while (self.index as usize) < len {
       let i = self.index;
       self.index += 1;
-      if iwrap![self.deletes.try_borrow()].contains(&(0,i)) {
+      let (point,value,id) = &iwrap![self.inserts.read_lock()][i as usize];
+      if iwrap![self.deletes.read_lock()].contains(id) {
         continue;
       }
-      let (point,value) = &iwrap![self.inserts.try_borrow()][i as usize];
-      if point.overlaps(self.bbox) {
-        return Some(Ok((*point,value.clone(),(0, i))));
+      if point.matches(self.bbox, self.predicate) {
+        return Some(Ok((*point,value.clone(),(0,i,*id,0))));
       }
     }
     None
@@ -44,9 +52,9 @@ pub struct Staging<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   insert_store: WriteCache<S>,
   delete_store: WriteCache<S>,
-  pub inserts: Rc<RefCell<Vec<(P,V)>>>,
-  pub deletes: Rc<RefCell<Vec<Location>>>,
-  pub delete_set: Rc<RefCell<HashSet<Location>>>
+  pub inserts: Arc<RwLock<Vec<(P,V,Id)>>>,
+  pub deletes: Arc<RwLock<Vec<Location>>>,
+  pub delete_set: Arc<RwLock<HashSet<Id>>>
 }
 
 impl<S,P,V> Staging<S,P,V>
@@ -55,35 +63,35 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     let mut staging = Self {
       insert_store: WriteCache::open(istore)?,
       delete_store: WriteCache::open(dstore)?,
-      inserts: Rc::new(RefCell::new(vec![])),
-      deletes: Rc::new(RefCell::new(vec![])),
-      delete_set: Rc::new(RefCell::new(HashSet::new()))
+      inserts: Arc::new(RwLock::new(vec![])),
+      deletes: Arc::new(RwLock::new(vec![])),
+      delete_set: Arc::new(RwLock::new(HashSet::new()))
     };
     staging.load()?;
     Ok(staging)
   }
   fn load (&mut self) -> Result<(),Error> {
     if !self.insert_store.is_empty()? {
-      self.inserts.try_borrow_mut()?.clear();
+      self.inserts.write_lock()?.clear();
       let len = self.insert_store.len()?;
       let buf = self.insert_store.read(0, len)?;
       let mut offset = 0;
       while offset < len as usize {
-        let (size,pv) = <(P,V)>::from_bytes(&buf[offset..])?;
-        self.inserts.try_borrow_mut()?.push(pv);
+        let (size,row) = <(P,V,Id)>::from_bytes(&buf[offset..])?;
+        self.inserts.write_lock()?.push(row);
         offset += size;
       }
     }
     if !self.delete_store.is_empty()? {
-      self.deletes.try_borrow_mut()?.clear();
-      self.delete_set.try_borrow_mut()?.clear();
+      self.deletes.write_lock()?.clear();
+      self.delete_set.write_lock()?.clear();
       let len = self.delete_store.len()?;
       let buf = self.delete_store.read(0, len)?;
       let mut offset = 0;
       while offset < len as usize {
         let (size,loc) = Location::from_bytes(&buf[offset..])?;
-        self.deletes.try_borrow_mut()?.push(loc);
-        self.delete_set.try_borrow_mut()?.insert(loc);
+        self.deletes.write_lock()?.push(loc);
+        self.delete_set.write_lock()?.insert(loc.2);
         offset += size;
       }
     }
@@ -96,35 +104,67 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
   }
   pub fn clear_inserts (&mut self) -> Result<(),Error> {
     self.insert_store.truncate(0)?;
-    self.inserts.try_borrow_mut()?.clear();
+    self.inserts.write_lock()?.clear();
     Ok(())
   }
   pub fn clear_deletes (&mut self) -> Result<(),Error> {
     self.delete_store.truncate(0)?;
-    self.deletes.try_borrow_mut()?.clear();
-    self.delete_set.try_borrow_mut()?.clear();
+    self.deletes.write_lock()?.clear();
+    self.delete_set.write_lock()?.clear();
     Ok(())
   }
-  pub fn delete (&mut self, deletes: &Vec<Location>) -> Result<(),Error> {
-    let mut del_set: HashSet<u32> = HashSet::new();
-    for delete in deletes {
-      if delete.0 == 0 { del_set.insert(delete.1); }
-    }
-    let mut i = 0;
-    self.inserts.try_borrow_mut()?.retain(|_row| {
-      let j = i;
-      i += 1;
-      !del_set.contains(&j)
+  /// Remove the staged inserts for `deletes` and rewrite the insert store.
+  /// Returns whether any of the remaining inserts moved to a new index.
+  pub fn delete (&mut self, deletes: &Vec<Location>) -> Result<bool,Error> {
+    let del_set: HashSet<Id> = deletes.iter().map(|loc| loc.2).collect();
+    let mut removed = false;
+    let mut moved = false;
+    self.inserts.write_lock()?.retain(|row| {
+      let keep = !del_set.contains(&row.2);
+      moved = moved || (keep && removed);
+      removed = removed || !keep;
+      keep
     });
+    if removed {
+      let inserts = self.inserts.read_lock()?.clone();
+      self.insert_store.truncate(0)?;
+      self.inserts.write_lock()?.clear();
+      self.batch(&inserts, &vec![])?;
+    }
+    Ok(moved)
+  }
+  /// Overwrite staged inserts with `(index,row)` replacements.
+  pub fn replace (&mut self, rows: &Vec<(usize,(P,V,Id))>) -> Result<(),Error> {
+    {
+      let mut inserts = self.inserts.write_lock()?;
+      for (i,row) in rows.iter() {
+        inserts[*i] = row.clone();
+      }
+    }
+    // rows may change size, so rewrite all of the staged inserts
+    let inserts = self.inserts.read_lock()?.clone();
+    self.insert_store.truncate(0)?;
+    self.inserts.write_lock()?.clear();
+    self.batch(&inserts, &vec![])
+  }
+  /// Find the index of the staged insert with the record id `id`.
+  pub fn locate (&self, id: Id) -> Result<Option<u32>,Error> {
+    Ok(self.inserts.read_lock()?.iter().position(|row| row.2 == id)
+      .map(|i| i as u32))
+  }
+  /// Save the staged inserts and deletes in `journal`.
+  pub fn journal (&mut self, journal: &mut Journal<S>) -> Result<(),Error> {
+    journal.keep("staging_inserts", &mut self.insert_store)?;
+    journal.keep("staging_deletes", &mut self.delete_store)?;
     Ok(())
   }
   pub fn bytes (&mut self) -> Result<u64,Error> {
     Ok(self.insert_store.len()? + self.delete_store.len()?)
   }
   pub fn len (&mut self) -> Result<usize,Error> {
-    Ok(self.inserts.try_borrow()?.len() + self.deletes.try_borrow()?.len())
+    Ok(self.inserts.read_lock()?.len() + self.deletes.read_lock()?.len())
   }
-  pub fn batch (&mut self, inserts: &Vec<(P,V)>, deletes: &Vec<Location>)
+  pub fn batch (&mut self, inserts: &Vec<(P,V,Id)>, deletes: &Vec<Location>)
   -> Result<(),Error> {
     // todo: calculate the necessary size before allocating
     let mut i_size = 0;
@@ -155,10 +195,10 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     self.insert_store.write(i_offset,&ibuf)?;
     let d_offset = self.delete_store.len()?;
     self.delete_store.write(d_offset,&dbuf)?;
-    self.inserts.try_borrow_mut()?.extend_from_slice(inserts);
-    self.deletes.try_borrow_mut()?.extend_from_slice(deletes);
+    self.inserts.write_lock()?.extend_from_slice(inserts);
+    self.deletes.write_lock()?.extend_from_slice(deletes);
     for delete in deletes {
-      self.delete_set.try_borrow_mut()?.insert(*delete);
+      self.delete_set.write_lock()?.insert(delete.2);
     }
     Ok(())
   }
@@ -167,11 +207,11 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     self.delete_store.sync_all()?;
     Ok(())
   }
-  pub fn query<'b> (&mut self, bbox: &'b P::Bounds)
+  pub fn query<'b> (&self, bbox: &'b P::Bounds)
   -> StagingIterator<'b,P,V> {
     <StagingIterator<'b,P,V>>::new(
-      Rc::clone(&self.inserts),
-      Rc::clone(&self.delete_set),
+      Arc::clone(&self.inserts),
+      Arc::clone(&self.delete_set),
       bbox
     )
   }
@@ -1,38 +1,55 @@
 use random_access_storage::RandomAccess;
-use failure::{Error,format_err,bail};
-use std::cell::RefCell;
-use std::rc::Rc;
+use failure::{Error,format_err,bail,ensure};
+use std::sync::{Arc,Mutex,RwLock};
 use std::mem::size_of;
+use std::collections::HashMap;
 
-use crate::{Point,Value,Location};
+use crate::{Point,Predicate,Value,Location,Id};
 use crate::branch::{Branch,Node};
-use crate::data::{DataStore,DataMerge,DataBatch};
+use crate::data::{DataStore,DataMerge,DataBatch,Pinned};
 use crate::read_block::read_block;
+use crate::journal::Journal;
+use crate::lock::{Acquire,ReadWrite};
 
 pub struct TreeIterator<'b,S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  tree: Rc<RefCell<Tree<S,P,V>>>,
+  tree: Arc<Mutex<Tree<S,P,V>>>,
   bbox: &'b P::Bounds,
+  predicate: Predicate,
   cursors: Vec<(u64,usize)>,
   blocks: Vec<u64>,
   queue: Vec<(P,V,Location)>,
-  tree_size: u64
+  tree_size: u64,
+  pinned: Option<Arc<Mutex<Pinned<P,V>>>>
 }
 
 impl<'b,S,P,V> TreeIterator<'b,S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
-  pub fn new (tree: Rc<RefCell<Tree<S,P,V>>>, bbox: &'b P::Bounds)
+  pub fn new (tree: Arc<Mutex<Tree<S,P,V>>>, bbox: &'b P::Bounds)
   -> Result<Self,Error> {
-    let tree_size = tree.try_borrow()?.store.len()? as u64;
+    let tree_size = tree.acquire()?.store.len()? as u64;
     Ok(Self {
       tree,
       tree_size,
       bbox,
+      predicate: Predicate::Overlaps,
       cursors: vec![(0,0)],
       blocks: vec![],
-      queue: vec![]
+      queue: vec![],
+      pinned: None
     })
   }
+  /// Return records with the relation `predicate` to the bounding box instead
+  /// of records that overlap it.
+  pub fn predicate (mut self, predicate: Predicate) -> Self {
+    self.predicate = predicate;
+    self
+  }
+  /// Read data blocks from the copies in `pinned` when they are present.
+  pub fn pin (mut self, pinned: Arc<Mutex<Pinned<P,V>>>) -> Self {
+    self.pinned = Some(pinned);
+    self
+  }
 }
 
 #[doc(hidden)]
@@ -50,7 +67,7 @@ impl<'b,S,P,V> Iterator for TreeIterator<'b,S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   type Item = Result<(P,V,Location),Error>;
   fn next (&mut self) -> Option<Self::Item> {
-    let bf = iwrap![self.tree.try_borrow()].branch_factor;
+    let bf = iwrap![self.tree.acquire()].branch_factor;
 
     // todo: used cached size or rolling max to implicitly read an appropriate
     // amount of data
@@ -61,9 +78,19 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
       }
       if !self.blocks.is_empty() { // data block:
         let offset = self.blocks.pop().unwrap();
-        let tree = iwrap![self.tree.try_borrow()];
-        let mut dstore = iwrap![tree.data_store.try_borrow_mut()];
-        self.queue.extend(iwrap![dstore.query(offset, self.bbox)]);
+        if let Some(pinned) = &self.pinned {
+          if let Some(rows) = iwrap![pinned.acquire()].get(&offset) {
+            let (bbox,predicate) = (self.bbox,self.predicate);
+            self.queue.extend(rows.iter()
+              .filter(|row| row.0.matches(bbox, predicate)).cloned());
+            continue
+          }
+        }
+        let tree = iwrap![self.tree.acquire()];
+        let dstore = iwrap![tree.data_store.read_lock()];
+        let (bbox,predicate) = (self.bbox,self.predicate);
+        self.queue.extend(iwrap![dstore.list(offset)].into_iter()
+          .filter(|row| row.0.matches(bbox, predicate)));
         continue
       }
       // branch block:
@@ -71,11 +98,11 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
       if cursor >= self.tree_size { continue }
 
       let buf = {
-        let mut tree = iwrap![self.tree.try_borrow_mut()];
+        let mut tree = iwrap![self.tree.acquire()];
         iwrap![read_block(&mut tree.store, cursor, self.tree_size, 1024)]
       };
       let (cursors,blocks) = iwrap![
-        P::query_branch(&buf, &self.bbox, bf, depth)
+        P::query_branch_with(&buf, &self.bbox, self.predicate, bf, depth)
       ];
       self.blocks.extend(blocks);
       self.cursors.extend(cursors);
@@ -87,7 +114,7 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
 pub struct TreeOpts<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   pub store: S,
-  pub data_store: Rc<RefCell<DataStore<S,P,V>>>,
+  pub data_store: Arc<RwLock<DataStore<S,P,V>>>,
   pub branch_factor: usize,
   pub max_data_size: usize,
   pub index: usize,
@@ -96,20 +123,21 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
 pub struct Tree<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   pub store: S,
-  data_store: Rc<RefCell<DataStore<S,P,V>>>,
-  data_merge: Rc<RefCell<DataMerge<S,P,V>>>,
+  data_store: Arc<RwLock<DataStore<S,P,V>>>,
+  data_merge: Arc<Mutex<DataMerge<S,P,V>>>,
   branch_factor: usize,
   pub bytes: u64,
   pub index: usize,
   max_data_size: usize,
+  detached: bool
 }
 
 impl<S,P,V> Tree<S,P,V>
 where S: RandomAccess<Error=Error>, P: Point, V: Value {
   pub fn open (opts: TreeOpts<S,P,V>) -> Result<Self,Error> {
     let bytes = opts.store.len()? as u64;
-    let data_merge = Rc::new(RefCell::new(
-      DataMerge::new(Rc::clone(&opts.data_store))));
+    let data_merge = Arc::new(Mutex::new(
+      DataMerge::new(Arc::clone(&opts.data_store))));
     Ok(Self {
       store: opts.store,
       data_store: opts.data_store,
@@ -118,8 +146,28 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
       bytes,
       branch_factor: opts.branch_factor,
       max_data_size: opts.max_data_size,
+      detached: false
     })
   }
+  /// Copy the tree into `copy` and keep reading from the copy, returning the
+  /// original store so a new tree can be built in it. The copy is synced and
+  /// is truncated when this tree is dropped.
+  pub fn detach (&mut self, mut copy: S) -> Result<S,Error> {
+    let len = self.store.len()?;
+    copy.truncate(0)?;
+    if len > 0 {
+      copy.write(0, &self.store.read(0, len)?)?;
+    }
+    copy.sync_all()?;
+    self.detached = true;
+    Ok(std::mem::replace(&mut self.store, copy))
+  }
+  /// Set whether the copy made by `detach()` is truncated when this tree is
+  /// dropped. Copies that the journal restores from are kept until the journal
+  /// is committed.
+  pub fn truncate_copy (&mut self, truncate: bool) {
+    self.detached = truncate;
+  }
   pub fn clear (&mut self) -> Result<(),Error> {
     if self.bytes > 0 {
       self.bytes = 0;
@@ -132,11 +180,12 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     let r = self.store.is_empty()?;
     Ok(r)
   }
-  pub fn build (&mut self, rows: &Vec<(P,V)>) -> Result<(),Error> {
-    let dstore = Rc::clone(&self.data_store);
+  pub fn build (&mut self, rows: &Vec<(P,V,Id)>) -> Result<(),Error> {
+    let dmerge = Arc::clone(&self.data_merge);
     self.builder(
-      Rc::new(rows.iter().map(|row| { (row.clone(),1u64) }).collect()),
-      dstore
+      Arc::new(rows.iter().map(|(p,v,id)| { ((*p,(v.clone(),*id)),1u64) })
+        .collect()),
+      dmerge
     )
   }
   pub fn build_from_blocks (&mut self, blocks: Vec<(P::Bounds,u64,u64)>)
@@ -147,11 +196,34 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     let rows = blocks.iter().enumerate().map(|(i,(_,_,len))| {
       (inserts[i],*len)
     }).collect();
-    let dmerge = Rc::clone(&self.data_merge);
-    self.builder(Rc::new(rows), dmerge)
+    let dmerge = Arc::clone(&self.data_merge);
+    self.builder(Arc::new(rows), dmerge)
   }
-  pub fn builder<D,T,U> (&mut self, rows: Rc<Vec<((T,U),u64)>>,
-  data_store: Rc<RefCell<D>>) -> Result<(),Error>
+  /// Build the tree from `rows` in the order they are read, writing every
+  /// `max_data_size` rows to a new data block right away. Only the bounds of
+  /// the data blocks are kept in memory.
+  pub fn build_from_iter<I> (&mut self, rows: I) -> Result<(),Error>
+  where I: Iterator<Item=Result<(P,V,Id),Error>> {
+    let m = self.max_data_size;
+    let mut blocks = vec![];
+    let mut srows = Vec::with_capacity(m+1);
+    for row in rows {
+      srows.push(row?);
+      // hold back a row so the last block is never empty
+      if srows.len() > m {
+        let block: Vec<(P,V,Id)> = srows.drain(0..m).collect();
+        self.pack(&block, &mut blocks)?;
+      }
+    }
+    if blocks.is_empty() {
+      ensure![srows.len() >= 2, "a tree needs at least 2 rows"];
+      return self.build(&srows)
+    }
+    self.pack(&srows, &mut blocks)?;
+    self.build_from_blocks(blocks)
+  }
+  pub fn builder<D,T,U> (&mut self, rows: Arc<Vec<((T,U),u64)>>,
+  data_store: Arc<Mutex<D>>) -> Result<(),Error>
   where D: DataBatch<T,U>, T: Point, U: Value {
     self.clear()?;
     let bucket = (0..rows.len()).collect();
@@ -160,7 +232,7 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
       self.index,
       self.max_data_size,
       self.branch_factor,
-      Rc::clone(&data_store),
+      Arc::clone(&data_store),
       bucket, rows
     )?;
     let mut branches = vec![Node::Branch(b)];
@@ -193,7 +265,7 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     self.store.sync_all()?;
     Ok(())
   }
-  pub fn query<'a,'b> (tree: Rc<RefCell<Self>>, bbox: &'b P::Bounds)
+  pub fn query<'a,'b> (tree: Arc<Mutex<Self>>, bbox: &'b P::Bounds)
   -> Result<TreeIterator<'b,S,P,V>,Error> {
     TreeIterator::new(tree, bbox)
   }
@@ -202,92 +274,198 @@ where S: RandomAccess<Error=Error>, P: Point, V: Value {
     self.bytes += bytes as u64;
     addr
   }
-  pub fn merge (trees: &mut Vec<Rc<RefCell<Self>>>, dst: usize, src: Vec<usize>,
-  rows: &Vec<(P,V)>) -> Result<(),Error> {
+  pub fn merge (trees: &mut Vec<Arc<Mutex<Self>>>, dst: usize, src: Vec<usize>,
+  rows: &Vec<(P,V,Id)>) -> Result<(),Error> {
     let mut blocks = vec![];
     for i in src.iter() {
-      blocks.extend(trees[*i].try_borrow_mut()?.unbuild()?);
+      blocks.extend(trees[*i].acquire()?.unbuild()?);
+    }
+    trees[dst].acquire()?.pack(rows, &mut blocks)?;
+    trees[dst].acquire()?.build_from_blocks(blocks)?;
+    for i in src.iter() {
+      trees[*i].acquire()?.clear()?
     }
-    {
-      let tree = trees[dst].try_borrow()?;
-      let mut dstore = tree.data_store.try_borrow_mut()?;
-      let m = tree.max_data_size;
-      let mut srow_len = 0;
-      for i in 0..(rows.len()+m-1)/m {
-        let srows = &rows[i*m..((i+1)*m).min(rows.len())];
-        srow_len += srows.len();
-        let inserts: Vec<(P,V)> = srows.iter()
-          .map(|(p,v)| (*p,v.clone())).collect();
-        let offset = dstore.batch(&inserts.iter().map(|pv| pv).collect())?;
-        match P::bounds(&inserts.iter().map(|(p,_)| *p).collect()) {
-          None => bail!["invalid data at offset {}", offset],
-          Some(bbox) => blocks.push((bbox,offset,inserts.len() as u64))
+    Ok(())
+  }
+  /// Merge the trees in `src` and `rows` into a single tree at `dst`, which
+  /// may be one of the trees in `src`. Data blocks with at least half of
+  /// `max_data_size` live rows are used as they are and the rows of sparser
+  /// blocks are written into new full blocks along with `rows`.
+  ///
+  /// A tree needs at least two rows, so a single remaining row is returned
+  /// instead of being written to the tree.
+  pub fn optimize (trees: &mut Vec<Arc<Mutex<Self>>>, dst: usize,
+  src: &Vec<usize>, rows: &Vec<(P,V,Id)>) -> Result<Vec<(P,V,Id)>,Error> {
+    let mut blocks = vec![];
+    let mut rows = rows.clone();
+    for i in src.iter() {
+      let mut tree = trees[*i].acquire()?;
+      let m = tree.max_data_size as u64;
+      for (bbox,offset,len) in tree.unbuild()? {
+        if len*2 >= m {
+          blocks.push((bbox,offset,len));
+          continue
         }
+        let dstore = tree.data_store.read_lock()?;
+        rows.extend(dstore.list(offset)?.into_iter()
+          .map(|(p,v,loc)| (p,v,loc.2)));
       }
-      ensure_eq!(srow_len, rows.len(), "divided rows incorrectly");
     }
-    trees[dst].try_borrow_mut()?.build_from_blocks(blocks)?;
     for i in src.iter() {
-      trees[*i].try_borrow_mut()?.clear()?
+      if *i != dst { trees[*i].acquire()?.clear()? }
+    }
+    let mut tree = trees[dst].acquire()?;
+    let m = tree.max_data_size;
+    if blocks.len() + (rows.len()+m-1)/m >= 2 {
+      tree.pack(&rows, &mut blocks)?;
+      tree.build_from_blocks(blocks)?;
+      return Ok(vec![])
+    }
+    // everything fits in one data block
+    for (_,offset,_) in blocks {
+      let dstore = tree.data_store.read_lock()?;
+      rows.extend(dstore.list(offset)?.into_iter()
+        .map(|(p,v,loc)| (p,v,loc.2)));
+    }
+    if rows.len() >= 2 {
+      tree.build(&rows)?;
+      return Ok(vec![])
     }
+    tree.clear()?;
+    Ok(rows)
+  }
+  // write `rows` into full data blocks and add them to `blocks`
+  fn pack (&mut self, rows: &Vec<(P,V,Id)>,
+  blocks: &mut Vec<(P::Bounds,u64,u64)>) -> Result<(),Error> {
+    let mut dstore = self.data_store.write_lock()?;
+    let m = self.max_data_size;
+    let mut srow_len = 0;
+    for i in 0..(rows.len()+m-1)/m {
+      let srows = &rows[i*m..((i+1)*m).min(rows.len())];
+      srow_len += srows.len();
+      let inserts: Vec<(P,(V,Id))> = srows.iter()
+        .map(|(p,v,id)| (*p,(v.clone(),*id))).collect();
+      let offset = dstore.batch(&inserts.iter().map(|pv| pv).collect())?;
+      match P::bounds(&inserts.iter().map(|(p,_)| *p).collect()) {
+        None => bail!["invalid data at offset {}", offset],
+        Some(bbox) => blocks.push((bbox,offset,inserts.len() as u64))
+      }
+    }
+    ensure_eq!(srow_len, rows.len(), "divided rows incorrectly");
     Ok(())
   }
-  fn unbuild (&mut self) -> Result<Vec<(P::Bounds,u64,u64)>,Error> {
+  pub fn unbuild (&mut self) -> Result<Vec<(P::Bounds,u64,u64)>,Error> {
+    let offsets = self.data_blocks()?;
+    let mut blocks = Vec::with_capacity(offsets.len());
+    let dstore = self.data_store.read_lock()?;
+    for offset in offsets {
+      match dstore.bbox(offset)? {
+        Some((bbox,len)) => blocks.push((bbox,offset,len)),
+        None => {},
+      }
+    }
+    Ok(blocks)
+  }
+  /// Return the offsets of the data blocks that a query for `bbox` reads.
+  pub fn query_blocks (&mut self, bbox: &P::Bounds) -> Result<Vec<u64>,Error> {
     let mut offsets: Vec<u64> = vec![];
+    if self.store.is_empty()? { return Ok(offsets) }
     let mut cursors: Vec<(u64,usize)> = vec![(0,0)];
     let bf = self.branch_factor;
-    let n = bf*2-3;
     let tree_size = self.store.len()? as u64;
     while !cursors.is_empty() {
       let (c,depth) = cursors.pop().unwrap();
+      if c >= tree_size { continue }
       let buf = read_block(&mut self.store, c, tree_size, 1024)?;
-      let mut offset = 0;
-      for _i in 0..n {
-        offset += P::count_bytes_at(&buf[offset..], depth)?;
-      }
-      let d_start = offset;
-      let i_start = d_start + (n+bf+7)/8;
-      let b_start = i_start + n*size_of::<u64>();
-      let b_end = b_start+bf*size_of::<u64>();
-      ensure_eq!(b_end, buf.len(), "unexpected block length");
-      for i in 0..n {
-        let offset = u64::from_be_bytes([
-          buf[i_start+i*8+0], buf[i_start+i*8+1],
-          buf[i_start+i*8+2], buf[i_start+i*8+3],
-          buf[i_start+i*8+4], buf[i_start+i*8+5],
-          buf[i_start+i*8+6], buf[i_start+i*8+7]
-        ]);
-        let is_data = ((buf[d_start+i/8]>>(i%8))&1) == 1;
-        if offset > 0 && is_data {
-          offsets.push(offset-1);
-        } else if offset > 0 {
-          cursors.push((offset-1,depth+1));
-        }
-      }
-      for i in 0..bf {
-        let offset = u64::from_be_bytes([
-          buf[b_start+i*8+0], buf[b_start+i*8+1],
-          buf[b_start+i*8+2], buf[b_start+i*8+3],
-          buf[b_start+i*8+4], buf[b_start+i*8+5],
-          buf[b_start+i*8+6], buf[b_start+i*8+7]
-        ]);
-        let j = i + n;
-        let is_data = ((buf[d_start+(j/8)]>>(j%8))&1) == 1;
-        if offset > 0 && is_data {
-          offsets.push(offset-1);
-        } else if offset > 0 {
-          cursors.push((offset-1,depth+1));
-        }
-      }
+      let (c_cursors,c_blocks) = P::query_branch(&buf, bbox, bf,
+        depth)?;
+      offsets.extend(c_blocks);
+      cursors.extend(c_cursors);
     }
-    let mut blocks = Vec::with_capacity(offsets.len());
-    let mut dstore = self.data_store.try_borrow_mut()?;
-    for offset in offsets {
-      match dstore.bbox(offset)? {
-        Some((bbox,len)) => blocks.push((bbox,offset,len)),
-        None => {},
+    Ok(offsets)
+  }
+  /// Return the offsets of every data block referenced by this tree.
+  pub fn data_blocks (&mut self) -> Result<Vec<u64>,Error> {
+    Ok(self.data_pointers()?.into_iter().map(|(_,block)| block).collect())
+  }
+  /// Save the data block pointers that `remap_blocks()` will overwrite to
+  /// `journal` under the store name `name`.
+  pub fn journal_remap (&mut self, journal: &mut Journal<S>, name: &str)
+  -> Result<(),Error> {
+    for (position,_) in self.data_pointers()? {
+      journal.keep_range(name, &mut self.store, position, 8)?;
+    }
+    Ok(())
+  }
+  /// Point the references to each data block in `moved` at its new offset, or
+  /// remove the references to blocks that map to `None`.
+  pub fn remap_blocks (&mut self, moved: &HashMap<u64,Option<u64>>)
+  -> Result<(),Error> {
+    for (position,block) in self.data_pointers()? {
+      let offset = match moved.get(&block) {
+        None => continue,
+        Some(None) => 0,
+        Some(Some(offset)) => offset+1
+      };
+      self.store.write(position, &offset.to_be_bytes())?;
+    }
+    self.store.sync_all()?;
+    Ok(())
+  }
+  fn data_pointers (&mut self) -> Result<Vec<(u64,u64)>,Error> {
+    data_pointers::<S,P>(&mut self.store, self.branch_factor)
+  }
+}
+
+impl<S,P,V> Drop for Tree<S,P,V>
+where S: RandomAccess<Error=Error>, P: Point, V: Value {
+  fn drop (&mut self) {
+    if self.detached {
+      // nothing reads the copy anymore, so reclaim its storage
+      self.store.truncate(0).ok();
+    }
+  }
+}
+
+// return the byte position in the tree store of every data block pointer
+// along with the offset of the data block it points at. the branch layout of
+// trees has not changed since format 0, so this also reads older databases
+pub fn data_pointers<S,P> (store: &mut S, branch_factor: usize)
+-> Result<Vec<(u64,u64)>,Error>
+where S: RandomAccess<Error=Error>, P: Point {
+  let mut pointers: Vec<(u64,u64)> = vec![];
+  if store.is_empty()? { return Ok(pointers) }
+  let mut cursors: Vec<(u64,usize)> = vec![(0,0)];
+  let bf = branch_factor;
+  let n = bf*2-3;
+  let tree_size = store.len()? as u64;
+  while !cursors.is_empty() {
+    let (c,depth) = cursors.pop().unwrap();
+    let buf = read_block(store, c, tree_size, 1024)?;
+    let mut offset = 0;
+    for _i in 0..n {
+      offset += P::count_bytes_at(&buf[offset..], depth)?;
+    }
+    let d_start = offset;
+    let i_start = d_start + (n+bf+7)/8;
+    let b_start = i_start + n*size_of::<u64>();
+    let b_end = b_start+bf*size_of::<u64>();
+    ensure_eq!(b_end, buf.len(), "unexpected block length");
+    // intersecting pointers followed by bucket pointers
+    for j in 0..n+bf {
+      let k = i_start + j*8;
+      let offset = u64::from_be_bytes([
+        buf[k+0], buf[k+1], buf[k+2], buf[k+3],
+        buf[k+4], buf[k+5], buf[k+6], buf[k+7]
+      ]);
+      let is_data = ((buf[d_start+(j/8)]>>(j%8))&1) == 1;
+      if offset > 0 && is_data {
+        // blocks read by read_block() start after the 4-byte length
+        pointers.push((c+4+(k as u64),offset-1));
+      } else if offset > 0 {
+        cursors.push((offset-1,depth+1));
       }
     }
-    Ok(blocks)
   }
+  Ok(pointers)
 }
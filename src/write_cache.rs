@@ -143,7 +143,7 @@ impl<S> RandomAccess for WriteCache<S> where S: RandomAccess {
       self.store.write(q.0, &q.1)?;
     }
     self.queue.clear();
-    Ok(())
+    self.store.sync_all()
   }
 }
 
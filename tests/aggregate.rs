@@ -0,0 +1,143 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Aggregate};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+#[test]
+fn aggregate() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let open = |measured: bool| -> Result<DB<_,_,P,V>,Error> {
+    let setup = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+      let p = dir.path().join(name);
+      Ok(RandomAccessDisk::builder(p)
+        .auto_sync(false)
+        .build()?)
+    })
+      .branch_factor(5)
+      .max_data_size(20)
+      .base_size(100);
+    match measured {
+      true => setup.build_measured(),
+      false => setup.build()
+    }
+  };
+  let mut db = open(true)?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as eyros::Point>::Bounds> = (0..40).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.4-1.2;
+    let y0: f32 = r.read::<f32>()*2.4-1.2;
+    let x1: f32 = x0 + r.read::<f32>()*(1.2-x0);
+    let y1: f32 = y0 + r.read::<f32>()*(1.2-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+  let full = ((-1.0,-1.0),(1.0,1.0));
+
+  assert_eq!(db.aggregate(&full, Aggregate::Count)?, Some(0.0), "empty count");
+  assert_eq!(db.aggregate(&full, Aggregate::Min)?, None, "empty min");
+  db.batch(&inserts[0..50])?; // staging only
+  check(&db, &bboxes)?;
+  db.batch(&inserts[50..250])?;
+  check(&db, &bboxes)?;
+  db.batch(&inserts[250..650])?;
+  check(&db, &bboxes)?;
+  check(&db, &bboxes)?; // summaries from the first pass
+  assert_eq!(db.aggregate(&full, Aggregate::Sum)?,
+    Some((0..650).sum::<u32>() as f64), "sum of every record");
+
+  // staged deletes
+  let deletes: Vec<Row<P,V>> = db.query(&bboxes[0])?
+    .filter_map(|result| result.ok())
+    .take(10)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  check(&db, &bboxes)?;
+
+  // deletes cleared from data blocks
+  db.batch(&vec![Row::DeleteBounds(bboxes[1])])?;
+  check(&db, &bboxes)?;
+  assert_eq!(db.aggregate(&bboxes[1], Aggregate::Max)?, None,
+    "no records left in deleted bbox");
+
+  // summaries are stored with the blocks
+  drop(db);
+  let db = open(true)?;
+  check(&db, &bboxes)?;
+  drop(db);
+
+  // blocks written without measuring are scanned and aggregates don't write
+  let mut db = open(false)?;
+  let rows: Vec<Row<P,V>> = db.query(&bboxes[2])?
+    .filter_map(|result| result.ok())
+    .map(|(p,v,loc)| Row::Replace(loc,p,v+1_000))
+    .collect();
+  db.batch(&rows)?;
+  db.batch(&inserts[0..200])?;
+  check(&db, &bboxes)?;
+  db.batch(&vec![Row::DeleteBounds(bboxes[3])])?;
+  let range = range_bytes(dir.path())?;
+  check(&db, &bboxes)?;
+  assert_eq!(range_bytes(dir.path())?, range, "range files");
+  drop(db);
+
+  // compacting stores the summaries of every block again
+  let mut db = open(true)?;
+  db.compact()?;
+  check(&db, &bboxes)?;
+  Ok(())
+}
+
+// contents of the range file in use and of the file that compacting
+// alternates with
+fn range_bytes (path: &Path) -> Result<Vec<u8>,Error> {
+  let mut bytes = vec![];
+  for name in ["range","range1"].iter() {
+    if let Ok(buf) = std::fs::read(path.join(name)) { bytes.extend(buf) }
+  }
+  Ok(bytes)
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, bboxes: &Vec<<P as eyros::Point>::Bounds>)
+-> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  for bbox in bboxes.iter() {
+    let mut values = vec![];
+    for result in db.query(bbox)? {
+      values.push(result?.1 as f64);
+    }
+    let min = values.iter().cloned().fold(None, |m: Option<f64>,x| {
+      Some(m.map_or(x, |m| m.min(x)))
+    });
+    let max = values.iter().cloned().fold(None, |m: Option<f64>,x| {
+      Some(m.map_or(x, |m| m.max(x)))
+    });
+    let sum: f64 = values.iter().sum();
+    assert_eq!(db.aggregate(bbox, Aggregate::Count)?,
+      Some(values.len() as f64), "count for {:?}", bbox);
+    assert_eq!(db.aggregate(bbox, Aggregate::Sum)?, Some(sum),
+      "sum for {:?}", bbox);
+    assert_eq!(db.aggregate(bbox, Aggregate::Min)?, min, "min for {:?}", bbox);
+    assert_eq!(db.aggregate(bbox, Aggregate::Max)?, max, "max for {:?}", bbox);
+  }
+  Ok(())
+}
@@ -0,0 +1,299 @@
+extern crate eyros;
+extern crate failure;
+extern crate futures;
+extern crate random;
+
+use eyros::{AsyncDB,AsyncRandomAccess,QueryStream,SetupFields,Row,Location};
+use failure::{Error,bail};
+use futures::executor::block_on;
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use random::{Source,default as rand};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use std::task::{Context,Poll};
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+type B = ((f32,f32,f32),(f32,f32,f32));
+
+// in-memory async store whose files outlive the database that opened them.
+// every operation yields to the executor once before it completes, and
+// writes fail once the shared budget of writes runs out
+struct MemStore {
+  bytes: Arc<Mutex<Vec<u8>>>,
+  budget: Arc<Mutex<Option<usize>>>
+}
+
+impl AsyncRandomAccess for MemStore {
+  fn write<'a> (&'a mut self, offset: u64, data: &'a [u8])
+  -> LocalBoxFuture<'a,Result<(),Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      if let Some(budget) = self.budget.lock().unwrap().as_mut() {
+        if *budget == 0 { bail!["out of writes"] }
+        *budget -= 1;
+      }
+      let mut bytes = self.bytes.lock().unwrap();
+      let end = offset as usize + data.len();
+      if bytes.len() < end { bytes.resize(end, 0) }
+      bytes[offset as usize..end].copy_from_slice(data);
+      Ok(())
+    })
+  }
+  fn read (&mut self, offset: u64, length: u64)
+  -> LocalBoxFuture<'_,Result<Vec<u8>,Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      let bytes = self.bytes.lock().unwrap();
+      let end = (offset + length) as usize;
+      if end > bytes.len() { bail!["read out of bounds"] }
+      Ok(bytes[offset as usize..end].to_vec())
+    })
+  }
+  fn del (&mut self, offset: u64, length: u64)
+  -> LocalBoxFuture<'_,Result<(),Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      let mut bytes = self.bytes.lock().unwrap();
+      let end = ((offset + length) as usize).min(bytes.len());
+      for b in bytes[(offset as usize).min(end)..end].iter_mut() { *b = 0 }
+      Ok(())
+    })
+  }
+  fn truncate (&mut self, length: u64) -> LocalBoxFuture<'_,Result<(),Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      self.bytes.lock().unwrap().resize(length as usize, 0);
+      Ok(())
+    })
+  }
+  fn len (&self) -> LocalBoxFuture<'_,Result<u64,Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      Ok(self.bytes.lock().unwrap().len() as u64)
+    })
+  }
+  fn is_empty (&mut self) -> LocalBoxFuture<'_,Result<bool,Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      Ok(self.bytes.lock().unwrap().is_empty())
+    })
+  }
+  fn sync_all (&mut self) -> LocalBoxFuture<'_,Result<(),Error>> {
+    Box::pin(async move {
+      Yield(false).await;
+      Ok(())
+    })
+  }
+}
+
+// future that is pending the first time it is polled
+struct Yield(bool);
+
+impl Future for Yield {
+  type Output = ();
+  fn poll (mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+    if self.0 { return Poll::Ready(()) }
+    self.0 = true;
+    cx.waker().wake_by_ref();
+    Poll::Pending
+  }
+}
+
+#[derive(Clone,Default)]
+struct Files {
+  bytes: Arc<Mutex<HashMap<String,Arc<Mutex<Vec<u8>>>>>>,
+  budget: Arc<Mutex<Option<usize>>>
+}
+
+async fn open (files: &Files) -> Result<AsyncDB<P,V>,Error> {
+  let files = files.clone();
+  AsyncDB::open_from_fields(move |name: &str| -> Result<MemStore,Error> {
+    let mut bytes = files.bytes.lock().unwrap();
+    let bytes = bytes.entry(name.to_string()).or_insert_with(Default::default);
+    Ok(MemStore {
+      bytes: Arc::clone(bytes),
+      budget: Arc::clone(&files.budget)
+    })
+  }, SetupFields {
+    branch_factor: 5,
+    max_data_size: 20,
+    base_size: 100,
+    ..SetupFields::default()
+  }).await
+}
+
+#[test]
+fn async_db() -> Result<(),Error> {
+  block_on(async {
+    let files = Files::default();
+    let mut db = open(&files).await?;
+    let mut r = rand().seed([13,12]);
+    let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+      let xmin: f32 = r.read::<f32>()*2.0-1.0;
+      let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+      let ymin: f32 = r.read::<f32>()*2.0-1.0;
+      let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+      let time: f32 = r.read::<f32>()*1000.0;
+      Row::Insert(((xmin,xmax),(ymin,ymax),time), i)
+    }).collect();
+    db.batch(&inserts[0..250]).await?;
+    db.batch(&inserts[250..650]).await?;
+
+    let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+    let bbox = ((-0.5,-0.8,0.0),(0.3,0.5,600.0));
+    let all = records(&db, &full).await?;
+    assert_eq!(all.len(), 650, "all records");
+    let expected: HashMap<V,(P,Location)> = all.iter()
+      .filter(|(_,(p,_))| overlaps(p, &bbox))
+      .map(|(v,x)| (*v,*x)).collect();
+    assert!(!expected.is_empty(), "query has results");
+    assert_eq!(records(&db, &bbox).await?, expected, "query results");
+
+    let deletes: Vec<Row<P,V>> = expected.values()
+      .map(|(_,loc)| Row::Delete(*loc)).collect();
+    db.batch(&deletes).await?;
+    let remaining = records(&db, &full).await?;
+    assert_eq!(remaining.len(), 650 - expected.len(), "records after deletes");
+    assert!(records(&db, &bbox).await?.is_empty(), "no records in bbox");
+    let (v,(_,loc)) = remaining.iter().next().unwrap();
+    assert_eq!(db.locate(loc.2).await?, Some(*loc), "located record {}", v);
+    drop(db);
+
+    let db = open(&files).await?;
+    let reopened = records(&db, &full).await?;
+    assert_eq!(reopened.len(), remaining.len(), "records after reopening");
+    for (v,(p,_)) in reopened.iter() {
+      assert_eq!(remaining[v].0, *p, "record {} after reopening", v);
+    }
+    Ok(())
+  })
+}
+
+#[test]
+fn interleaved_query() -> Result<(),Error> {
+  block_on(async {
+    let files = Files::default();
+    let mut db = open(&files).await?;
+    let mut r = rand().seed([5,3]);
+    let inserts: Vec<(P,V)> = (0..650).map(|i| {
+      let xmin: f32 = r.read::<f32>()*2.0-1.0;
+      let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+      let ymin: f32 = r.read::<f32>()*2.0-1.0;
+      let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+      let time: f32 = r.read::<f32>()*1000.0;
+      (((xmin,xmax),(ymin,ymax),time), i)
+    }).collect();
+    let rows: Vec<Row<P,V>> = inserts.iter()
+      .map(|(p,v)| Row::Insert(*p,*v)).collect();
+    db.batch(&rows[0..400]).await?;
+
+    // a stream that is open during a batch still yields every record the
+    // batch doesn't write or delete
+    let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+    let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,100.0));
+    let mut stream = db.query(&bbox)?;
+    let (_,first,_) = stream.next().await.unwrap()?;
+    db.batch(&rows[400..650]).await?;
+    let mut found = values(stream).await?;
+    found.push(first);
+    let in_bbox: Vec<V> = inserts.iter()
+      .filter(|(p,_)| overlaps(p, &bbox))
+      .map(|(_,v)| *v).collect();
+    let before: Vec<V> = in_bbox.iter().cloned().filter(|v| *v < 400).collect();
+    assert!(!before.is_empty(), "query has results");
+    for v in before.iter() {
+      assert!(found.contains(v), "record {} from before the batch", v);
+    }
+    for v in found.iter() {
+      assert!(in_bbox.contains(v), "record {} in the query", v);
+    }
+
+    let deletes: Vec<Row<P,V>> = records(&db, &bbox).await?.values()
+      .map(|(_,loc)| Row::Delete(*loc)).collect();
+    let stream = db.query(&full)?;
+    db.batch(&deletes).await?;
+    let found = values(stream).await?;
+    let remaining = records(&db, &full).await?;
+    assert_eq!(remaining.len(), 650 - deletes.len(), "records after deletes");
+    for v in remaining.keys() {
+      assert!(found.contains(v), "record {} the batch didn't delete", v);
+    }
+    Ok(())
+  })
+}
+
+#[test]
+fn interrupted_batch() -> Result<(),Error> {
+  block_on(async {
+    let mut r = rand().seed([7,4]);
+    let inserts: Vec<Row<P,V>> = (0..400).map(|i| {
+      let xmin: f32 = r.read::<f32>()*2.0-1.0;
+      let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+      let ymin: f32 = r.read::<f32>()*2.0-1.0;
+      let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+      let time: f32 = r.read::<f32>()*1000.0;
+      Row::Insert(((xmin,xmax),(ymin,ymax),time), i)
+    }).collect();
+    let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+    // storage fails after a growing number of writes until the batch that
+    // merges the trees is written completely
+    let mut budget = 0;
+    loop {
+      let files = Files::default();
+      let mut db = open(&files).await?;
+      db.batch(&inserts[0..150]).await?;
+      let before = records(&db, &full).await?;
+      *files.budget.lock().unwrap() = Some(budget);
+      let result = db.batch(&inserts[150..400]).await;
+      drop(db);
+      *files.budget.lock().unwrap() = None;
+      let db = open(&files).await?;
+      let after = records(&db, &full).await?;
+      if result.is_ok() {
+        assert_eq!(after.len(), 400, "records after the batch");
+        break;
+      }
+      assert_eq!(after, before, "records rolled back after {} writes", budget);
+      budget += 5;
+    }
+    assert!(budget > 0, "batch was interrupted");
+    Ok(())
+  })
+}
+
+async fn values (mut stream: QueryStream<'_,P,V>) -> Result<Vec<V>,Error> {
+  let mut values = vec![];
+  while let Some(result) = stream.next().await {
+    values.push(result?.1);
+  }
+  values.sort();
+  Ok(values)
+}
+
+async fn records (db: &AsyncDB<P,V>, bbox: &B)
+-> Result<HashMap<V,(P,Location)>,Error> {
+  let mut results = HashMap::new();
+  let mut stream = db.query(bbox)?;
+  while let Some(result) = stream.next().await {
+    let (p,v,loc) = result?;
+    assert!(results.insert(v,(p,loc)).is_none(), "no duplicate results");
+  }
+  Ok(results)
+}
+
+fn overlaps (p: &P, bbox: &B) -> bool {
+  contains_iv((bbox.0).0, (bbox.1).0, p.0)
+  && contains_iv((bbox.0).1, (bbox.1).1, p.1)
+  && contains_pt((bbox.0).2, (bbox.1).2, p.2)
+}
+fn contains_iv<T> (min: T, max: T, iv: (T,T)) -> bool where T: PartialOrd {
+  min <= iv.1 && iv.0 <= max
+}
+fn contains_pt<T> (min: T, max: T, pt: T) -> bool where T: PartialOrd {
+  min <= pt && pt <= max
+}
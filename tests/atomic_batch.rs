@@ -0,0 +1,307 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::{Error,bail};
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+
+// Storage that simulates the process being killed: once the shared write
+// budget runs out, the write in progress is only partly applied and every
+// write after that fails.
+struct FaultStore {
+  store: RandomAccessDisk,
+  budget: Rc<Cell<Option<usize>>>,
+  writes: Rc<Cell<usize>>
+}
+
+impl FaultStore {
+  fn check (&mut self) -> Result<bool,Error> {
+    self.writes.set(self.writes.get()+1);
+    match self.budget.get() {
+      None => Ok(true),
+      Some(0) => bail!["storage is dead"],
+      Some(n) => {
+        self.budget.set(Some(n-1));
+        Ok(n > 1)
+      }
+    }
+  }
+}
+
+impl RandomAccess for FaultStore {
+  type Error = Error;
+  fn write (&mut self, offset: u64, data: &[u8]) -> Result<(),Error> {
+    if !self.check()? {
+      self.store.write(offset, &data[0..data.len()/2])?;
+      bail!["storage died during write"]
+    }
+    self.store.write(offset, data)
+  }
+  fn read (&mut self, offset: u64, length: u64) -> Result<Vec<u8>,Error> {
+    self.store.read(offset, length)
+  }
+  fn read_to_writer (&mut self, offset: u64, length: u64,
+  buf: &mut impl Write) -> Result<(),Error> {
+    self.store.read_to_writer(offset, length, buf)
+  }
+  fn del (&mut self, offset: u64, length: u64) -> Result<(),Error> {
+    if !self.check()? { bail!["storage died during del"] }
+    self.store.del(offset, length)
+  }
+  fn truncate (&mut self, length: u64) -> Result<(),Error> {
+    if !self.check()? { bail!["storage died during truncate"] }
+    self.store.truncate(length)
+  }
+  fn len (&self) -> Result<u64,Error> {
+    self.store.len()
+  }
+  fn is_empty (&mut self) -> Result<bool,Error> {
+    self.store.is_empty()
+  }
+  fn sync_all (&mut self) -> Result<(),Error> {
+    if self.budget.get() == Some(0) { bail!["storage is dead"] }
+    self.store.sync_all()
+  }
+}
+
+#[test]
+fn atomic_batch() -> Result<(),Error> {
+  let mut r = rand().seed([13,12]);
+  let first: Vec<Row<P,V>> = (0..250).map(|_| random_insert(&mut r)).collect();
+  let second: Vec<Row<P,V>> = (0..180).map(|_| random_insert(&mut r)).collect();
+  let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+
+  // reference run without any faults to count the writes in the second batch
+  let (before, after, total_writes) = {
+    let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+    let budget = Rc::new(Cell::new(None));
+    let writes = Rc::new(Cell::new(0));
+    let mut db = open(dir.path(), &budget, &writes)?;
+    db.batch(&first)?;
+    let before = query(&mut db, &bbox)?;
+    let mut rows = second.clone();
+    rows.extend(deletes(&mut db, &bbox)?);
+    writes.set(0);
+    db.batch(&rows)?;
+    let total_writes = writes.get();
+    let after = query(&mut db, &bbox)?;
+    (before, after, total_writes)
+  };
+  assert_ne!(before, after, "second batch changes the results");
+  assert!(total_writes > 0, "second batch writes to storage");
+
+  for k in 0..total_writes {
+    let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+    let budget = Rc::new(Cell::new(None));
+    let writes = Rc::new(Cell::new(0));
+    {
+      let mut db = open(dir.path(), &budget, &writes)?;
+      db.batch(&first)?;
+      let mut rows = second.clone();
+      rows.extend(deletes(&mut db, &bbox)?);
+      budget.set(Some(k));
+      assert!(db.batch(&rows).is_err(), "batch fails after {} writes", k);
+    }
+    budget.set(None);
+    let mut db = open(dir.path(), &budget, &writes)?;
+    let results = query(&mut db, &bbox)?;
+    assert!(results == before || results == after,
+      "database is consistent after a failure at write {} of {}",
+      k, total_writes);
+    // the version history is rolled back along with the batch
+    let batch = if results == before { 1 } else { 2 };
+    assert_eq!(db.batch_number(), Some(batch),
+      "batch number after a failure at write {}", k);
+    let mut versions = vec![];
+    for result in db.query_as_of(&bbox, 1)? {
+      let (p,v,_) = result?;
+      versions.push((p,v));
+    }
+    versions.sort_unstable_by(cmp);
+    assert_eq!(versions, before, "first batch after a failure at write {}", k);
+    // the database is still writable after recovering
+    db.batch(&vec![random_insert(&mut r)])?;
+  }
+  Ok(())
+}
+
+#[test]
+fn failed_batch_with_snapshot() -> Result<(),Error> {
+  let mut r = rand().seed([13,12]);
+  let first: Vec<Row<P,V>> = (0..250).map(|_| random_insert(&mut r)).collect();
+  let second: Vec<Row<P,V>> = (0..180).map(|_| random_insert(&mut r)).collect();
+  let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let points = |rows: &[Row<P,V>]| -> Vec<(P,V)> {
+    let mut points: Vec<(P,V)> = rows.iter().map(|row| match row {
+      Row::Insert(p,v) => (*p,*v),
+      _ => panic!["unexpected row type"]
+    }).collect();
+    points.sort_unstable_by(cmp);
+    points
+  };
+  let before = points(&first);
+  let after = points(&first.iter().chain(second.iter()).cloned()
+    .collect::<Vec<_>>());
+
+  for k in (0..).step_by(40) {
+    let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+    let budget = Rc::new(Cell::new(None));
+    let writes = Rc::new(Cell::new(0));
+    let failed = {
+      let mut db = open(dir.path(), &budget, &writes)?;
+      db.batch(&first)?;
+      let snapshot = db.snapshot()?;
+      budget.set(Some(k));
+      let failed = db.batch(&second).is_err();
+      // storage works again before the database and the snapshot are dropped,
+      // so the tree copies that the journal restores from must be kept
+      budget.set(None);
+      let mut results = vec![];
+      for result in snapshot.query(&bbox)? {
+        let (p,v,_) = result?;
+        results.push((p,v));
+      }
+      results.sort_unstable_by(cmp);
+      assert_eq!(results, before, "snapshot after a failure at write {}", k);
+      failed
+    };
+    let mut db = open(dir.path(), &budget, &writes)?;
+    let results = query(&mut db, &bbox)?;
+    if !failed {
+      assert_eq!(results, after, "batch without a failure");
+      break
+    }
+    assert!(results == before || results == after,
+      "database is consistent after a failure at write {}", k);
+  }
+  Ok(())
+}
+
+#[test]
+fn atomic_compact() -> Result<(),Error> {
+  let mut r = rand().seed([13,12]);
+  let first: Vec<Row<P,V>> = (0..250).map(|_| random_insert(&mut r)).collect();
+  let second: Vec<Row<P,V>> = (0..180).map(|_| random_insert(&mut r)).collect();
+  let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let (expected, total_writes) = {
+    let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+    let budget = Rc::new(Cell::new(None));
+    let writes = Rc::new(Cell::new(0));
+    let mut db = open(dir.path(), &budget, &writes)?;
+    db.batch(&first)?;
+    // merging trees leaves garbage in the data store for compact() to reclaim
+    db.batch(&second)?;
+    assert!(db.garbage()? > 0, "garbage to compact");
+    let expected = query(&mut db, &bbox)?;
+    writes.set(0);
+    db.compact()?;
+    assert_eq!(query(&mut db, &bbox)?, expected, "records after compacting");
+    (expected, writes.get())
+  };
+  assert!(total_writes > 0, "compacting writes to storage");
+
+  for k in 0..total_writes {
+    let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+    let budget = Rc::new(Cell::new(None));
+    let writes = Rc::new(Cell::new(0));
+    {
+      let mut db = open(dir.path(), &budget, &writes)?;
+      db.batch(&first)?;
+      db.batch(&second)?;
+      budget.set(Some(k));
+      assert!(db.compact().is_err(), "compact fails after {} writes", k);
+    }
+    budget.set(None);
+    let mut db = open(dir.path(), &budget, &writes)?;
+    assert_eq!(query(&mut db, &bbox)?, expected,
+      "records after a failure at write {} of {}", k, total_writes);
+    for result in db.query(&bbox)? {
+      let loc = result?.2;
+      assert_eq!(db.locate(loc.2)?, Some(loc),
+        "location after a failure at write {}", k);
+    }
+    db.compact()?;
+    assert_eq!(query(&mut db, &bbox)?, expected,
+      "records after compacting again from a failure at write {}", k);
+  }
+  Ok(())
+}
+
+fn open (path: &Path, budget: &Rc<Cell<Option<usize>>>,
+writes: &Rc<Cell<usize>>) -> Result<DB<FaultStore,
+impl Fn(&str) -> Result<FaultStore,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  let budget = Rc::clone(budget);
+  let writes = Rc::clone(writes);
+  Setup::new(move |name: &str| -> Result<FaultStore,Error> {
+    Ok(FaultStore {
+      store: RandomAccessDisk::builder(path.join(name))
+        .auto_sync(false)
+        .build()?,
+      budget: Rc::clone(&budget),
+      writes: Rc::clone(&writes)
+    })
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(50)
+    .versioned(true)
+    .history_horizon(1)
+    .build()
+}
+
+fn query<S,U> (db: &mut DB<S,U,P,V>, bbox: &((f32,f32,f32),(f32,f32,f32)))
+-> Result<Vec<(P,V)>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = vec![];
+  for result in db.query(bbox)? {
+    let (p,v,_) = result?;
+    results.push((p,v));
+  }
+  results.sort_unstable_by(cmp);
+  Ok(results)
+}
+
+fn deletes<S,U> (db: &mut DB<S,U,P,V>, bbox: &((f32,f32,f32),(f32,f32,f32)))
+-> Result<Vec<Row<P,V>>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut rows = vec![];
+  for (i,result) in db.query(bbox)?.enumerate() {
+    let (_,_,loc) = result?;
+    if i % 3 == 0 && loc.0 > 0 { rows.push(Row::Delete(loc)) }
+  }
+  Ok(rows)
+}
+
+fn random_insert<T> (r: &mut T) -> Row<P,V> where T: Source {
+  let xmin: f32 = r.read::<f32>()*2.0-1.0;
+  let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+  let ymin: f32 = r.read::<f32>()*2.0-1.0;
+  let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+  let time: f32 = r.read::<f32>()*1000.0;
+  let value: u32 = r.read();
+  Row::Insert(((xmin,xmax),(ymin,ymax),time), value)
+}
+
+fn cmp<T> (a: &T, b: &T) -> Ordering where T: PartialOrd {
+  match a.partial_cmp(b) {
+    Some(o) => o,
+    None => panic!["comparison failed"]
+  }
+}
@@ -0,0 +1,106 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+use std::collections::HashSet;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn batch_iter() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..2_000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+
+  db.batch_iter(inserts[0..1_050].iter().cloned())?;
+  let mut expected: HashSet<V> = (0..1_050).collect();
+  check(&db, &expected)?;
+  for result in db.query(&BBOX)? {
+    let (_,v,loc) = result?;
+    assert_eq!(loc.2, v as u64, "ids in the order the rows were read");
+  }
+
+  // deletes from before the call stay valid after the first groups merge
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 3 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  let replaces: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 3 == 1 && v % 5 == 0)
+    .map(|(p,v,loc)| Row::Replace(loc,p,v+10_000))
+    .collect();
+  let rows: Vec<Row<P,V>> = inserts[1_050..2_000].iter().cloned()
+    .chain(deletes.into_iter())
+    .chain(replaces.into_iter())
+    .collect();
+  db.batch_iter(rows)?;
+  expected.extend(1_050..2_000);
+  expected.retain(|v| v % 3 != 0 || *v >= 1_050);
+  let replaced: Vec<V> = expected.iter().cloned()
+    .filter(|v| *v < 1_050 && v % 3 == 1 && v % 5 == 0)
+    .collect();
+  for v in replaced {
+    expected.remove(&v);
+    expected.insert(v+10_000);
+  }
+  check(&db, &expected)?;
+  drop(db);
+
+  let db = open(dir.path())?;
+  check(&db, &expected)?;
+  Ok(())
+}
+
+fn open (path: &Path)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, expected: &HashSet<V>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut values = vec![];
+  for result in db.query(&BBOX)? {
+    let (_,v,loc) = result?;
+    assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+    values.push(v);
+  }
+  values.sort();
+  let mut expected: Vec<V> = expected.iter().cloned().collect();
+  expected.sort();
+  assert_eq!(values, expected, "records");
+  assert_eq!(db.count(&BBOX)?, expected.len() as u64, "count");
+  Ok(())
+}
@@ -0,0 +1,155 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Point};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn bulk_load() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  let mut r = rand().seed([13,12]);
+  let rows: Vec<(P,V)> = (0..5_000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    (((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as Point>::Bounds> = (0..20).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.0-1.0;
+    let y0: f32 = r.read::<f32>()*2.0-1.0;
+    let x1: f32 = x0 + r.read::<f32>().powf(2.0)*(1.0-x0);
+    let y1: f32 = y0 + r.read::<f32>().powf(2.0)*(1.0-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+
+  db.bulk_load(rows[0..4_000].iter().cloned())?;
+  let mut expected: Vec<(P,V)> = rows[0..4_000].to_vec();
+  assert_eq!(trees(dir.path())?, 1, "a single tree");
+  assert_eq!(file_len(dir.path(), "staging_inserts")?, 0, "staged inserts");
+  for i in 0..6 {
+    assert_eq!(file_len(dir.path(), &format!("bulk{}",i))?, 0,
+      "temporary run {}", i);
+  }
+  check(&db, &expected, &bboxes)?;
+  for (i,result) in db.query(&BBOX)?.enumerate() {
+    let (_,v,loc) = result?;
+    assert_eq!(loc.2, v as u64, "ids in the order the rows were read");
+    if i > 100 { break }
+  }
+
+  // batches and another load on top of the loaded tree
+  let inserts: Vec<Row<P,V>> = rows[4_000..4_250].iter()
+    .map(|(p,v)| Row::Insert(*p,*v))
+    .collect();
+  db.batch(&inserts)?;
+  expected.extend_from_slice(&rows[4_000..4_250]);
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 3 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  expected.retain(|(_,v)| v % 3 != 0);
+  let (_,token) = db.query_page(&BBOX, 10, None)?;
+  db.bulk_load(rows[4_250..5_000].iter().cloned())?;
+  expected.extend_from_slice(&rows[4_250..5_000]);
+  check(&db, &expected, &bboxes)?;
+  assert!(db.query_page(&BBOX, 10, token.as_ref()).is_err(),
+    "page token from before the load");
+  drop(db);
+
+  let mut db = open(dir.path())?;
+  check(&db, &expected, &bboxes)?;
+  db.optimize()?;
+  check(&db, &expected, &bboxes)?;
+  Ok(())
+}
+
+#[test]
+fn bulk_load_small() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  db.bulk_load(vec![])?;
+  assert_eq!(trees(dir.path())?, 0, "nothing loaded");
+  let rows: Vec<(P,V)> = (0..50).map(|i| {
+    let x = (i as f32)/50.0;
+    (((x,x+0.01),(-x,-x+0.01)),i)
+  }).collect();
+  db.bulk_load(rows.clone())?;
+  assert_eq!(trees(dir.path())?, 0, "small loads are staged");
+  check(&db, &rows, &vec![((-0.5,-0.5),(0.5,0.5))])?;
+  Ok(())
+}
+
+fn open (path: &Path)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .bulk_run_size(700)
+    .build()
+}
+
+fn file_len (path: &Path, name: &str) -> Result<u64,Error> {
+  Ok(std::fs::metadata(path.join(name))?.len())
+}
+
+// number of non-empty tree files
+fn trees (path: &Path) -> Result<usize,Error> {
+  let mut n = 0;
+  for entry in std::fs::read_dir(path)? {
+    let entry = entry?;
+    let name = entry.file_name().into_string().unwrap();
+    if name.starts_with("tree") && !name.contains("snapshot")
+    && entry.metadata()?.len() > 0 {
+      n += 1;
+    }
+  }
+  Ok(n)
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, rows: &Vec<(P,V)>,
+bboxes: &Vec<<P as Point>::Bounds>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  for bbox in bboxes.iter().chain(vec![BBOX].iter()) {
+    let mut values = vec![];
+    for result in db.query(bbox)? {
+      let (_,v,loc) = result?;
+      assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+      values.push(v);
+    }
+    values.sort();
+    let mut expected: Vec<V> = rows.iter()
+      .filter(|(p,_)| p.overlaps(bbox))
+      .map(|(_,v)| *v)
+      .collect();
+    expected.sort();
+    assert_eq!(values, expected, "records in {:?}", bbox);
+    assert_eq!(db.count(bbox)?, expected.len() as u64, "count in {:?}", bbox);
+  }
+  Ok(())
+}
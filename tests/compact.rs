@@ -0,0 +1,132 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn compact() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path(), 1.0)?;
+  let inserts = random_inserts(1000);
+  // every batch but the first merges trees
+  for i in 0..10 {
+    db.batch(&inserts[i*100..(i+1)*100])?;
+  }
+  let garbage = db.garbage()?;
+  assert!(garbage > 0, "merges leave blocks behind");
+  let data_len = file_len(dir.path(), "data")?;
+  let range_len = file_len(dir.path(), "range")?;
+  assert_eq!(db.compact()?, garbage, "reclaimed bytes");
+  assert_eq!(db.garbage()?, 0, "no garbage after compacting");
+  assert_eq!(file_len(dir.path(), "data")?, data_len - garbage, "data file");
+  assert!(file_len(dir.path(), "range")? < range_len, "range file");
+  assert_eq!(std::fs::metadata(dir.path().join("data"))?.len(), 0,
+    "blocks are copied into a new data file");
+  check(&db, (0..1000).collect())?;
+  assert_eq!(db.compact()?, 0, "nothing left to compact");
+  drop(db);
+  let db = open(dir.path(), 1.0)?;
+  check(&db, (0..1000).collect())?;
+  Ok(())
+}
+
+#[test]
+fn compact_after_merges() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path(), 0.5)?;
+  let inserts = random_inserts(1000);
+  for i in 0..10 {
+    db.batch(&inserts[i*100..(i+1)*100])?;
+    let garbage = db.garbage()?;
+    assert!(garbage*2 <= file_len(dir.path(), "data")?,
+      "garbage after batch {}", i);
+  }
+  check(&db, (0..1000).collect())?;
+
+  // snapshots keep reading the blocks they started with
+  let snapshot = db.snapshot()?;
+  let expected: Vec<V> = (0..1000).collect();
+  let more = random_inserts(300);
+  for i in 0..3 {
+    db.batch(&more[i*100..(i+1)*100])?;
+  }
+  let mut values: Vec<V> = snapshot.query(&BBOX)?
+    .map(|result| result.map(|(_,v,_)| v))
+    .collect::<Result<_,Error>>()?;
+  values.sort();
+  assert_eq!(values, expected, "snapshot records");
+  assert!(db.compact().is_err(), "compact with an open snapshot");
+  drop(snapshot);
+  db.compact()?;
+  check(&db, (0..1000).chain(0..300).collect())?;
+  Ok(())
+}
+
+fn random_inserts (n: usize) -> Vec<Row<P,V>> {
+  let mut r = rand().seed([13,12]);
+  (0..n).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i as V)
+  }).collect()
+}
+
+fn open (path: &Path, gc_ratio: f64)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .gc_ratio(gc_ratio)
+    .build()
+}
+
+// length of the data or range file in use. compacting copies the blocks into
+// the file of the same name followed by 1 or back and empties the other file
+fn file_len (path: &Path, name: &str) -> Result<u64,Error> {
+  let mut len = 0;
+  for name in [name.to_string(), format!("{}1",name)].iter() {
+    if let Ok(metadata) = std::fs::metadata(path.join(name)) {
+      len += metadata.len();
+    }
+  }
+  Ok(len)
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, mut expected: Vec<V>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut values = vec![];
+  for result in db.query(&BBOX)? {
+    let (_,v,loc) = result?;
+    assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+    values.push(v);
+  }
+  values.sort();
+  expected.sort();
+  assert_eq!(values, expected, "records");
+  Ok(())
+}
@@ -0,0 +1,101 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::sync::{Arc,RwLock};
+use std::thread;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+
+fn assert_send_sync<T: Send+Sync> (_x: &T) {}
+
+#[test]
+fn concurrent_query() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let path = dir.path().to_path_buf();
+  let mut db: DB<_,_,P,V> = Setup::new(move |name: &str| {
+    Ok(RandomAccessDisk::builder(path.join(name))
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  assert_send_sync(&db);
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..1_000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    Row::Insert(((xmin,xmax),(ymin,ymax),time), i)
+  }).collect();
+  db.batch(&inserts)?;
+
+  let bbox = ((-0.5,-0.8,0.0),(0.3,0.5,1000.0));
+  let expected = query(&db, &bbox)?;
+  assert!(!expected.is_empty(), "query has results");
+
+  let db = Arc::new(RwLock::new(db));
+  let mut handles = vec![];
+  for _ in 0..8 {
+    let db = Arc::clone(&db);
+    let expected = expected.clone();
+    handles.push(thread::spawn(move || -> Result<(),Error> {
+      for _ in 0..20 {
+        let results = query(&db.read().unwrap(), &bbox)?;
+        assert_eq!(results, expected, "concurrent query results");
+      }
+      Ok(())
+    }));
+  }
+  {
+    // a writer that only inserts records outside of the query bbox
+    let db = Arc::clone(&db);
+    handles.push(thread::spawn(move || -> Result<(),Error> {
+      let mut r = rand().seed([13,12]);
+      for i in 0..10 {
+        let rows: Vec<Row<P,V>> = (0..50).map(|j| {
+          let x: f32 = r.read::<f32>()*2.0-1.0;
+          let y: f32 = r.read::<f32>()*2.0-1.0;
+          let time: f32 = 2000.0 + r.read::<f32>()*1000.0;
+          Row::Insert(((x,x),(y,y),time), 1_000+i*50+j)
+        }).collect();
+        db.write().unwrap().batch(&rows)?;
+      }
+      Ok(())
+    }));
+  }
+  for handle in handles {
+    handle.join().unwrap()?;
+  }
+  let db = db.read().unwrap();
+  assert_eq!(query(&db, &bbox)?, expected, "results after writes");
+  assert_eq!(query(&db, &((-1.0,-1.0,0.0),(1.0,1.0,3000.0)))?.len(), 1_500,
+    "all records after writes");
+  Ok(())
+}
+
+fn query<S,U> (db: &DB<S,U,P,V>, bbox: &((f32,f32,f32),(f32,f32,f32)))
+-> Result<Vec<V>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = vec![];
+  for result in db.query(bbox)? {
+    results.push(result?.1);
+  }
+  results.sort();
+  Ok(results)
+}
@@ -0,0 +1,136 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::{Path,PathBuf};
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn stored_config() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = setup(dir.path())
+    .branch_factor(9)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..550).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts)?;
+  drop(db);
+
+  // the stored settings are used instead of the defaults
+  let mut db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  assert_eq!(db.fields.branch_factor, 9, "branch factor");
+  assert_eq!(db.fields.max_data_size, 20, "max data size");
+  assert_eq!(db.fields.base_size, 100, "base size");
+  db.batch(&inserts[0..60])?;
+  let mut values: Vec<V> = db.query(&BBOX)?
+    .map(|result| result.map(|(_,v,_)| v))
+    .collect::<Result<_,Error>>()?;
+  values.sort();
+  let mut expected: Vec<V> = (0..550).chain(0..60).collect();
+  expected.sort();
+  assert_eq!(values, expected, "records");
+  drop(db);
+
+  let db: Result<DB<_,_,((f32,f32),(f32,f32),f32),V>,Error> =
+    setup(dir.path()).build();
+  assert!(db.is_err(), "different point dimension");
+  let db: Result<DB<_,_,((f64,f64),(f64,f64)),V>,Error> =
+    setup(dir.path()).build();
+  assert!(db.is_err(), "different point type");
+  let db: Result<DB<_,_,P,u64>,Error> = setup(dir.path()).build();
+  assert!(db.is_err(), "different value type");
+  Ok(())
+}
+
+#[test]
+fn config_of_empty_database() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let db: DB<_,_,P,V> = setup(dir.path()).base_size(123).build()?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  assert_eq!(db.fields.base_size, 123, "settings stored when created");
+  drop(db);
+  let db: Result<DB<_,_,P,u64>,Error> = setup(dir.path()).build();
+  assert!(db.is_err(), "different value type");
+  Ok(())
+}
+
+#[test]
+fn config_tag() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let db: DB<_,_,P,V> = setup(dir.path()).tag("reading").build()?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dir.path()).tag("reading").build()?;
+  drop(db);
+  // values with the same size are only told apart by the tag
+  let db: Result<DB<_,_,P,i32>,Error> = setup(dir.path()).tag("count").build();
+  assert!(db.is_err(), "different tag");
+  let db: Result<DB<_,_,P,V>,Error> = setup(dir.path()).build();
+  assert!(db.is_err(), "no tag");
+  Ok(())
+}
+
+#[test]
+fn stored_history_config() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = setup(dir.path())
+    .versioned(true)
+    .history_horizon(5)
+    .build()?;
+  db.batch(&[Row::Insert(((0.0,0.1),(0.0,0.1)), 1)])?;
+  drop(db);
+
+  // versioned mode and the horizon stay as they were created
+  let mut db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  assert!(db.fields.versioned, "versioned");
+  assert_eq!(db.fields.history_horizon, 5, "history horizon");
+  let loc = db.query(&BBOX)?.next().unwrap()?.2;
+  db.batch(&[Row::Delete(loc)])?;
+  assert_eq!(db.query(&BBOX)?.count(), 0, "deleted");
+  assert_eq!(db.query_as_of(&BBOX, 1)?.count(), 1, "history kept");
+  drop(db);
+
+  let db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dir.path()).versioned(true).build()?;
+  assert_eq!(db.fields.history_horizon, 5, "history horizon");
+  drop(db);
+
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dir.path()).versioned(true).build()?;
+  assert!(!db.fields.versioned, "not versioned");
+  assert!(db.query_as_of(&BBOX, 0).is_err(), "no history");
+  Ok(())
+}
+
+fn setup (path: &Path)
+-> Setup<RandomAccessDisk,impl Fn(&str) -> Result<RandomAccessDisk,Error>> {
+  let path: PathBuf = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+}
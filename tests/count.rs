@@ -0,0 +1,97 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+#[test]
+fn count() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let open = || -> Result<DB<_,_,P,V>,Error> {
+    Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+      let p = dir.path().join(name);
+      Ok(RandomAccessDisk::builder(p)
+        .auto_sync(false)
+        .build()?)
+    })
+      .branch_factor(5)
+      .max_data_size(20)
+      .base_size(100)
+      .build()
+  };
+  let mut db = open()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as eyros::Point>::Bounds> = (0..40).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.4-1.2;
+    let y0: f32 = r.read::<f32>()*2.4-1.2;
+    let x1: f32 = x0 + r.read::<f32>()*(1.2-x0);
+    let y1: f32 = y0 + r.read::<f32>()*(1.2-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+  let full = ((-1.0,-1.0),(1.0,1.0));
+
+  assert_eq!(db.count(&full)?, 0, "empty database");
+  db.batch(&inserts[0..50])?; // staging only
+  check(&db, &bboxes)?;
+  db.batch(&inserts[50..250])?;
+  check(&db, &bboxes)?;
+  db.batch(&inserts[250..650])?;
+  check(&db, &bboxes)?;
+  assert_eq!(db.count(&full)?, 650, "every record");
+
+  // staged deletes
+  let deletes: Vec<Row<P,V>> = db.query(&bboxes[0])?
+    .filter_map(|result| result.ok())
+    .take(10)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  check(&db, &bboxes)?;
+  assert_eq!(db.count(&full)?, 650 - deletes.len() as u64,
+    "staged deletes are not counted");
+
+  // deletes cleared from data blocks
+  db.batch(&vec![Row::DeleteBounds(bboxes[1])])?;
+  check(&db, &bboxes)?;
+  assert_eq!(db.count(&bboxes[1])?, 0, "no records left in deleted bbox");
+
+  // the number of live records in each block is stored
+  let total = db.count(&full)?;
+  drop(db);
+  let db = open()?;
+  check(&db, &bboxes)?;
+  assert_eq!(db.count(&full)?, total, "count after reopening");
+  Ok(())
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, bboxes: &Vec<<P as eyros::Point>::Bounds>)
+-> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  for bbox in bboxes.iter() {
+    let mut expected = 0;
+    for result in db.query(bbox)? {
+      result?;
+      expected += 1;
+    }
+    assert_eq!(db.count(bbox)?, expected, "count for {:?}", bbox);
+  }
+  Ok(())
+}
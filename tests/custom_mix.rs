@@ -1,4 +1,4 @@
-use eyros::{DB,Row,Point,Cursor,Block,order,order_len};
+use eyros::{DB,Row,Point,Predicate,Cursor,Block,order,order_len};
 use random::{Source,default as rand};
 use failure::{Error,bail};
 use random_access_disk::RandomAccessDisk;
@@ -189,6 +189,11 @@ impl Point for P {
     }
   }
 
+  fn bounds_within (bbox: &Self::Bounds, outer: &Self::Bounds) -> bool {
+    (outer.0).0 <= (bbox.0).0 && (bbox.1).0 <= (outer.1).0
+    && (outer.0).1 <= (bbox.0).1 && (bbox.1).1 <= (outer.1).1
+  }
+
   fn query_branch (buf: &[u8], bbox: &Self::Bounds, bf: usize, level: usize)
   -> Result<(Vec<Cursor>,Vec<Block>),Error> {
     let mut cursors = vec![];
@@ -279,7 +284,7 @@ impl Point for P {
           (f32::min((acc.0).0,*x),f32::min((acc.0).1,*y)),
           (f32::max((acc.1).0,*x),f32::max((acc.1).1,*y))
         ),
-        P::Interval((x0,y0),(x1,y1)) => (
+        P::Interval((x0,x1),(y0,y1)) => (
           (f32::min((acc.0).0,*x0),f32::min((acc.0).1,*y0)),
           (f32::max((acc.1).0,*x1),f32::max((acc.1).1,*y1))
         ),
@@ -344,6 +349,19 @@ fn mix() -> Result<(),Error> {
   expected.sort_unstable_by(cmp);
   assert_eq![results.len(), expected.len(), "expected number of results"];
   assert_eq![results, expected, "incorrect results"];
+
+  // query_with() falls back on query_branch() for this point type
+  let mut within: Vec<(P,V)> = db.query_with(&bbox, Predicate::Within)?
+    .map(|result| result.map(|(p,v,_)| (p,v)))
+    .collect::<Result<_,Error>>()?;
+  within.sort_unstable_by_key(|(_p,v)| *v);
+  let mut expected_within: Vec<(P,V)> = expected.iter()
+    .filter(|(p,_v)| p.matches(&bbox, Predicate::Within))
+    .map(|(p,v)| (*p,*v))
+    .collect();
+  expected_within.sort_unstable_by_key(|(_p,v)| *v);
+  assert![expected_within.len() > 0, "records within the bbox"];
+  assert_eq![within, expected_within, "incorrect results within the bbox"];
   Ok(())
 }
 
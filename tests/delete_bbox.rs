@@ -0,0 +1,95 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashMap;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+type B = ((f32,f32,f32),(f32,f32,f32));
+
+#[test]
+fn delete_bbox() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    let point = ((xmin,xmax),(ymin,ymax),time);
+    Row::Insert(point, i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  let before = records(&mut db, &full)?;
+  assert_eq!(before.len(), 250, "all records before deleting");
+
+  let bbox = ((-0.5,-0.3,0.0),(0.4,0.6,600.0));
+  db.delete_bbox(&bbox)?;
+  let expected: HashMap<V,P> = before.iter()
+    .filter(|(_,p)| !overlaps(p, &bbox))
+    .map(|(v,p)| (*v,*p)).collect();
+  assert!(expected.len() < before.len(), "some records are deleted");
+  assert!(expected.len() > 0, "some records are not deleted");
+  assert_eq!(records(&mut db, &full)?, expected, "records after delete_bbox");
+  assert!(records(&mut db, &bbox)?.is_empty(), "no records left in bbox");
+
+  // inserts in the same batch as a DeleteBounds row are not deleted
+  let bbox2 = ((0.2,-1.0,500.0),(1.0,0.0,1000.0));
+  let mut rows = inserts[250..650].to_vec();
+  rows.push(Row::DeleteBounds(bbox2));
+  db.batch(&rows)?;
+  let mut expected: HashMap<V,P> = expected.iter()
+    .filter(|(_,p)| !overlaps(p, &bbox2))
+    .map(|(v,p)| (*v,*p)).collect();
+  for row in inserts[250..650].iter() {
+    if let Row::Insert(p,v) = row { expected.insert(*v,*p); }
+  }
+  assert_eq!(records(&mut db, &full)?, expected,
+    "records after merging with a DeleteBounds row");
+  Ok(())
+}
+
+fn records<S,U> (db: &mut DB<S,U,P,V>, bbox: &B) -> Result<HashMap<V,P>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = HashMap::new();
+  for result in db.query(bbox)? {
+    let (p,v,_) = result?;
+    assert!(results.insert(v,p).is_none(), "no duplicate results");
+  }
+  Ok(results)
+}
+
+fn overlaps (p: &P, bbox: &B) -> bool {
+  contains_iv((bbox.0).0, (bbox.1).0, p.0)
+  && contains_iv((bbox.0).1, (bbox.1).1, p.1)
+  && contains_pt((bbox.0).2, (bbox.1).2, p.2)
+}
+fn contains_iv<T> (min: T, max: T, iv: (T,T)) -> bool where T: PartialOrd {
+  min <= iv.1 && iv.0 <= max
+}
+fn contains_pt<T> (min: T, max: T, pt: T) -> bool where T: PartialOrd {
+  min <= pt && pt <= max
+}
@@ -0,0 +1,91 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashMap;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = (u32,u32);
+type B = ((f32,f32,f32),(f32,f32,f32));
+
+#[test]
+fn delete_where() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  // values are (source, record number)
+  let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    let point = ((xmin,xmax),(ymin,ymax),time);
+    Row::Insert(point, (i%4,i))
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  let before = records(&mut db, &full)?;
+  assert_eq!(before.len(), 250, "all records before deleting");
+
+  let bbox = ((-0.5,-0.3,0.0),(0.4,0.6,600.0));
+  db.delete_where(&bbox, |_p,v| v.0 == 2)?;
+  let expected: HashMap<u32,(P,V)> = before.iter()
+    .filter(|(_,(p,v))| !(v.0 == 2 && overlaps(p, &bbox)))
+    .map(|(k,pv)| (*k,*pv)).collect();
+  assert!(expected.len() < before.len(), "some records are deleted");
+  assert_eq!(records(&mut db, &full)?, expected, "records after delete_where");
+  assert!(records(&mut db, &bbox)?.values().all(|(_,v)| v.0 != 2),
+    "no matching records left in bbox");
+
+  // the deletes hold after merging trees
+  db.batch(&inserts[250..650])?;
+  let mut expected = expected;
+  for row in inserts[250..650].iter() {
+    if let Row::Insert(p,v) = row { expected.insert(v.1,(*p,*v)); }
+  }
+  assert_eq!(records(&mut db, &full)?, expected, "records after merging");
+  Ok(())
+}
+
+fn records<S,U> (db: &mut DB<S,U,P,V>, bbox: &B)
+-> Result<HashMap<u32,(P,V)>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = HashMap::new();
+  for result in db.query(bbox)? {
+    let (p,v,_) = result?;
+    assert!(results.insert(v.1,(p,v)).is_none(), "no duplicate results");
+  }
+  Ok(results)
+}
+
+fn overlaps (p: &P, bbox: &B) -> bool {
+  contains_iv((bbox.0).0, (bbox.1).0, p.0)
+  && contains_iv((bbox.0).1, (bbox.1).1, p.1)
+  && contains_pt((bbox.0).2, (bbox.1).2, p.2)
+}
+fn contains_iv<T> (min: T, max: T, iv: (T,T)) -> bool where T: PartialOrd {
+  min <= iv.1 && iv.0 <= max
+}
+fn contains_pt<T> (min: T, max: T, pt: T) -> bool where T: PartialOrd {
+  min <= pt && pt <= max
+}
@@ -0,0 +1,182 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::{Path,PathBuf};
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+// tests/fixtures/format0 was written by eyros 2.0.0 with branch_factor(5),
+// max_data_size(20) and base_size(100): batches of the records 0..250,
+// 250..650 and 650..680 generated below, then staged deletes for every record
+// with a value divisible by 7
+#[test]
+fn migrate() -> Result<(),Error> {
+  let src = Tmpfile::new().prefix("eyros").tempdir()?;
+  let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/fixtures/format0");
+  for entry in std::fs::read_dir(&fixtures)? {
+    let entry = entry?;
+    std::fs::copy(entry.path(), src.path().join(entry.file_name()))?;
+  }
+  let dst = Tmpfile::new().prefix("eyros").tempdir()?;
+
+  let db: Result<DB<_,_,P,V>,Error> = setup(src.path()).build();
+  assert!(db.is_err(), "older format without migrating");
+  let mut db: DB<_,_,P,V> = eyros::migrate(
+    storage(src.path()),
+    setup(dst.path()).branch_factor(5).max_data_size(20).base_size(100)
+  )?;
+  let mut r = rand().seed([13,12]);
+  let mut expected: Vec<(P,V)> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    (((xmin,xmax),(ymin,ymax)), i)
+  }).filter(|(_,v)| v % 7 != 0).collect();
+  check(&db, &expected)?;
+
+  // the migrated database is an ordinary database in the current format
+  let rows: Vec<Row<P,V>> = expected[0..150].iter()
+    .map(|(p,v)| Row::Insert(*p,v+1_000))
+    .collect();
+  db.batch(&rows)?;
+  expected.extend(expected[0..150].iter().map(|(p,v)| (*p,v+1_000))
+    .collect::<Vec<_>>());
+  check(&db, &expected)?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dst.path()).build()?;
+  assert_eq!(db.fields.max_data_size, 20, "settings of the migrated database");
+  check(&db, &expected)?;
+  drop(db);
+
+  let other = Tmpfile::new().prefix("eyros").tempdir()?;
+  let db: Result<DB<_,_,P,V>,Error> = eyros::migrate(
+    storage(dst.path()),
+    setup(other.path()).branch_factor(5)
+  );
+  assert!(db.is_err(), "already in the current format");
+  Ok(())
+}
+
+// tests/fixtures/format1 was written in format 1 with the same settings,
+// records and staged deletes as tests/fixtures/format0
+#[test]
+fn migrate_format1() -> Result<(),Error> {
+  let src = Tmpfile::new().prefix("eyros").tempdir()?;
+  let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/fixtures/format1");
+  for entry in std::fs::read_dir(&fixtures)? {
+    let entry = entry?;
+    std::fs::copy(entry.path(), src.path().join(entry.file_name()))?;
+  }
+  let dst = Tmpfile::new().prefix("eyros").tempdir()?;
+
+  let db: Result<DB<_,_,P,V>,Error> = setup(src.path()).build();
+  assert!(db.is_err(), "format 1 without migrating");
+  // the branch factor of format 1 databases is read from their meta file
+  let db: DB<_,_,P,V> = eyros::migrate(
+    storage(src.path()),
+    setup(dst.path()).branch_factor(9).max_data_size(20).base_size(100)
+  )?;
+  let mut r = rand().seed([13,12]);
+  let expected: Vec<(P,V)> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    (((xmin,xmax),(ymin,ymax)), i)
+  }).filter(|(_,v)| v % 7 != 0).collect();
+  check(&db, &expected)?;
+  drop(db);
+  let db: DB<_,_,P,V> = setup(dst.path()).build()?;
+  assert_eq!(db.fields.branch_factor, 9, "settings of the migrated database");
+  check(&db, &expected)?;
+  Ok(())
+}
+
+#[test]
+fn unreadable_source() -> Result<(),Error> {
+  let src = Tmpfile::new().prefix("eyros").tempdir()?;
+  let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/fixtures/format0");
+  for entry in std::fs::read_dir(&fixtures)? {
+    let entry = entry?;
+    std::fs::copy(entry.path(), src.path().join(entry.file_name()))?;
+  }
+  // the later data blocks are cut off
+  let data = std::fs::read(src.path().join("data"))?;
+  std::fs::write(src.path().join("data"), &data[0..data.len()/2])?;
+  let dst = Tmpfile::new().prefix("eyros").tempdir()?;
+  let db: Result<DB<_,_,P,V>,Error> = eyros::migrate(
+    storage(src.path()),
+    setup(dst.path()).branch_factor(5).max_data_size(20).base_size(100)
+  );
+  assert!(db.is_err(), "truncated data");
+  let db: DB<_,_,P,V> = setup(dst.path()).build()?;
+  check(&db, &vec![])?;
+  assert_eq!(db.count(&BBOX)?, 0, "nothing loaded");
+  Ok(())
+}
+
+#[test]
+fn unknown_format_version() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = setup(dir.path()).build()?;
+  db.batch(&[Row::Insert(((0.1,0.2),(0.3,0.4)),5)])?;
+  drop(db);
+  let path = dir.path().join("meta");
+  let mut meta = std::fs::read(&path)?;
+  assert_eq!(&meta[0..5], b"eyros", "header");
+  assert_eq!(meta[5..7], eyros::FORMAT_VERSION.to_be_bytes(), "version");
+  meta[6] += 1;
+  std::fs::write(&path, &meta)?;
+  let db: Result<DB<_,_,P,V>,Error> = setup(dir.path()).build();
+  assert!(db.is_err(), "newer format version");
+  Ok(())
+}
+
+fn storage (path: &Path) -> impl Fn(&str) -> Result<RandomAccessDisk,Error> {
+  let path: PathBuf = path.to_path_buf();
+  move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  }
+}
+
+fn setup (path: &Path)
+-> Setup<RandomAccessDisk,impl Fn(&str) -> Result<RandomAccessDisk,Error>> {
+  Setup::new(storage(path))
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, expected: &Vec<(P,V)>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = vec![];
+  for result in db.query(&BBOX)? {
+    let (p,v,loc) = result?;
+    assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+    results.push((v,format!("{:?}",p)));
+  }
+  results.sort();
+  let mut expected: Vec<(V,String)> = expected.iter()
+    .map(|(p,v)| (*v,format!("{:?}",p)))
+    .collect();
+  expected.sort();
+  assert_eq!(results, expected, "records");
+  Ok(())
+}
@@ -0,0 +1,108 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Spatial,Distance,Euclidean};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashSet;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+// distance along the axes instead of a straight line
+struct Manhattan;
+
+impl Distance for Manhattan {
+  fn distance (&self, a: &[(f64,f64)], b: &[(f64,f64)]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x,y)| {
+      (x.0-y.1).max(y.0-x.1).max(0.0)
+    }).sum()
+  }
+}
+
+#[test]
+fn nearest() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..650).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+
+  let targets: Vec<P> = (0..20).map(|_| {
+    let x: f32 = r.read::<f32>()*2.4-1.2;
+    let y: f32 = r.read::<f32>()*2.4-1.2;
+    ((x,x),(y,y))
+  }).collect();
+  for target in targets.iter() {
+    for k in [1,10,50].iter() {
+      check(&db, target, *k, &Euclidean)?;
+      check(&db, target, *k, &Manhattan)?;
+    }
+  }
+  assert_eq!(db.nearest(&targets[0], 0)?.len(), 0, "no results for k=0");
+  assert_eq!(db.nearest(&targets[0], 1000)?.len(), 650,
+    "every record when k is larger than the database");
+
+  // an interval that contains the target is at a distance of 0
+  let target = ((0.0,0.0),(0.0,0.0));
+  db.batch(&vec![Row::Insert(((-0.9,0.9),(-0.01,0.01)), 1000)])?;
+  assert_eq!(db.nearest(&target, 1)?[0].1, 1000, "overlapping interval");
+
+  // deleted records are skipped
+  let full = ((-1.0,-1.0),(1.0,1.0));
+  let deletes: Vec<Row<P,V>> = db.query(&full)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 2 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  for target in targets.iter() {
+    let results = check(&db, target, 30, &Euclidean)?;
+    assert!(results.iter().all(|v| v % 2 == 1), "no deleted records");
+  }
+  Ok(())
+}
+
+// compare the distances of the nearest records to a full scan
+fn check<S,U,D> (db: &DB<S,U,P,V>, target: &P, k: usize, metric: &D)
+-> Result<Vec<V>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error>,
+D: Distance {
+  let t = target.extents();
+  let mut expected: Vec<f64> = db.query(&((-2.0,-2.0),(2.0,2.0)))?
+    .map(|result| Ok(metric.distance(&t, &result?.0.extents())))
+    .collect::<Result<Vec<f64>,Error>>()?;
+  expected.sort_by(|a,b| a.partial_cmp(b).unwrap());
+  expected.truncate(k);
+  let results = db.nearest_by(target, k, metric)?;
+  let distances: Vec<f64> = results.iter()
+    .map(|(p,_,_)| metric.distance(&t, &p.extents())).collect();
+  assert_eq!(distances, expected, "nearest {} to {:?}", k, target);
+  let values: HashSet<V> = results.iter().map(|(_,v,_)| *v).collect();
+  assert_eq!(values.len(), results.len(), "no duplicate results");
+  Ok(results.iter().map(|(_,v,_)| *v).collect())
+}
@@ -0,0 +1,151 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+use std::collections::HashSet;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn optimize() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..1000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+  let mut expected: HashSet<V> = (0..680).collect();
+  // sparse data blocks
+  db.delete_where(&BBOX, |_,v| v % 3 == 0 && *v < 400)?;
+  expected.retain(|v| v % 3 != 0 || *v >= 400);
+  // staged deletes for records in the trees and in staging
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 7 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  expected.retain(|v| v % 7 != 0);
+  let before = trees(dir.path())?;
+  assert!(before > 1, "several trees before optimizing");
+
+  assert_eq!(db.optimize()?, before-1, "collapsed levels");
+  assert_eq!(trees(dir.path())?, 1, "a single tree");
+  assert_eq!(file_len(dir.path(), "staging_inserts")?, 0, "staged inserts");
+  assert_eq!(file_len(dir.path(), "staging_deletes")?, 0, "staged deletes");
+  check(&db, &expected)?;
+  assert_eq!(db.optimize()?, 0, "already optimized");
+  check(&db, &expected)?;
+
+  // writes after optimizing
+  db.batch(&inserts[680..1000])?;
+  expected.extend(680..1000);
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 5 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  expected.retain(|v| v % 5 != 0);
+  check(&db, &expected)?;
+  drop(db);
+
+  let mut db = open(dir.path())?;
+  check(&db, &expected)?;
+  db.optimize()?;
+  assert_eq!(trees(dir.path())?, 1, "a single tree");
+  check(&db, &expected)?;
+  drop(db);
+  let db = open(dir.path())?;
+  check(&db, &expected)?;
+  Ok(())
+}
+
+#[test]
+fn optimize_small() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  assert_eq!(db.optimize()?, 0, "empty database");
+  db.batch(&[Row::Insert(((0.1,0.2),(0.3,0.4)),5)])?;
+  db.optimize()?;
+  check(&db, &vec![5].into_iter().collect())?;
+  db.batch(&[Row::Insert(((-0.1,0.2),(0.3,0.5)),6)])?;
+  db.optimize()?;
+  assert_eq!(trees(dir.path())?, 1, "a tree with two records");
+  check(&db, &vec![5,6].into_iter().collect())?;
+  db.delete_bbox(&BBOX)?;
+  db.optimize()?;
+  assert_eq!(trees(dir.path())?, 0, "no records left");
+  check(&db, &HashSet::new())?;
+  Ok(())
+}
+
+fn open (path: &Path)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()
+}
+
+fn file_len (path: &Path, name: &str) -> Result<u64,Error> {
+  Ok(std::fs::metadata(path.join(name))?.len())
+}
+
+// number of non-empty tree files
+fn trees (path: &Path) -> Result<usize,Error> {
+  let mut n = 0;
+  for entry in std::fs::read_dir(path)? {
+    let entry = entry?;
+    let name = entry.file_name().into_string().unwrap();
+    if name.starts_with("tree") && !name.contains("snapshot")
+    && entry.metadata()?.len() > 0 {
+      n += 1;
+    }
+  }
+  Ok(n)
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, expected: &HashSet<V>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut values = vec![];
+  for result in db.query(&BBOX)? {
+    let (_,v,loc) = result?;
+    assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+    values.push(v);
+  }
+  values.sort();
+  let mut expected: Vec<V> = expected.iter().cloned().collect();
+  expected.sort();
+  assert_eq!(values, expected, "records");
+  assert_eq!(db.count(&BBOX)?, expected.len() as u64, "count");
+  Ok(())
+}
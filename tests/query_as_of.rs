@@ -0,0 +1,194 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Point,Id};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+type Snapshot = Vec<(P,V,Id)>;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn query_as_of() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path(), 1_000)?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as Point>::Bounds> = (0..10).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.0-1.0;
+    let y0: f32 = r.read::<f32>()*2.0-1.0;
+    let x1: f32 = x0 + r.read::<f32>().powf(2.0)*(1.0-x0);
+    let y1: f32 = y0 + r.read::<f32>().powf(2.0)*(1.0-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+
+  let snapshots = write_batches(&mut db, &inserts, &bboxes)?;
+  check(&db, &snapshots, &bboxes)?;
+  assert!(db.query_as_of(&BBOX, snapshots.len() as u64).is_err(),
+    "batch past the latest batch");
+  drop(db);
+  let db = open(dir.path(), 1_000)?;
+  check(&db, &snapshots, &bboxes)?;
+  drop(db);
+
+  // versions older than the horizon are discarded in a database created
+  // with a shorter horizon
+  let len = std::fs::metadata(dir.path().join("history"))?.len();
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path(), 2)?;
+  let mut snapshots = write_batches(&mut db, &inserts, &bboxes)?;
+  db.batch(&inserts[0..10])?;
+  snapshots.push(snapshot(&db)?);
+  let g = snapshots.len() as u64 - 1;
+  assert!(db.query_as_of(&BBOX, g-3).is_err(), "older than the horizon");
+  for i in g-2..g+1 {
+    compare(&db, &BBOX, i, &snapshots[i as usize])?;
+  }
+
+  // the records of batches before the horizon are dropped from the file
+  for i in 0..20 {
+    let rows: Vec<Row<P,V>> = db.query(&BBOX)?.take(5)
+      .map(|result| result.map(|(p,v,loc)| Row::Replace(loc,p,v+i)))
+      .collect::<Result<_,Error>>()?;
+    db.batch(&rows)?;
+    snapshots.push(snapshot(&db)?);
+  }
+  let history_len = std::fs::metadata(dir.path().join("history"))?.len();
+  assert!(history_len < len, "history before the horizon is dropped");
+  drop(db);
+  let db = open(dir.path(), 2)?;
+  let g = snapshots.len() as u64 - 1;
+  assert_eq!(db.batch_number(), Some(g), "batch number after reopening");
+  assert!(db.query_as_of(&BBOX, g-3).is_err(), "older than the horizon");
+  for i in g-2..g+1 {
+    compare(&db, &BBOX, i, &snapshots[i as usize])?;
+  }
+  Ok(())
+}
+
+#[test]
+fn query_as_of_unversioned() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = Setup::new(
+    |name: &str| -> Result<RandomAccessDisk,Error> {
+      let p = dir.path().join(name);
+      Ok(RandomAccessDisk::builder(p)
+        .auto_sync(false)
+        .build()?)
+    })
+    .build()?;
+  db.batch(&[Row::Insert(((0.1,0.2),(0.3,0.4)),5)])?;
+  assert_eq!(db.batch_number(), None);
+  assert!(db.query_as_of(&BBOX, 0).is_err(), "not versioned");
+  Ok(())
+}
+
+fn open (path: &Path, horizon: u64)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .versioned(true)
+    .history_horizon(horizon)
+    .build()
+}
+
+// write batches of inserts, deletes and replaces to `db`, returning the live
+// records after each batch
+fn write_batches<S,U> (db: &mut DB<S,U,P,V>, inserts: &[Row<P,V>],
+bboxes: &[<P as Point>::Bounds]) -> Result<Vec<Snapshot>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut snapshots: Vec<Snapshot> = vec![vec![]];
+  db.batch(&inserts[0..250])?;
+  snapshots.push(snapshot(db)?);
+  db.batch(&inserts[250..650])?;
+  snapshots.push(snapshot(db)?);
+  db.batch(&inserts[650..680])?; // staging
+  snapshots.push(snapshot(db)?);
+  // deletes from the trees and from staging
+  let deletes: Vec<Row<P,V>> = db.query(&bboxes[0])?
+    .map(|result| result.map(|(_,_,loc)| Row::Delete(loc)))
+    .collect::<Result<_,Error>>()?;
+  assert!(deletes.len() > 0, "records to delete");
+  db.batch(&deletes)?;
+  snapshots.push(snapshot(db)?);
+  let replaces: Vec<Row<P,V>> = db.query(&BBOX)?.step_by(7)
+    .map(|result| result.map(|(p,v,loc)| Row::Replace(loc,p,v+1000)))
+    .collect::<Result<_,Error>>()?;
+  assert!(replaces.len() > 0, "records to replace");
+  db.batch(&replaces)?;
+  snapshots.push(snapshot(db)?);
+  db.delete_bbox(&bboxes[2])?;
+  snapshots.push(snapshot(db)?);
+  // merges the trees and flushes the staged deletes
+  db.batch(&inserts[0..100].iter().map(|row| match row {
+    Row::Insert(p,v) => Row::Insert(*p,v+2000),
+    _ => panic!["unexpected row type"]
+  }).collect::<Vec<_>>())?;
+  snapshots.push(snapshot(db)?);
+  assert_eq!(db.batch_number(), Some((snapshots.len()-1) as u64));
+  Ok(snapshots)
+}
+
+// every live record after the latest batch
+fn snapshot<S,U> (db: &DB<S,U,P,V>) -> Result<Snapshot,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  db.query(&BBOX)?
+    .map(|result| result.map(|(p,v,loc)| (p,v,loc.2)))
+    .collect()
+}
+
+fn check<S,U> (db: &DB<S,U,P,V>, snapshots: &[Snapshot],
+bboxes: &[<P as Point>::Bounds]) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  for (g,snapshot) in snapshots.iter().enumerate() {
+    compare(db, &BBOX, g as u64, snapshot)?;
+    for bbox in bboxes.iter() {
+      compare(db, bbox, g as u64, snapshot)?;
+    }
+  }
+  Ok(())
+}
+
+fn compare<S,U> (db: &DB<S,U,P,V>, bbox: &<P as Point>::Bounds,
+batch: u64, snapshot: &Snapshot) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut expected: Vec<(Id,V)> = snapshot.iter()
+    .filter(|(p,_,_)| p.overlaps(bbox))
+    .map(|(_,v,id)| (*id,*v))
+    .collect();
+  expected.sort();
+  let mut results: Vec<(Id,V)> = vec![];
+  for result in db.query_as_of(bbox, batch)? {
+    let (_,v,id) = result?;
+    results.push((id,v));
+  }
+  results.sort();
+  assert_eq!(results, expected, "batch {} in {:?}", batch, bbox);
+  Ok(())
+}
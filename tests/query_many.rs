@@ -0,0 +1,86 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Point};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashMap;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+#[test]
+fn query_many() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+
+  let bboxes: Vec<<P as Point>::Bounds> = (0..40).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.4-1.2;
+    let y0: f32 = r.read::<f32>()*2.4-1.2;
+    let x1: f32 = x0 + r.read::<f32>()*(1.2-x0)*0.5;
+    let y1: f32 = y0 + r.read::<f32>()*(1.2-y0)*0.5;
+    ((x0,y0),(x1,y1))
+  }).collect();
+  for n in [1,2,5,40].iter() {
+    for chunk in bboxes.chunks(*n) {
+      check(&db, chunk)?;
+    }
+  }
+  assert_eq!(db.query_many(&[])?.count(), 0, "no boxes");
+
+  let deletes: Vec<Row<P,V>> = db.query(&bboxes[0])?
+    .filter_map(|result| result.ok())
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  check(&db, &bboxes[0..5])?;
+  Ok(())
+}
+
+// compare to a query for each box
+fn check<S,U> (db: &DB<S,U,P,V>, bboxes: &[<P as Point>::Bounds])
+-> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut expected: HashMap<V,Vec<usize>> = HashMap::new();
+  for (i,bbox) in bboxes.iter().enumerate() {
+    for result in db.query(bbox)? {
+      expected.entry(result?.1).or_insert_with(Vec::new).push(i);
+    }
+  }
+  let mut results: HashMap<V,Vec<usize>> = HashMap::new();
+  for result in db.query_many(bboxes)? {
+    let (_,v,_,mut matched) = result?;
+    matched.sort();
+    assert!(results.insert(v, matched).is_none(), "record {} returned once", v);
+  }
+  assert_eq!(results, expected, "matches for {:?}", bboxes);
+  Ok(())
+}
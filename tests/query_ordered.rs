@@ -0,0 +1,91 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+type P = (f32,f32,(f32,f32));
+type V = u32;
+
+#[test]
+fn query_ordered() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..680).map(|i| {
+    let x: f32 = r.read::<f32>()*2.0-1.0;
+    let y: f32 = r.read::<f32>()*2.0-1.0;
+    let t0: f32 = r.read::<f32>()*1000.0;
+    let t1: f32 = t0 + r.read::<f32>().powf(8.0)*100.0;
+    Row::Insert((x,y,(t0,t1)), i)
+  }).collect();
+  let bboxes: Vec<<P as eyros::Point>::Bounds> = (0..20).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.4-1.2;
+    let y0: f32 = r.read::<f32>()*2.4-1.2;
+    let t0: f32 = r.read::<f32>()*1200.0-100.0;
+    let x1: f32 = x0 + r.read::<f32>()*(1.2-x0);
+    let y1: f32 = y0 + r.read::<f32>()*(1.2-y0);
+    let t1: f32 = t0 + r.read::<f32>()*(1100.0-t0);
+    ((x0,y0,t0),(x1,y1,t1))
+  }).collect();
+
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+  for bbox in bboxes.iter() {
+    check(&db, bbox, 2)?;
+    check(&db, bbox, 0)?;
+  }
+
+  let deletes: Vec<Row<P,V>> = db.query(&bboxes[0])?
+    .filter_map(|result| result.ok())
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  for bbox in bboxes.iter() {
+    check(&db, bbox, 2)?;
+  }
+  assert_eq!(db.query_ordered(&bboxes[0], 2)?.count(), 0, "deleted records");
+  assert!(db.query_ordered(&bboxes[0], 3).is_err(), "dimension out of bounds");
+  Ok(())
+}
+
+// results are in order and match a regular query
+fn check<S,U> (db: &DB<S,U,P,V>, bbox: &<P as eyros::Point>::Bounds,
+dim: usize) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut expected = vec![];
+  for result in db.query(bbox)? {
+    expected.push(result?.1);
+  }
+  expected.sort();
+  let mut keys = vec![];
+  let mut values = vec![];
+  for result in db.query_ordered(bbox, dim)? {
+    let (p,v,_) = result?;
+    keys.push(match dim { 0 => p.0, 1 => p.1, _ => (p.2).0 });
+    values.push(v);
+  }
+  assert!(keys.windows(2).all(|w| w[0] <= w[1]),
+    "ordered along dimension {} for {:?}", dim, bbox);
+  values.sort();
+  assert_eq!(values, expected, "same records as query for {:?}", bbox);
+  Ok(())
+}
@@ -0,0 +1,117 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Token};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use desert::{ToBytes,FromBytes};
+
+use std::collections::HashSet;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+#[test]
+fn query_page() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..1000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as eyros::Point>::Bounds> = (0..20).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.4-1.2;
+    let y0: f32 = r.read::<f32>()*2.4-1.2;
+    let x1: f32 = x0 + r.read::<f32>()*(1.2-x0);
+    let y1: f32 = y0 + r.read::<f32>()*(1.2-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+  let full = ((-1.0,-1.0),(1.0,1.0));
+
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+  for bbox in bboxes.iter() {
+    for limit in [1,7,100,1000].iter() {
+      check(&db, bbox, *limit)?;
+    }
+  }
+  let (all,token) = db.query_page(&full, 1000, None)?;
+  assert_eq!(all.len(), 680, "every record in one page");
+  assert!(token.is_none(), "no token after the last page");
+
+  // records staged between pages are returned
+  let (page,token) = db.query_page(&full, 300, None)?;
+  db.batch(&inserts[680..685])?;
+  let mut values: HashSet<V> = page.iter().map(|(_,v,_)| *v).collect();
+  let mut token = token;
+  while let Some(t) = token {
+    let (page,next) = db.query_page(&full, 300, Some(&t))?;
+    values.extend(page.iter().map(|(_,v,_)| *v));
+    token = next;
+  }
+  assert_eq!(values.len(), 685, "records staged between pages");
+
+  // tokens are rejected after a batch that changes the trees
+  let (_,token) = db.query_page(&full, 300, None)?;
+  let token = token.unwrap();
+  db.batch(&inserts[685..1000])?;
+  assert!(db.query_page(&full, 300, Some(&token)).is_err(), "stale token");
+
+  // tokens are rejected for a different bbox
+  let (_,token) = db.query_page(&full, 300, None)?;
+  let token = token.unwrap();
+  let bbox = ((-0.5,-0.5),(0.5,0.5));
+  assert!(db.query_page(&bbox, 300, Some(&token)).is_err(), "different bbox");
+  db.query_page(&full, 300, Some(&token))?;
+  Ok(())
+}
+
+// page through bbox and compare to a regular query
+fn check<S,U> (db: &DB<S,U,P,V>, bbox: &<P as eyros::Point>::Bounds,
+limit: usize) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut expected = vec![];
+  for result in db.query(bbox)? {
+    expected.push(result?.1);
+  }
+  expected.sort();
+  let mut values = vec![];
+  let mut token: Option<Token> = None;
+  loop {
+    let (page,next) = db.query_page(bbox, limit, token.as_ref())?;
+    assert!(page.len() <= limit, "page no larger than the limit");
+    values.extend(page.iter().map(|(_,v,_)| *v));
+    match next {
+      None => break,
+      Some(t) => {
+        // resume from a serialized token
+        let (_,t) = Token::from_bytes(&t.to_bytes()?)?;
+        token = Some(t);
+      }
+    }
+  }
+  values.sort();
+  assert_eq!(values, expected, "pages with limit {} for {:?}", limit, bbox);
+  Ok(())
+}
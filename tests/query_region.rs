@@ -0,0 +1,111 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Spatial,QueryRegion,Polygon,Radius};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+#[test]
+fn query_region() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+
+  for _ in 0..20 {
+    let x: f64 = r.read::<f64>()*2.0-1.0;
+    let y: f64 = r.read::<f64>()*2.0-1.0;
+    check(&db, &Radius::new((x,y), r.read::<f64>()*0.5))?;
+    // star with 5 points, which is concave
+    let size = r.read::<f64>()*0.8;
+    let points = (0..10).map(|i| {
+      let a = (i as f64)*std::f64::consts::PI/5.0;
+      let d = if i % 2 == 0 { size } else { size*0.4 };
+      (x + d*a.cos(), y + d*a.sin())
+    }).collect();
+    check(&db, &Polygon::new(points))?;
+  }
+
+  // deleted records are skipped
+  let circle = Radius::new((0.1,-0.2), 0.4);
+  let deletes: Vec<Row<P,V>> = db.query_region(&circle)?
+    .filter_map(|result| result.ok())
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  assert!(deletes.len() > 0, "records in the circle");
+  db.batch(&deletes)?;
+  assert_eq!(db.query_region(&circle)?.count(), 0, "deleted records");
+  Ok(())
+}
+
+#[test]
+fn region_geometry() {
+  let square = Polygon::new(vec![(0.0,0.0),(2.0,0.0),(2.0,2.0),(0.0,2.0)]);
+  let notch = Polygon::new(vec![
+    (0.0,0.0),(3.0,0.0),(3.0,3.0),(2.0,3.0),(2.0,1.0),(1.0,1.0),(1.0,3.0),
+    (0.0,3.0)
+  ]);
+  assert!(square.overlaps(&[(1.0,1.0),(1.0,1.0)]), "point inside");
+  assert!(!square.overlaps(&[(3.0,3.0),(1.0,1.0)]), "point outside");
+  assert!(square.overlaps(&[(2.0,2.0),(1.0,1.0)]), "point on an edge");
+  assert!(square.overlaps_box(&[(0.5,0.6),(0.5,0.6)]), "box inside");
+  assert!(square.overlaps_box(&[(-1.0,3.0),(-1.0,3.0)]), "box around");
+  assert!(square.overlaps_box(&[(-1.0,3.0),(0.5,0.6)]), "box across");
+  assert!(!square.overlaps_box(&[(2.5,3.0),(0.0,2.0)]), "box beside");
+  assert!(!notch.overlaps(&[(1.5,1.5),(2.0,2.0)]), "point in the notch");
+  assert!(!notch.overlaps_box(&[(1.2,1.8),(1.5,2.5)]), "box in the notch");
+  assert!(notch.overlaps_box(&[(1.2,1.8),(0.5,2.5)]), "box into the notch");
+
+  let circle = Radius::new((0.0,0.0), 1.0);
+  assert!(circle.overlaps(&[(0.6,0.6),(0.6,0.6)]), "point inside");
+  assert!(!circle.overlaps(&[(0.8,0.8),(0.8,0.8)]), "point outside");
+  assert!(circle.overlaps_box(&[(0.8,2.0),(-5.0,5.0)]), "box across");
+  assert!(!circle.overlaps_box(&[(0.8,2.0),(0.8,2.0)]), "box at a corner");
+}
+
+// compare to a full scan of the database
+fn check<S,U,R> (db: &DB<S,U,P,V>, region: &R) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error>,
+R: QueryRegion {
+  let mut expected = vec![];
+  for result in db.query(&((-1.0,-1.0),(1.0,1.0)))? {
+    let (p,v,_) = result?;
+    if region.overlaps(&p.extents()) { expected.push(v) }
+  }
+  expected.sort();
+  let mut values = vec![];
+  for result in db.query_region(region)? {
+    values.push(result?.1);
+  }
+  values.sort();
+  assert_eq!(values, expected, "records in {:?}", region.bounds());
+  Ok(())
+}
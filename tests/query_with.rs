@@ -0,0 +1,132 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Point,Spatial,Predicate,Mix,Mix2};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+type V = u32;
+
+#[test]
+fn query_with_intervals() -> Result<(),Error> {
+  type P = ((f32,f32),(f32,f32));
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = Setup::new(
+    |name: &str| -> Result<RandomAccessDisk,Error> {
+      let p = dir.path().join(name);
+      Ok(RandomAccessDisk::builder(p)
+        .auto_sync(false)
+        .build()?)
+    })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<(P,V)> = (0..680).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(2.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(2.0)*(1.0-ymin);
+    (((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  let bboxes: Vec<<P as Point>::Bounds> = (0..20).map(|_| {
+    let x0: f32 = r.read::<f32>()*2.0-1.0;
+    let y0: f32 = r.read::<f32>()*2.0-1.0;
+    let x1: f32 = x0 + r.read::<f32>().powf(2.0)*(1.0-x0);
+    let y1: f32 = y0 + r.read::<f32>().powf(2.0)*(1.0-y0);
+    ((x0,y0),(x1,y1))
+  }).collect();
+  load(&mut db, &inserts)?;
+  let mut found = (0,0);
+  for bbox in bboxes.iter() {
+    found.0 += check(&db, bbox, Predicate::Within)?;
+    found.1 += check(&db, bbox, Predicate::Contains)?;
+    check(&db, bbox, Predicate::Overlaps)?;
+  }
+  assert!(found.0 > 0 && found.1 > 0, "matching records {:?}", found);
+  Ok(())
+}
+
+#[test]
+fn query_with_mix() -> Result<(),Error> {
+  type P = Mix2<f32,f32>;
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db: DB<_,_,P,V> = Setup::new(
+    |name: &str| -> Result<RandomAccessDisk,Error> {
+      let p = dir.path().join(name);
+      Ok(RandomAccessDisk::builder(p)
+        .auto_sync(false)
+        .build()?)
+    })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  // scalar x with interval y
+  let inserts: Vec<(P,V)> = (0..680).map(|i| {
+    let x: f32 = r.read::<f32>()*2.0-1.0;
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(2.0)*(1.0-ymin);
+    (P::new(Mix::Scalar(x),Mix::Interval(ymin,ymax)), i)
+  }).collect();
+  load(&mut db, &inserts)?;
+  let mut found = (0,0);
+  for (p,_) in inserts.iter().step_by(40) {
+    let y0: f32 = r.read::<f32>()*2.0-1.0;
+    let y1: f32 = y0 + r.read::<f32>().powf(2.0)*(1.0-y0);
+    let x = match p.v0 { Mix::Scalar(x) => x, Mix::Interval(x,_) => x };
+    // only scalars equal to x can contain a box with no width in x
+    found.1 += check(&db, &((x,y0),(x,y1)), Predicate::Contains)?;
+    let x0: f32 = r.read::<f32>()*2.0-1.0;
+    let x1: f32 = x0 + r.read::<f32>().powf(2.0)*(1.0-x0);
+    found.0 += check(&db, &((x0,y0),(x1,y1)), Predicate::Within)?;
+    check(&db, &((x0,y0),(x1,y1)), Predicate::Contains)?;
+  }
+  assert!(found.0 > 0 && found.1 > 0, "matching records {:?}", found);
+  Ok(())
+}
+
+fn load<S,U,P> (db: &mut DB<S,U,P,V>, inserts: &[(P,V)]) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error>, P: Point {
+  let rows: Vec<Row<P,V>> = inserts.iter()
+    .map(|(p,v)| Row::Insert(*p,*v)).collect();
+  db.batch(&rows[0..250])?;
+  db.batch(&rows[250..650])?;
+  db.batch(&rows[650..680])?; // staging
+  Ok(())
+}
+
+// compare to the records that overlap bbox, returning the number of results
+fn check<S,U,P> (db: &DB<S,U,P,V>, bbox: &P::Bounds, predicate: Predicate)
+-> Result<usize,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error>, P: Spatial {
+  let b = P::bounds_extents(bbox);
+  let mut expected = vec![];
+  for result in db.query_with(bbox, Predicate::Overlaps)? {
+    let (p,v,_) = result?;
+    let e = p.extents();
+    let keep = e.iter().zip(b.iter()).all(|(x,y)| match predicate {
+      Predicate::Overlaps => x.0 <= y.1 && y.0 <= x.1,
+      Predicate::Within => y.0 <= x.0 && x.1 <= y.1,
+      Predicate::Contains => x.0 <= y.0 && y.1 <= x.1
+    });
+    if keep { expected.push(v) }
+  }
+  expected.sort();
+  let mut values = vec![];
+  for result in db.query_with(bbox, predicate)? {
+    values.push(result?.1);
+  }
+  values.sort();
+  assert_eq!(values, expected, "{:?} {:?}", predicate, bbox);
+  Ok(values.len())
+}
@@ -0,0 +1,115 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate tempfile;
+
+use eyros::{Setup,Row,Location,Id};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::{HashMap,HashSet};
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+
+#[test]
+fn record_id() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let mut inserts: Vec<Row<P,V>> = vec![];
+  for i in 0..5 {
+    // values are unique so they can identify records
+    let batch: Vec<Row<P,V>> = (0..150).map(|j| {
+      let xmin: f32 = r.read::<f32>()*2.0-1.0;
+      let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+      let ymin: f32 = r.read::<f32>()*2.0-1.0;
+      let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+      let time: f32 = r.read::<f32>()*1000.0;
+      let point = ((xmin,xmax),(ymin,ymax),time);
+      Row::Insert(point, i*150+j)
+    }).collect();
+    inserts.extend(batch);
+  }
+
+  db.batch(&inserts[0..150])?;
+  let first: HashMap<V,Location> = {
+    let mut results = HashMap::new();
+    for result in db.query(&bbox)? {
+      let (_,v,loc) = result?;
+      results.insert(v,loc);
+    }
+    results
+  };
+  assert_eq!(first.len(), 150, "all records from the first batch");
+  assert!(first.values().any(|loc| loc.0 == 0), "some records in staging");
+  assert!(first.values().any(|loc| loc.0 > 0), "some records in trees");
+  {
+    let ids: HashSet<Id> = first.values().map(|loc| loc.2).collect();
+    assert_eq!(ids.len(), 150, "record ids are unique");
+  }
+
+  // merge trees and flush staging so the records move into new blocks
+  db.batch(&inserts[150..450])?;
+  let moved: HashMap<V,Location> = {
+    let mut results = HashMap::new();
+    for result in db.query(&bbox)? {
+      let (_,v,loc) = result?;
+      results.insert(v,loc);
+    }
+    results
+  };
+  assert_eq!(moved.len(), 450, "all records after merging");
+  let mut changed = 0;
+  for (v,loc) in first.iter() {
+    assert_eq!(moved[v].2, loc.2, "record id is stable for value {}", v);
+    if moved[v].0 != loc.0 || moved[v].1 != loc.1 { changed += 1 }
+  }
+  assert!(changed > 0, "some records moved to a new location");
+
+  // locations from before the merge are stale, but their ids still locate
+  // the records
+  let deleted: HashSet<V> = first.keys().filter(|v| *v % 3 == 0)
+    .map(|v| *v).collect();
+  let stale: Vec<Row<P,V>> = deleted.iter()
+    .map(|v| Row::Delete(first[v])).collect();
+  assert!(db.batch(&stale).is_err(), "stale locations are rejected");
+  let mut deletes: Vec<Row<P,V>> = vec![];
+  for v in deleted.iter() {
+    let loc = db.locate(first[v].2)?;
+    assert_eq!(loc, Some(moved[v]), "located record for value {}", v);
+    deletes.push(Row::Delete(loc.unwrap()));
+  }
+  db.batch(&deletes)?;
+  for v in deleted.iter() {
+    assert_eq!(db.locate(first[v].2)?, None, "deleted record is not located");
+  }
+  for n in [450,750].iter() {
+    let mut results: HashSet<V> = HashSet::new();
+    for result in db.query(&bbox)? {
+      let (_,v,_) = result?;
+      assert!(results.insert(v), "no duplicate results");
+    }
+    let expected: HashSet<V> = (0..*n).filter(|v| !deleted.contains(v))
+      .collect();
+    assert_eq!(results, expected, "the correct records are deleted");
+    if *n == 450 {
+      // merge again after the deletes
+      db.batch(&inserts[450..750])?;
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,118 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Location};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::{HashMap,HashSet};
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+
+#[test]
+fn replace() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let bbox = ((-10.0,-10.0,0.0),(10.0,10.0,10000.0));
+  let inserts: Vec<Row<P,V>> = (0..550).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    let point = ((xmin,xmax),(ymin,ymax),time);
+    Row::Insert(point, i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+
+  let before = records(&mut db, &bbox)?;
+  assert_eq!(before.len(), 250, "all records before replacing");
+  let staged = *before.iter().find(|(_,(_,loc))| loc.0 == 0).unwrap().0;
+  let mut stored = before.iter().filter(|(_,(_,loc))| loc.0 > 0)
+    .map(|(v,_)| *v);
+  let (in_place, moved) = (stored.next().unwrap(), stored.next().unwrap());
+
+  let far: P = ((5.0,5.5),(-5.0,-5.0),5000.0);
+  let near: P = ((0.5,0.5),(0.5,0.5),500.0);
+  db.batch(&vec![
+    Row::Replace(before[&staged].1, near, 1000+staged),
+    Row::Replace(before[&in_place].1, before[&in_place].0, 1000+in_place),
+    Row::Replace(before[&moved].1, far, 1000+moved)
+  ])?;
+
+  let after = records(&mut db, &bbox)?;
+  assert_eq!(after.len(), 250, "replacing doesn't change the record count");
+  for v in [staged,in_place,moved].iter() {
+    assert!(!after.contains_key(v), "old value {} is replaced", v);
+  }
+  assert_eq!(after[&(1000+staged)].0, near, "staged point is replaced");
+  assert_eq!(after[&(1000+in_place)].0, before[&in_place].0,
+    "point replaced in place");
+  assert_eq!(after[&(1000+moved)].0, far, "moved point is replaced");
+
+  let generation = (after[&(1000+moved)].1).3;
+  assert_ne!(generation, (before[&moved].1).3,
+    "reinserting a record starts a new generation");
+  let same = |a: &Location, b: &Location| (a.0,a.1,a.2) == (b.0,b.1,b.2);
+  assert!(same(&after[&(1000+staged)].1, &before[&staged].1),
+    "staged record keeps its location");
+  assert!(same(&after[&(1000+in_place)].1, &before[&in_place].1),
+    "record replaced in place keeps its location");
+  assert_eq!((after[&(1000+moved)].1).2, (before[&moved].1).2,
+    "moved record keeps its id");
+  assert_eq!((after[&(1000+moved)].1).0, 0, "moved record is staged");
+  assert_eq!(db.locate((before[&moved].1).2)?, Some(after[&(1000+moved)].1),
+    "located moved record");
+
+  // records can only be replaced once per batch and must exist
+  let loc = after[&(1000+staged)].1;
+  assert!(db.batch(&vec![
+    Row::Replace(loc, near, 1), Row::Replace(loc, far, 2)
+  ]).is_err(), "replacing a record twice in a batch fails");
+  assert!(db.batch(&vec![
+    Row::Delete(loc), Row::Replace(loc, near, 1)
+  ]).is_err(), "replacing a deleted record in a batch fails");
+  db.batch(&vec![Row::Delete(loc)])?;
+  assert!(db.batch(&vec![Row::Replace(loc, near, 1)]).is_err(),
+    "replacing a deleted record fails");
+
+  // replaced records survive tree merges
+  db.batch(&inserts[250..550])?;
+  let merged = records(&mut db, &bbox)?;
+  assert_eq!(merged.len(), 549, "all records after merging");
+  assert_eq!(merged[&(1000+in_place)].0, before[&in_place].0,
+    "point replaced in place after merging");
+  assert_eq!(merged[&(1000+moved)].0, far, "moved point after merging");
+  let ids: HashSet<u64> = merged.values().map(|(_,loc)| loc.2).collect();
+  assert_eq!(ids.len(), 549, "record ids are unique");
+  Ok(())
+}
+
+fn records<S,U> (db: &mut DB<S,U,P,V>, bbox: &((f32,f32,f32),(f32,f32,f32)))
+-> Result<HashMap<V,(P,Location)>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = HashMap::new();
+  for result in db.query(bbox)? {
+    let (p,v,loc) = result?;
+    assert!(results.insert(v,(p,loc)).is_none(), "no duplicate results");
+  }
+  Ok(results)
+}
@@ -0,0 +1,165 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,Row,Location,QueryIterator};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+type B = ((f32,f32,f32),(f32,f32,f32));
+
+#[test]
+fn snapshot() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..400).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    Row::Insert(((xmin,xmax),(ymin,ymax),time), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+
+  let full = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let before = records(db.query(&full)?)?;
+  assert_eq!(before.len(), 250, "all records before the snapshot");
+  let snapshot = db.snapshot()?;
+  assert_eq!(snapshot.generation(), (before.values().next().unwrap().1).3,
+    "snapshot generation");
+  assert_eq!(records(snapshot.query(&full)?)?, before, "snapshot records");
+
+  // start reading from the snapshot before the next batch
+  let mut iter = snapshot.query(&full)?;
+  let mut partial = HashMap::new();
+  for _ in 0..20 {
+    let (p,v,loc) = iter.next().unwrap()?;
+    partial.insert(v,(p,loc));
+  }
+
+  // merge trees and delete records from data blocks and from staging
+  let mut rows = inserts[250..400].to_vec();
+  let mut deleted = 0;
+  for (i,(_,loc)) in before.values().enumerate() {
+    if i % 3 == 0 {
+      rows.push(Row::Delete(*loc));
+      deleted += 1;
+    }
+  }
+  db.batch(&rows)?;
+  assert!(snapshot_bytes(dir.path())? > 0, "trees are copied for snapshot");
+
+  for result in iter {
+    let (p,v,loc) = result?;
+    assert!(partial.insert(v,(p,loc)).is_none(), "no duplicate results");
+  }
+  assert_eq!(partial, before, "open snapshot iterator across a batch");
+  assert_eq!(records(snapshot.query(&full)?)?, before,
+    "snapshot records after a batch");
+  let after = records(db.query(&full)?)?;
+  assert_eq!(after.len(), 400-deleted, "records after the batch");
+
+  let bbox = ((-0.5,-0.8,0.0),(0.3,0.5,600.0));
+  let expected: HashMap<V,(P,Location)> = before.iter()
+    .filter(|(_,(p,_))| overlaps(p, &bbox))
+    .map(|(v,x)| (*v,*x)).collect();
+  assert_eq!(records(snapshot.query(&bbox)?)?, expected,
+    "snapshot records in a bbox");
+
+  // snapshot locations are from an older generation
+  let loc = (after.values().next().unwrap().1).2;
+  let old = before.values().find(|(_,l)| l.2 == loc).map(|(_,l)| *l);
+  if let Some(old) = old {
+    assert!(db.batch(&vec![Row::Delete(old)]).is_err(),
+      "snapshot location is stale");
+  }
+
+  // records deleted from data blocks in place are kept for the snapshot
+  let later = db.snapshot()?;
+  db.delete_bbox(&bbox)?;
+  assert!(records(db.query(&bbox)?)?.is_empty(), "records deleted in bbox");
+  assert_eq!(records(later.query(&full)?)?, after, "later snapshot records");
+  let after = records(db.query(&full)?)?;
+  drop(later);
+  drop(snapshot);
+  assert_eq!(snapshot_bytes(dir.path())?, 0,
+    "tree copies are reclaimed after the snapshot is dropped");
+  assert_eq!(records(db.query(&full)?)?, after, "records after dropping");
+
+  // trees that nothing reads are not copied
+  let files = snapshot_files(dir.path())?;
+  db.batch(&inserts[0..150].iter().map(|row| match row {
+    Row::Insert(p,v) => Row::Insert(*p,v+1000),
+    _ => panic!["unexpected row type"]
+  }).collect::<Vec<_>>())?;
+  assert_eq!(snapshot_files(dir.path())?, files, "no new tree copies");
+  assert_eq!(records(db.query(&full)?)?.len(), after.len()+150,
+    "records after merging without readers");
+  Ok(())
+}
+
+fn records<S> (iter: QueryIterator<S,P,V>)
+-> Result<HashMap<V,(P,Location)>,Error>
+where S: RandomAccess<Error=Error> {
+  let mut results = HashMap::new();
+  for result in iter {
+    let (p,v,loc) = result?;
+    assert!(results.insert(v,(p,loc)).is_none(), "no duplicate results");
+  }
+  Ok(results)
+}
+
+fn snapshot_bytes (dir: &Path) -> Result<u64,Error> {
+  let mut bytes = 0;
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_name().to_string_lossy().contains("_snapshot") {
+      bytes += entry.metadata()?.len();
+    }
+  }
+  Ok(bytes)
+}
+
+fn snapshot_files (dir: &Path) -> Result<usize,Error> {
+  let mut files = 0;
+  for entry in std::fs::read_dir(dir)? {
+    if entry?.file_name().to_string_lossy().contains("_snapshot") {
+      files += 1;
+    }
+  }
+  Ok(files)
+}
+
+fn overlaps (p: &P, bbox: &B) -> bool {
+  contains_iv((bbox.0).0, (bbox.1).0, p.0)
+  && contains_iv((bbox.0).1, (bbox.1).1, p.1)
+  && contains_pt((bbox.0).2, (bbox.1).2, p.2)
+}
+fn contains_iv<T> (min: T, max: T, iv: (T,T)) -> bool where T: PartialOrd {
+  min <= iv.1 && iv.0 <= max
+}
+fn contains_pt<T> (min: T, max: T, pt: T) -> bool where T: PartialOrd {
+  min <= pt && pt <= max
+}
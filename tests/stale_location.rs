@@ -0,0 +1,110 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row,Location};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+
+use std::collections::HashMap;
+
+type P = ((f32,f32),(f32,f32),f32);
+type V = u32;
+
+#[test]
+fn stale_location() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = Setup::new(|name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = dir.path().join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()?;
+  let mut r = rand().seed([13,12]);
+  let bbox = ((-1.0,-1.0,0.0),(1.0,1.0,1000.0));
+  let inserts: Vec<Row<P,V>> = (0..250).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(64.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(64.0)*(1.0-ymin);
+    let time: f32 = r.read::<f32>()*1000.0;
+    let point = ((xmin,xmax),(ymin,ymax),time);
+    Row::Insert(point, i)
+  }).collect();
+  db.batch(&inserts)?;
+
+  let before = locations(&mut db, &bbox)?;
+  assert_eq!(before.len(), 250, "all records before deleting");
+  let mut tree_values: Vec<V> = before.iter()
+    .filter(|(_,loc)| loc.0 > 0).map(|(v,_)| *v).collect();
+  tree_values.sort();
+  assert_eq!(tree_values.len(), 200, "records in trees");
+  let staging_value = |index: u32| -> V {
+    *before.iter().find(|(_,loc)| loc.0 == 0 && loc.1 == index).unwrap().0
+  };
+
+  // deleting only from trees does not move any records
+  db.batch(&tree_values[0..100].iter()
+    .map(|v| Row::Delete(before[v])).collect::<Vec<Row<P,V>>>())?;
+  {
+    let after = locations(&mut db, &bbox)?;
+    assert_eq!(after.len(), 150, "records after deleting from trees");
+    for (v,loc) in after.iter() {
+      assert_eq!(before[v], *loc, "location for value {} is unchanged", v);
+    }
+  }
+
+  // removing the first staged record moves the rest of the staging records
+  let mut deletes: Vec<Row<P,V>> = tree_values[100..200].iter()
+    .map(|v| Row::Delete(before[v])).collect();
+  deletes.push(Row::Delete(before[&staging_value(0)]));
+  db.batch(&deletes)?;
+
+  let v = staging_value(10);
+  let stale = before[&v];
+  assert!(db.batch(&vec![Row::Delete(stale)]).is_err(),
+    "stale staging location is rejected");
+  let loc = db.locate(stale.2)?.unwrap();
+  assert_eq!((loc.0,loc.1,loc.2), (0,9,stale.2), "located moved record");
+  assert_ne!(loc.3, stale.3, "generation changed");
+  db.batch(&vec![Row::Delete(loc)])?;
+  assert_eq!(db.locate(stale.2)?, None, "deleted record is not located");
+
+  // batch_relocate() looks up stale locations by record id instead
+  let stale = before[&staging_value(11)];
+  db.batch_relocate(&vec![Row::Delete(stale)])?;
+  assert_eq!(db.locate(stale.2)?, None, "relocated record is deleted");
+  assert!(db.batch_relocate(&vec![Row::Delete(stale)]).is_err(),
+    "stale location of a deleted record is rejected");
+
+  let after = locations(&mut db, &bbox)?;
+  let mut values: Vec<V> = after.keys().map(|v| *v).collect();
+  values.sort();
+  let mut expected: Vec<V> = before.iter()
+    .filter(|(_,loc)| loc.0 == 0 && ![0,10,11].contains(&loc.1))
+    .map(|(v,_)| *v).collect();
+  expected.sort();
+  assert_eq!(values, expected, "remaining staging records");
+  Ok(())
+}
+
+fn locations<S,U> (db: &mut DB<S,U,P,V>, bbox: &((f32,f32,f32),(f32,f32,f32)))
+-> Result<HashMap<V,Location>,Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut results = HashMap::new();
+  for result in db.query(bbox)? {
+    let (_,v,loc) = result?;
+    results.insert(v,loc);
+  }
+  Ok(results)
+}
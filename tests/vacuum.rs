@@ -0,0 +1,132 @@
+extern crate eyros;
+extern crate failure;
+extern crate random;
+extern crate random_access_disk;
+extern crate random_access_storage;
+extern crate tempfile;
+
+use eyros::{Setup,DB,Row};
+use failure::Error;
+use random_access_disk::RandomAccessDisk;
+use random_access_storage::RandomAccess;
+use random::{Source,default as rand};
+use tempfile::Builder as Tmpfile;
+use std::path::Path;
+use std::collections::HashSet;
+
+type P = ((f32,f32),(f32,f32));
+type V = u32;
+
+const BBOX: ((f32,f32),(f32,f32)) = ((-1.0,-1.0),(1.0,1.0));
+
+#[test]
+fn vacuum() -> Result<(),Error> {
+  let dir = Tmpfile::new().prefix("eyros").tempdir()?;
+  let mut db = open(dir.path())?;
+  let mut r = rand().seed([13,12]);
+  let inserts: Vec<Row<P,V>> = (0..1000).map(|i| {
+    let xmin: f32 = r.read::<f32>()*2.0-1.0;
+    let xmax: f32 = xmin + r.read::<f32>().powf(16.0)*(1.0-xmin);
+    let ymin: f32 = r.read::<f32>()*2.0-1.0;
+    let ymax: f32 = ymin + r.read::<f32>().powf(16.0)*(1.0-ymin);
+    Row::Insert(((xmin,xmax),(ymin,ymax)), i)
+  }).collect();
+  db.batch(&inserts[0..250])?;
+  db.batch(&inserts[250..650])?;
+  db.batch(&inserts[650..680])?; // staging
+  let mut expected: HashSet<V> = (0..680).collect();
+
+  db.vacuum()?;
+  check(&db, &expected)?;
+  assert_eq!(db.vacuum()?, 0, "nothing to reclaim");
+
+  db.delete_where(&BBOX, |_,v| v % 4 != 0)?;
+  expected.retain(|v| v % 4 == 0);
+  // a staged delete that is still pending during the vacuum
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 5 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  expected.retain(|v| v % 5 != 0);
+  let stale = db.query(&BBOX)?.next().unwrap()?.2;
+
+  let before = data_len(dir.path())?;
+  let reclaimed = db.vacuum()?;
+  let after = data_len(dir.path())?;
+  assert!(reclaimed > 0, "reclaimed bytes");
+  assert_eq!(before - after, reclaimed, "data file size");
+  check(&db, &expected)?;
+  assert_eq!(db.vacuum()?, 0, "nothing left to reclaim");
+  assert!(db.batch(&[Row::Delete(stale)]).is_err(), "stale location");
+
+  // writes after a vacuum
+  db.batch(&inserts[680..1000])?;
+  expected.extend(680..1000);
+  let deletes: Vec<Row<P,V>> = db.query(&BBOX)?
+    .filter_map(|result| result.ok())
+    .filter(|(_,v,_)| v % 3 == 0)
+    .map(|(_,_,loc)| Row::Delete(loc))
+    .collect();
+  db.batch(&deletes)?;
+  expected.retain(|v| v % 3 != 0);
+  check(&db, &expected)?;
+  drop(db);
+
+  let mut db = open(dir.path())?;
+  check(&db, &expected)?;
+  db.vacuum()?;
+  check(&db, &expected)?;
+
+  let snapshot = db.snapshot()?;
+  assert!(db.vacuum().is_err(), "vacuum with an open snapshot");
+  drop(snapshot);
+  db.vacuum()?;
+  Ok(())
+}
+
+fn open (path: &Path)
+-> Result<DB<RandomAccessDisk,impl Fn(&str)
+-> Result<RandomAccessDisk,Error>,P,V>,Error> {
+  let path = path.to_path_buf();
+  Setup::new(move |name: &str| -> Result<RandomAccessDisk,Error> {
+    let p = path.join(name);
+    Ok(RandomAccessDisk::builder(p)
+      .auto_sync(false)
+      .build()?)
+  })
+    .branch_factor(5)
+    .max_data_size(20)
+    .base_size(100)
+    .build()
+}
+
+// length of the data file in use. vacuuming copies the blocks into "data1" or
+// back and empties the other file
+fn data_len (path: &Path) -> Result<u64,Error> {
+  let mut len = 0;
+  for name in ["data","data1"].iter() {
+    if let Ok(metadata) = std::fs::metadata(path.join(name)) {
+      len += metadata.len();
+    }
+  }
+  Ok(len)
+}
+
+// queries, counts, and record locations all agree with `expected`
+fn check<S,U> (db: &DB<S,U,P,V>, expected: &HashSet<V>) -> Result<(),Error>
+where S: RandomAccess<Error=Error>, U: Fn(&str) -> Result<S,Error> {
+  let mut values = vec![];
+  for result in db.query(&BBOX)? {
+    let (_,v,loc) = result?;
+    assert_eq!(db.locate(loc.2)?, Some(loc), "location of {}", v);
+    values.push(v);
+  }
+  values.sort();
+  let mut expected: Vec<V> = expected.iter().cloned().collect();
+  expected.sort();
+  assert_eq!(values, expected, "records");
+  assert_eq!(db.count(&BBOX)?, expected.len() as u64, "count");
+  Ok(())
+}